@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+use structopt::StructOpt;
+use structopt::clap::AppSettings;
+
+use crate::error::TimelapseError;
+use crate::request::Request;
+
+/// Options which only make sense on the command line
+const COMMAND_LINE_ONLY: &[&str] = &["config", "input", "output"];
+
+/// Options which clap knows by another name than their long one, as they can be given more than
+/// once to fill in a list
+const ARG_NAMES: &[(&str, &str)] = &[("input-option", "input-options"), ("output-option", "output-options")];
+
+/// The value of an option in a config file
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum ConfigValue {
+    Flag(bool),
+    Integer(i64),
+    Float(f64),
+    Text(String),
+    /// Options which can be given more than once, eg. `input-option = ["probesize=32", "fflags=nobuffer"]`
+    List(Vec<ConfigValue>),
+}
+
+/// Parses the request from the command line like `Request::from_args` does, filling in the
+/// options which weren't given there from the `--config` file if there is one
+pub fn request_from_args() -> Result<Request, TimelapseError> {
+    request_from_iter(std::env::args_os())
+}
+
+/// Parses the request from the given arguments, the first of which is the program name, filling
+/// in the options which weren't given there from the `--config` file if there is one. Like
+/// `Request::from_iter`, this exits the process on `--help` and on invalid arguments.
+pub fn request_from_iter<I>(args: I) -> Result<Request, TimelapseError>
+where
+    I: IntoIterator,
+    I::Item: Into<OsString>,
+{
+    let args = args.into_iter().map(Into::into).collect::<Vec<OsString>>();
+    let matches = Request::clap().get_matches_from(args.iter().cloned());
+    let request = Request::from_clap(&matches);
+    let config_path = match &request.config {
+        Some(path) => path.clone(),
+        None => return Ok(request),
+    };
+
+    // The options from the file are put in front of those from the command line and parsed again,
+    // so that they go through the same parsing and checks. Options given on the command line are
+    // left out, so that no option is given twice.
+    let mut merged = args.iter().take(1).cloned().collect::<Vec<_>>();
+    for (key, value) in read_config(&config_path)? {
+        // clap knows options by their kebab-case names, which the file may also give in snake_case
+        let option = key.replace('_', "-");
+        if COMMAND_LINE_ONLY.contains(&option.as_str()) {
+            return Err(config_error(&config_path, &format!("`{}` can only be given on the command line", key)));
+        }
+        let arg_name = ARG_NAMES.iter().find(|(long, _)| *long == option).map_or(option.as_str(), |(_, name)| *name);
+        if matches.occurrences_of(arg_name) > 0 {
+            continue;
+        }
+        push_option(&mut merged, &option, value);
+    }
+    merged.extend(args.iter().skip(1).cloned());
+
+    let matches = Request::clap()
+        .setting(AppSettings::ColorNever)
+        .get_matches_from_safe(merged)
+        .map_err(|e| {
+            let message = e.message.lines().next().unwrap_or_default();
+            config_error(&config_path, message.trim_start_matches("error: "))
+        })?;
+    Ok(Request::from_clap(&matches))
+}
+
+/// Adds the option to the arguments as it would have been given on the command line
+fn push_option(args: &mut Vec<OsString>, option: &str, value: ConfigValue) {
+    let flag = format!("--{}", option);
+    match value {
+        ConfigValue::Flag(true) => args.push(OsString::from(flag)),
+        ConfigValue::Flag(false) => {},
+        // `verbose = 2` is the same as `-vv`
+        ConfigValue::Integer(count) if option == "verbose" => {
+            args.extend((0..count).map(|_| OsString::from(&flag)));
+        },
+        ConfigValue::Integer(value) => args.push(OsString::from(format!("{}={}", flag, value))),
+        ConfigValue::Float(value) => args.push(OsString::from(format!("{}={}", flag, value))),
+        ConfigValue::Text(value) => args.push(OsString::from(format!("{}={}", flag, value))),
+        ConfigValue::List(values) => {
+            for value in values {
+                push_option(args, option, value);
+            }
+        },
+    }
+}
+
+fn read_config(path: &Path) -> Result<BTreeMap<String, ConfigValue>, TimelapseError> {
+    let contents = fs::read_to_string(path)?;
+    toml::from_str(&contents).map_err(|e| config_error(path, &e.to_string()))
+}
+
+fn config_error(path: &Path, message: &str) -> TimelapseError {
+    TimelapseError::InvalidRequest(format!("in config file {}: {}", path.display(), message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::process;
+
+    /// Writes the config file to the temporary directory and returns its path
+    fn write_config(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("timelapse-rs-config-{}-{}.toml", name, process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn request_with_config(config: &Path, args: &[&str]) -> Result<Request, TimelapseError> {
+        let mut all_args = vec!["timelapse-rs", "input.mp4", "output.webm", "--config", config.to_str().unwrap()];
+        all_args.extend_from_slice(args);
+        request_from_iter(all_args)
+    }
+
+    #[test]
+    fn command_line_options_override_the_config_file() {
+        let config = write_config("override", "window-size = 50\nframes-per-window = 2\nkeep-audio = true\nverbose = 2\n");
+        let request = request_with_config(&config, &["--window-size", "10"]).unwrap();
+        fs::remove_file(&config).unwrap();
+
+        assert_eq!(request.window_size, 10);
+        assert_eq!(request.frames_per_window, 2);
+        assert!(request.keep_audio);
+        assert_eq!(request.verbose, 2);
+    }
+
+    #[test]
+    fn config_keys_can_be_snake_case() {
+        let config = write_config("snake-case", "window_size = 50\nframes_per_window = 2\n");
+        let request = request_with_config(&config, &["--window-size", "10"]).unwrap();
+        fs::remove_file(&config).unwrap();
+
+        assert_eq!(request.window_size, 10);
+        assert_eq!(request.frames_per_window, 2);
+    }
+
+    #[test]
+    fn lists_give_an_option_more_than_once() {
+        let config = write_config("lists", "input-option = [\"probesize=32\", \"fflags=nobuffer\"]\noutput-option = [\"cpu-used=4\"]\n");
+        let request = request_with_config(&config, &[]).unwrap();
+        let overridden = request_with_config(&config, &["--input-option", "analyzeduration=0"]).unwrap();
+        fs::remove_file(&config).unwrap();
+
+        let option = |key: &str, value: &str| (String::from(key), String::from(value));
+        assert_eq!(request.input_options, vec![option("probesize", "32"), option("fflags", "nobuffer")]);
+        assert_eq!(request.output_options, vec![option("cpu-used", "4")]);
+        assert_eq!(overridden.input_options, vec![option("analyzeduration", "0")]);
+        assert_eq!(overridden.output_options, vec![option("cpu-used", "4")]);
+    }
+
+    #[test]
+    fn paths_cannot_be_given_in_the_config_file() {
+        let config = write_config("paths", "output = \"other.webm\"\n");
+        let result = request_with_config(&config, &[]);
+        fs::remove_file(&config).unwrap();
+
+        match result {
+            Err(TimelapseError::InvalidRequest(message)) => assert!(message.contains("`output` can only be given on the command line"), "{}", message),
+            other => panic!("expected an invalid request, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn invalid_values_in_the_config_file_are_reported() {
+        let config = write_config("invalid", "window-size = \"lots\"\n");
+        let result = request_with_config(&config, &[]);
+        fs::remove_file(&config).unwrap();
+
+        match result {
+            Err(TimelapseError::InvalidRequest(message)) => assert!(message.starts_with("in config file "), "{}", message),
+            other => panic!("expected an invalid request, got {:?}", other.map(|_| ())),
+        }
+    }
+}
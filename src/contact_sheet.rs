@@ -0,0 +1,170 @@
+use std::path::Path;
+
+use ffmpeg::format::Pixel;
+use ffmpeg::software::scaling::{flag::Flags as ScalingFlags, Context as ScalingContext};
+use ffmpeg::util::frame::Video as VideoFrame;
+use image::{imageops, RgbImage};
+
+use crate::error::TimelapseError;
+use crate::request::{Rect, Request};
+
+/// Width of each thumbnail on the sheet; the height follows from the aspect ratio of the frames
+const THUMBNAIL_WIDTH: u32 = 160;
+
+/// Collects thumbnails of every Nth frame it is given and lays them out in a grid
+pub struct ContactSheet {
+    columns: u32,
+    every: u64,
+    frames_seen: u64,
+    scaler: Option<ScalingContext>,
+    thumbnails: Vec<RgbImage>,
+}
+
+impl ContactSheet {
+    /// Creates a sheet for the request. `estimated_total` is the number of frames expected to be
+    /// given, which is used to work out how many to skip when `--sheet-every` is not given.
+    pub fn new(request: &Request, estimated_total: Option<u64>) -> Self {
+        let columns = request.sheet_cols;
+        let every = request.sheet_every.unwrap_or_else(|| sheet_every(estimated_total, columns));
+        Self::with_spacing(columns, every)
+    }
+
+    /// Creates a sheet with the given number of thumbnails in each row, taking a thumbnail of
+    /// every `every`th frame
+    pub fn with_spacing(columns: u32, every: u64) -> Self {
+        Self {
+            columns,
+            every,
+            frames_seen: 0,
+            scaler: None,
+            thumbnails: Vec::new(),
+        }
+    }
+
+    /// Takes a thumbnail of the frame if it is one of those that go on the sheet
+    pub fn add(&mut self, frame: &VideoFrame) -> Result<(), TimelapseError> {
+        let index = self.frames_seen;
+        self.frames_seen += 1;
+        if index % self.every != 0 {
+            return Ok(());
+        }
+
+        if self.scaler.is_none() {
+            let (width, height) = thumbnail_size(frame.width(), frame.height());
+            self.scaler = Some(ScalingContext::get(frame.format(), frame.width(), frame.height(), Pixel::RGB24, width, height, ScalingFlags::AREA)?);
+        }
+        let mut thumbnail = VideoFrame::empty();
+        self.scaler.as_mut().unwrap().run(frame, &mut thumbnail)?;
+
+        let (width, height) = (thumbnail.width(), thumbnail.height());
+        let row_len = width as usize * 3;
+        let stride = thumbnail.stride(0);
+        let mut data = Vec::with_capacity(row_len * height as usize);
+        for y in 0..(height as usize) {
+            data.extend_from_slice(&thumbnail.data(0)[(y * stride)..(y * stride + row_len)]);
+        }
+        self.thumbnails.push(RgbImage::from_raw(width, height, data).unwrap());
+        Ok(())
+    }
+
+    /// Number of frames given so far, including those left off the sheet
+    pub fn frames_seen(&self) -> u64 {
+        self.frames_seen
+    }
+
+    /// Number of frames given for each thumbnail on the sheet
+    pub fn every(&self) -> u64 {
+        self.every
+    }
+
+    pub fn thumbnail_count(&self) -> usize {
+        self.thumbnails.len()
+    }
+
+    /// Where the thumbnail with the given index ends up on the sheet
+    pub fn thumbnail_rect(&self, index: usize) -> Option<Rect> {
+        let (width, height) = self.thumbnails.get(index)?.dimensions();
+        let (columns, _) = grid_size(self.thumbnails.len(), self.columns);
+        Some(Rect {
+            x: index as u32 % columns * width,
+            y: index as u32 / columns * height,
+            width,
+            height,
+        })
+    }
+
+    /// Lays the thumbnails out left to right, top to bottom, and writes the sheet to the path
+    pub fn save(&self, path: &Path) -> Result<(), TimelapseError> {
+        let (thumbnail_width, thumbnail_height) = match self.thumbnails.first() {
+            Some(thumbnail) => thumbnail.dimensions(),
+            None => return Ok(()),
+        };
+        let (columns, rows) = grid_size(self.thumbnails.len(), self.columns);
+        let mut sheet = RgbImage::new(columns * thumbnail_width, rows * thumbnail_height);
+        for (i, thumbnail) in self.thumbnails.iter().enumerate() {
+            let rect = self.thumbnail_rect(i).unwrap();
+            imageops::replace(&mut sheet, thumbnail, rect.x, rect.y);
+        }
+        sheet.save(path)?;
+        Ok(())
+    }
+}
+
+/// Number of columns and rows of a sheet holding the given number of thumbnails. A sheet with
+/// fewer thumbnails than fit in a row is only as wide as they are.
+pub fn grid_size(thumbnails: usize, columns: u32) -> (u32, u32) {
+    let thumbnails = thumbnails as u32;
+    let columns = columns.min(thumbnails);
+    if columns == 0 {
+        return (0, 0);
+    }
+    (columns, (thumbnails + columns - 1) / columns)
+}
+
+/// How many frames to move on by between thumbnails so that the given number of frames fills a
+/// sheet about as many rows tall as it is columns wide
+fn sheet_every(estimated_total: Option<u64>, columns: u32) -> u64 {
+    let thumbnails = u64::from(columns) * u64::from(columns);
+    match estimated_total {
+        Some(total) if total > thumbnails => (total + thumbnails - 1) / thumbnails,
+        _ => 1,
+    }
+}
+
+fn thumbnail_size(width: u32, height: u32) -> (u32, u32) {
+    let thumbnail_width = THUMBNAIL_WIDTH.min(width);
+    let thumbnail_height = (u64::from(height) * u64::from(thumbnail_width) / u64::from(width)).max(1) as u32;
+    (thumbnail_width, thumbnail_height)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grid_size_fills_rows_before_adding_another() {
+        assert_eq!(grid_size(10, 5), (5, 2));
+        assert_eq!(grid_size(11, 5), (5, 3));
+        assert_eq!(grid_size(1, 5), (1, 1));
+    }
+
+    #[test]
+    fn grid_size_is_only_as_wide_as_a_short_row() {
+        assert_eq!(grid_size(3, 10), (3, 1));
+        assert_eq!(grid_size(0, 10), (0, 0));
+    }
+
+    #[test]
+    fn sheet_every_spreads_the_frames_over_a_square_sheet() {
+        assert_eq!(sheet_every(None, 4), 1);
+        assert_eq!(sheet_every(Some(10), 4), 1);
+        assert_eq!(sheet_every(Some(160), 4), 10);
+        assert_eq!(sheet_every(Some(161), 4), 11);
+    }
+
+    #[test]
+    fn thumbnail_size_keeps_the_aspect_ratio() {
+        assert_eq!(thumbnail_size(1920, 1080), (160, 90));
+        assert_eq!(thumbnail_size(64, 48), (64, 48));
+    }
+}
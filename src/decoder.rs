@@ -1,147 +1,1099 @@
+use std::ffi::CString;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::slice::IterMut;
+use std::thread;
+use std::time::Duration;
+
 use ffmpeg::codec::packet::flag::Flags as PacketFlags;
-use ffmpeg::format::{Pixel, context::input::{Input as InputContext, PacketIter, dump as dump_format}};
+use ffmpeg::codec::packet::side_data::Type as SideDataType;
+use ffmpeg::ffi::{av_display_rotation_get, av_find_input_format, avformat_find_stream_info, avformat_open_input, AV_NOPTS_VALUE};
+use ffmpeg::format::{self, Pixel, context::input::{Input as InputContext, dump as dump_format}, stream::Stream};
 use ffmpeg::media::Type;
 use ffmpeg::decoder::{Video as VideoDecoder};
-use ffmpeg::software::scaling::{flag::Flags as ScalingFlags, Context as ScalingContext};
+use ffmpeg::software::scaling::Context as ScalingContext;
+use ffmpeg::util::color;
 use ffmpeg::util::frame::{Video as VideoFrame};
-use ffmpeg::Rational;
+use ffmpeg::codec::Parameters as CodecParameters;
+use ffmpeg::codec::threading;
+use ffmpeg::{Dictionary, Packet, Rational, Rescale, rescale};
+
+use crate::error::TimelapseError;
+use crate::sampled_frame_count;
+use crate::frame_selection::{required_pixel_format, DuplicateFilter, StaticTrimmer, Trim};
+use crate::post_processing::{is_full_range_format, rotate_frame, scaling_flags, set_color_details};
+use crate::request::{ffmpeg_path, is_stdio, is_url, Request, ComparisonMode};
+
+/// Microseconds network streams may stall for before reading them fails
+const NETWORK_TIMEOUT: &str = "10000000";
+
+/// Pause before the first attempt at reading the input again with `--read-retries`, which grows
+/// with every attempt after it
+const READ_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Frame rate assumed for inputs which give no way of working theirs out
+const DEFAULT_FRAME_RATE: i32 = 25;
+
+/// Extensions of still images that are picked up when the input is a directory
+const IMAGE_EXTENSIONS: [&str; 6] = ["png", "jpg", "jpeg", "bmp", "tif", "tiff"];
+
+/// Opens every input of the request, in order
+pub fn open_inputs(request: &Request) -> Result<Vec<InputContext>, TimelapseError> {
+    request.input_paths().iter().map(|path| open_input(request, path)).collect()
+}
+
+/// Opens a single input. Besides any file ffmpeg can open, the input can be a directory of images,
+/// a printf-style pattern such as `frames/%04d.png` or a glob such as `frames/*.jpg`, in which case
+/// the images are read in order as a video. `-` reads from standard input.
+pub fn open_input(request: &Request, path: &Path) -> Result<InputContext, TimelapseError> {
+    if is_stdio(path) {
+        return open_input_with(request, Path::new("pipe:0"), None, input_options(request, &[]));
+    }
+
+    // URLs often hold `?` or `%`, which would otherwise make them look like image sequences
+    if is_url(path) {
+        if request.verbose > 1 { log_message!("decoder::open_input: reading network stream {}", path.display()); }
+        let defaults = if path.to_string_lossy().starts_with("rtsp") {
+            // RTSP over UDP drops packets on any network that is less than perfect
+            vec![("rtsp_transport", "tcp"), ("stimeout", NETWORK_TIMEOUT)]
+        } else {
+            vec![("rw_timeout", NETWORK_TIMEOUT)]
+        };
+        return open_input_with(request, path, None, input_options(request, &defaults));
+    }
 
-use crate::request::{Request, ComparisonMode};
+    match image_sequence_pattern(path)? {
+        Some((pattern, pattern_type)) => {
+            if request.verbose > 1 { log_message!("decoder::open_input: reading image sequence {} ({})", pattern.display(), pattern_type); }
+
+            let image2 = find_input_format("image2").ok_or(ffmpeg::Error::DemuxerNotFound)?;
+            let options = input_options(request, &[("pattern_type", pattern_type)]);
+
+            open_input_with(request, &ffmpeg_path(&pattern), Some(image2), options)
+        },
+        None => open_input_with(request, &ffmpeg_path(path), None, input_options(request, &[])),
+    }
+}
+
+/// Opens the input in the given format, or the one ffmpeg guesses, with the given options. Fails
+/// if ffmpeg has no use for any of the options given with `--input-option`.
+fn open_input_with(request: &Request, url: &Path, format: Option<format::Input>, options: Dictionary) -> Result<InputContext, TimelapseError> {
+    let url = CString::new(url.to_string_lossy().into_owned())
+        .map_err(|_| TimelapseError::InvalidRequest(String::from("input path must not contain NUL bytes")))?;
+    let format = format.map_or(ptr::null_mut(), |format| unsafe { format.as_ptr() as *mut _ });
+
+    unsafe {
+        let mut ps = ptr::null_mut();
+        let mut opts = options.disown();
+        let res = avformat_open_input(&mut ps, url.as_ptr(), format, &mut opts);
+        let unused = Dictionary::own(opts);
+        if res != 0 {
+            return Err(ffmpeg::Error::from(res).into());
+        }
+
+        // Closes the input if anything below fails
+        let ictx = InputContext::wrap(ps);
+        check_unused_options(&request.input_options, &unused, "--input-option")?;
+        match avformat_find_stream_info(ps, ptr::null_mut()) {
+            r if r >= 0 => Ok(ictx),
+            e => Err(ffmpeg::Error::from(e).into()),
+        }
+    }
+}
+
+/// Fails if any of the options given on the command line were left unused by ffmpeg, which
+/// means it doesn't know them
+pub fn check_unused_options(given: &[(String, String)], unused: &Dictionary, flag: &str) -> Result<(), TimelapseError> {
+    let unknown = given.iter()
+        .filter(|(key, _)| unused.get(key).is_some())
+        .map(|(key, _)| key.as_str())
+        .collect::<Vec<_>>();
+    if unknown.is_empty() {
+        Ok(())
+    } else {
+        Err(TimelapseError::InvalidRequest(format!("unknown {} {}", flag, unknown.join(", "))))
+    }
+}
+
+/// Options to open an input with: the given defaults, `--probe-size` and `--analyze-duration`,
+/// overridden by `--input-option`
+fn input_options<'d>(request: &Request, defaults: &[(&str, &str)]) -> Dictionary<'d> {
+    let mut options = Dictionary::new();
+    for (key, value) in defaults {
+        options.set(key, value);
+    }
+    // Set on the format context as it is opened, before the streams are looked for
+    if let Some(probe_size) = request.probe_size {
+        options.set("probesize", &probe_size.to_string());
+    }
+    if let Some(analyze_duration) = request.analyze_duration {
+        options.set("analyzeduration", &((analyze_duration * 1_000_000.0) as i64).to_string());
+    }
+    for (key, value) in &request.input_options {
+        options.set(key, value);
+    }
+    options
+}
+
+/// Opens the decoder for the stream, decoding on as many threads as `--decode-threads` asks for
+fn open_video_decoder(request: &Request, stream: &Stream) -> Result<VideoDecoder, TimelapseError> {
+    let mut context = stream.codec();
+    context.set_threading(threading::Config {
+        kind: threading::Type::Frame,
+        count: request.decode_threads,
+        safe: false,
+    });
+    Ok(context.decoder().video()?)
+}
+
+/// Finds the video stream to read from the input - the one given with `--video-stream`, or the one
+/// ffmpeg thinks is best
+fn find_video_stream<'i>(request: &Request, ictx: &'i InputContext) -> Result<Stream<'i>, TimelapseError> {
+    match request.video_stream {
+        Some(index) => {
+            let stream = ictx.stream(index)
+                .ok_or_else(|| TimelapseError::InvalidRequest(format!("the input has no stream #{}", index)))?;
+            if stream.codec().medium() != Type::Video {
+                return Err(TimelapseError::InvalidRequest(format!("stream #{} of the input is not a video stream", index)));
+            }
+            Ok(stream)
+        },
+        None => Ok(ictx.streams().best(Type::Video).ok_or(ffmpeg::Error::StreamNotFound)?),
+    }
+}
+
+/// Works out whether the input path names an image sequence, returning the pattern to hand to the
+/// image2 demuxer along with its `pattern_type`
+fn image_sequence_pattern(path: &Path) -> Result<Option<(PathBuf, &'static str)>, TimelapseError> {
+    if path.is_dir() {
+        let extension = fs::read_dir(path)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.path().extension().map(|e| e.to_string_lossy().to_ascii_lowercase()))
+            .filter(|extension| IMAGE_EXTENSIONS.contains(&extension.as_str()))
+            .min()
+            .ok_or_else(|| TimelapseError::InvalidRequest(format!("no images found in {}", path.display())))?;
+        return Ok(Some((path.join(format!("*.{}", extension)), "glob")));
+    }
+
+    let pathname = path.to_string_lossy();
+    if pathname.contains('*') || pathname.contains('?') {
+        Ok(Some((path.to_path_buf(), "glob")))
+    } else if pathname.contains('%') && !path.exists() {
+        Ok(Some((path.to_path_buf(), "sequence")))
+    } else {
+        Ok(None)
+    }
+}
+
+fn find_input_format(name: &str) -> Option<format::Input> {
+    let name = CString::new(name).ok()?;
+    unsafe {
+        let ptr = av_find_input_format(name.as_ptr());
+        if ptr.is_null() {
+            None
+        } else {
+            Some(format::Input::wrap(ptr as *mut _))
+        }
+    }
+}
 
 pub struct Decoder<'a> {
     request: &'a Request,
 
-    packet_iter: PacketIter<'a>,
+    /// Inputs which haven't been started on yet
+    inputs: IterMut<'a, InputContext>,
+    input_index: usize,
+    /// The input being read
+    input: &'a mut InputContext,
     decoder: VideoDecoder,
+    /// Whether the current input has run out and the frames the decoder still holds are being
+    /// taken from it before moving on to the next one
+    draining: bool,
     scaler: ScalingContext,
+    /// Clockwise rotation applied to every frame so that it comes out the way a player would show it
+    rotation: u32,
+    duplicate_filter: Option<DuplicateFilter>,
+    static_trimmer: Option<StaticTrimmer>,
 
     video_stream_id: usize,
-    num_frames: i64,
+    creation_time: Option<String>,
+    audio: Option<AudioStream>,
+    /// Audio packets read since the last call to `take_audio_packets`
+    audio_packets: Vec<Packet>,
+    /// Number of frames in the inputs, if their streams say
+    num_frames: Option<u64>,
+    /// Time base of the first input's video stream, which all frame timestamps are converted to
+    time_base: Rational,
+    /// Time base of the current input's video stream
+    input_time_base: Rational,
+    /// Added to the converted timestamps of the current input so that they carry on from the
+    /// inputs before it
+    pts_offset: i64,
+    last_timestamp: Option<i64>,
+    /// Number of frames left out by `--skip-errors` because they failed to decode or convert
+    skipped_frames: u64,
+    /// Number of frames read so far which `--sample-every` was applied to
+    sampled_frames: u64,
+    /// Length of one frame in `time_base`
+    frame_duration: i64,
+    frame_rate: Rational,
+    window_size: u32,
+    /// With `--window-duration`, the length of each window in `time_base`. Windows then end where
+    /// the timestamps say rather than after `window_size` frames.
+    window_pts: Option<i64>,
+    /// Frame read past the end of the last window, to start the next one with
+    pending: Option<VideoFrame>,
+    /// Number of frames at the end of each window which are carried over into the next one
+    window_overlap: u32,
+    /// Frames of the last window to start the next one with, and the index in that window of the
+    /// first of them
+    overlap: Vec<VideoFrame>,
+    overlap_start: usize,
+    /// Number of frames the last window returned carried over from the one before it
+    carried_frames: usize,
+    start_pts: Option<i64>,
+    end_pts: Option<i64>,
 }
 
 impl<'a> Decoder<'a> {
-    pub fn new(request: &'a Request, ictx: &'a mut InputContext) -> Result<Self, ffmpeg::Error> {
+    pub fn new(request: &'a Request, inputs: &'a mut [InputContext]) -> Result<Self, TimelapseError> {
+        // The frames of every input after the first one count towards the total as they are
+        let mut extra_frames = Some(0);
+        for ictx in inputs.iter().skip(1) {
+            let frames = frame_count(&find_video_stream(request, ictx)?);
+            extra_frames = extra_frames.and_then(|total| frames.map(|frames| total + frames));
+        }
+
+        let mut inputs = inputs.iter_mut();
+        let ictx = inputs.next().ok_or_else(|| TimelapseError::InvalidRequest(String::from("no input was given")))?;
+
         if request.verbose > 0 { dump_format(&ictx, 0, request.input_path().to_str()); }
 
-        if request.verbose > 1 { println!("TimelapseContext::new found {} streams in file", ictx.streams().count()); }
+        if request.verbose > 1 { log_message!("Decoder::new found {} streams in file", ictx.streams().count()); }
+
+        let stream = find_video_stream(request, ictx)?;
+        if request.verbose > 1 { log_message!("Decoder::new using video stream #{}", stream.index()); }
 
-        let stream = ictx.streams().best(Type::Video).ok_or(ffmpeg::Error::StreamNotFound)?;
-        if request.verbose > 2 { println!("TimelapseContext::new found video stream at #{}", stream.index()); }
+        let creation_time = ictx.metadata().get("creation_time")
+            .or_else(|| stream.metadata().get("creation_time"))
+            .map(String::from);
 
-        let num_frames = stream.frames();
-        if request.verbose > 2 { println!("TimelapseContext::new stream appears to have {} frames", num_frames); }
+        let audio = if request.keep_audio {
+            let audio_stream = ictx.streams().best(Type::Audio)
+                .ok_or_else(|| TimelapseError::InvalidRequest(String::from("--keep-audio was given but the input has no audio stream")))?;
+            if request.verbose > 1 { log_message!("Decoder::new copying audio stream #{}", audio_stream.index()); }
+            Some(AudioStream {
+                index: audio_stream.index(),
+                parameters: audio_stream.parameters().clone(),
+                time_base: audio_stream.time_base(),
+            })
+        } else {
+            None
+        };
+
+        let mut num_frames = frame_count(&stream);
+        if request.verbose > 2 {
+            match num_frames {
+                Some(num_frames) => log_message!("Decoder::new stream appears to have {} frames", num_frames),
+                None => log_message!("Decoder::new stream does not say how many frames it has"),
+            }
+        }
+
+        let time_base = stream.time_base();
+        // --start-time and --end-time are measured from the start of the stream, which isn't at 0
+        // in eg. MPEG-TS files or inputs cut without re-encoding
+        let stream_start = if stream.start_time() == AV_NOPTS_VALUE { 0 } else { stream.start_time() };
+        let start_pts = request.start_time.map(|t| stream_start + (t.as_micros() as i64).rescale(rescale::TIME_BASE, time_base));
+        let end_pts = request.end_time.map(|t| stream_start + (t.as_micros() as i64).rescale(rescale::TIME_BASE, time_base));
+        if start_pts.is_some() || end_pts.is_some() {
+            num_frames = num_frames.map(|num_frames| trimmed_frame_count(num_frames, stream_start, stream.duration(), start_pts, end_pts));
+            if request.verbose > 2 {
+                if let Some(num_frames) = num_frames { log_message!("Decoder::new trimmed stream appears to have {} frames", num_frames); }
+            }
+        }
+        // Trimming is worked out on the first input only, so there is no telling how much of the
+        // rest is left
+        num_frames = match extra_frames {
+            Some(0) => num_frames,
+            Some(extra_frames) if start_pts.is_none() && end_pts.is_none() => num_frames.map(|num_frames| num_frames + extra_frames),
+            _ => None,
+        };
+
+        let rotation = stream_rotation(&stream);
+        if request.verbose > 1 && rotation != 0 { log_message!("Decoder::new stream is rotated by {} degrees", rotation); }
 
         let video_stream_id = stream.index();
-        let decoder = stream.codec().decoder().video()?;
-        if request.verbose > 2 { println!("TimelapseContext::new codec appears to be {:?}", decoder.id()); }
+        let decoder = open_video_decoder(request, &stream)?;
+        if request.verbose > 2 { log_message!("Decoder::new codec appears to be {:?}", decoder.id()); }
 
-        let scaler = ScalingContext::get(
+        let (width, height) = rotated_size(decoder.width(), decoder.height(), rotation);
+        request.validate_for_frame_size(width, height)?;
+
+        let frame_rate = match stream_frame_rate(&decoder, &stream) {
+            Some(frame_rate) => frame_rate,
+            None => {
+                if request.verbose > 0 { log_message!("Cannot determine the frame rate of the input, assuming {} fps", DEFAULT_FRAME_RATE); }
+                Rational::new(DEFAULT_FRAME_RATE, 1)
+            },
+        };
+        let frame_duration = 1i64.rescale(frame_rate.invert(), time_base).max(1);
+
+        let (window_size, window_pts) = match request.window_duration {
+            Some(duration) => {
+                // Only used to estimate the number of output frames; the windows themselves end
+                // where the timestamps say
+                let window_size = window_size_for_duration(duration, frame_rate)
+                    .ok_or_else(|| TimelapseError::InvalidRequest(format!("cannot derive a window size from --window-duration {}", duration)))?;
+                let window_size = (window_size / request.sample_every).max(1);
+                let window_pts = ((duration * 1_000_000.0) as i64).rescale(rescale::TIME_BASE, time_base).max(1);
+                if request.verbose > 0 { log_message!("Using windows of {}s, about {} frames at {} fps", duration, window_size, frame_rate); }
+                (window_size, Some(window_pts))
+            },
+            None => {
+                let output_fps = request.playback_fps().unwrap_or_else(|| f64::from(frame_rate));
+                let target_size = match (request.target_duration, num_frames) {
+                    (Some(target_duration), Some(num_frames)) if request.comparison_mode != ComparisonMode::Motion => {
+                        let picked_frames = picked_frames_for_duration(request, target_duration, output_fps);
+                        let window_size = capped_window_size(sampled_frame_count(num_frames, request.sample_every), picked_frames, request.frames_per_window, request.window_overlap);
+                        if request.verbose > 0 { log_message!("Using windows of {} frames so that the output lasts {}s at {} fps", window_size, target_duration, output_fps); }
+                        Some(window_size)
+                    },
+                    (Some(_), None) => {
+                        if request.verbose > 0 { log_message!("Cannot determine the length of the input, using windows of {} frames rather than going by --target-duration", request.window_size); }
+                        None
+                    },
+                    _ => None,
+                };
+                let speed_size = request.speed.map(|speed| {
+                    let window_size = window_size_for_speed(request, speed, f64::from(frame_rate), output_fps);
+                    if request.verbose > 0 { log_message!("Using windows of {} frames so that the output plays {}x as fast as the input at {} fps", window_size, speed, output_fps); }
+                    window_size
+                });
+                (speed_size.or(target_size).unwrap_or(request.window_size), None)
+            },
+        };
+        let window_size = match (request.max_output_frames, num_frames, window_pts) {
+            // Motion mode doesn't go by windows, and windows of a set length can't be made longer
+            // by counting frames
+            (Some(max_output_frames), Some(num_frames), None) if request.comparison_mode != ComparisonMode::Motion => {
+                let capped_size = capped_window_size(sampled_frame_count(num_frames, request.sample_every), max_output_frames, request.frames_per_window, request.window_overlap);
+                if capped_size > window_size && request.verbose > 0 {
+                    log_message!("Using windows of {} frames so that the input fits in {} output frames", capped_size, max_output_frames);
+                }
+                window_size.max(capped_size)
+            },
+            _ => window_size,
+        };
+        let static_trimmer = if request.trim_static {
+            let tail_pts = ((request.static_tail * 1_000_000.0) as i64).rescale(rescale::TIME_BASE, time_base);
+            Some(StaticTrimmer::new(request.static_threshold, tail_pts))
+        } else {
+            None
+        };
+
+        // A window made of nothing but frames from the last one would never move on
+        if request.window_overlap >= window_size {
+            return Err(TimelapseError::InvalidRequest(format!("--window-overlap {} must be smaller than the window size of {} frames", request.window_overlap, window_size)));
+        }
+        let window_overlap = request.window_overlap;
+
+        let mut scaler = ScalingContext::get(
             decoder.format(),
             decoder.width(),
             decoder.height(),
-            Pixel::RGB24,
+            output_pixel_format(request),
             decoder.width(),
             decoder.height(),
-            ScalingFlags::BILINEAR
+            scaling_flags(request.scale_algo)
         )?;
+        // Full range inputs are brought down to the limited range, so that every YUV frame handed
+        // over uses the same one
+        let full_range = is_full_range_format(decoder.format()) || decoder.color_range() == color::Range::JPEG;
+        set_color_details(&mut scaler, decoder.color_space(), full_range, false);
+        if request.verbose > 2 {
+            log_message!("Decoder::new colour range {:?}, matrix {:?}, primaries {:?}, transfer {:?}",
+                decoder.color_range(), decoder.color_space(), decoder.color_primaries(), decoder.color_transfer_characteristic());
+        }
+
+        if let Some(start_time) = request.start_time {
+            // Lands on the nearest key frame before the start, next_frame discards the rest. Pipes
+            // can't seek, in which case next_frame has to discard everything up to the start.
+            let format_start = unsafe { (*ictx.as_ptr()).start_time };
+            let ts = start_time.as_micros() as i64 + if format_start == AV_NOPTS_VALUE { 0 } else { format_start };
+            if let Err(e) = ictx.seek(ts, ..ts) {
+                if request.verbose > 1 { log_message!("Decoder::new cannot seek the input ({}), reading it from the start", e); }
+            }
+        }
 
         Ok(Self {
             request,
 
             decoder,
+            draining: false,
             scaler,
+            rotation,
+            duplicate_filter: if request.dedup { Some(DuplicateFilter::new(request.dedup_epsilon)) } else { None },
+            static_trimmer,
             video_stream_id,
+            creation_time,
+            audio,
+            audio_packets: Vec::new(),
             num_frames,
+            time_base,
+            input_time_base: time_base,
+            pts_offset: 0,
+            last_timestamp: None,
+            skipped_frames: 0,
+            sampled_frames: 0,
+            frame_duration,
+            frame_rate,
+            window_size,
+            window_pts,
+            pending: None,
+            window_overlap,
+            overlap: Vec::new(),
+            overlap_start: 0,
+            carried_frames: 0,
+            start_pts,
+            end_pts,
 
-            packet_iter: ictx.packets(),
+            inputs,
+            input_index: 0,
+            input: ictx,
         })
     }
 
+    /// Moves on to the next input once the current one has run out, making sure its frames match
+    /// those of the first input. Returns false if there are no inputs left.
+    fn next_input(&mut self) -> Result<bool, TimelapseError> {
+        let ictx = match self.inputs.next() {
+            Some(ictx) => ictx,
+            None => return Ok(false),
+        };
+        self.input_index += 1;
+        let input_path = self.request.input_paths().get(self.input_index);
+        if self.request.verbose > 0 { dump_format(&ictx, 0, input_path.and_then(|path| path.to_str())); }
+
+        let (video_stream_id, decoder, input_time_base, stream_start) = {
+            let stream = find_video_stream(self.request, ictx)?;
+            (stream.index(), open_video_decoder(self.request, &stream)?, stream.time_base(), stream.start_time())
+        };
+        if self.request.verbose > 1 { log_message!("decoder::next_input: moving on to input #{}, video stream #{}", self.input_index, video_stream_id); }
+
+        let expected = self.scaler.input();
+        if decoder.width() != expected.width || decoder.height() != expected.height || decoder.format() != expected.format {
+            return Err(TimelapseError::InputMismatch(format!(
+                "input #{} is {}x{} {:?} but the first input is {}x{} {:?}",
+                self.input_index, decoder.width(), decoder.height(), decoder.format(), expected.width, expected.height, expected.format)));
+        }
+
+        // The first frame of this input follows straight on from the last frame of the one before
+        let stream_start = if stream_start == AV_NOPTS_VALUE { 0 } else { stream_start.rescale(input_time_base, self.time_base) };
+        self.pts_offset = self.last_timestamp.map_or(0, |last| last + self.frame_duration) - stream_start;
+
+        self.decoder = decoder;
+        self.video_stream_id = video_stream_id;
+        self.input_time_base = input_time_base;
+        self.input = ictx;
+        Ok(true)
+    }
+
     pub fn get_info(&self) -> VideoInfo<Rational> {
+        let (width, height) = rotated_size(self.decoder.width(), self.decoder.height(), self.rotation);
         VideoInfo {
-            width: self.decoder.width(),
-            height: self.decoder.height(),
-            frame_rate: self.decoder.frame_rate().unwrap(),
-            timebase: self.decoder.time_base(),
+            width,
+            height,
+            frame_rate: self.frame_rate,
+            timebase: self.time_base,
             total_frames: self.num_frames,
-            decoded_pixel_format: output_pixel_format(self.request.comparison_mode),
+            window_size: self.window_size,
+            window_overlap: self.window_overlap,
+            decoded_pixel_format: self.scaler.output().format,
+            color_space: self.decoder.color_space(),
+            color_primaries: self.decoder.color_primaries(),
+            color_transfer: self.decoder.color_transfer_characteristic(),
         }
     }
 
+    /// The `creation_time` tag of the input, if it has one
+    pub fn creation_time(&self) -> Option<&str> {
+        self.creation_time.as_ref().map(String::as_str)
+    }
+
+    /// The audio stream to copy into the output, if `--keep-audio` was given
+    pub fn audio_stream(&self) -> Option<&AudioStream> {
+        self.audio.as_ref()
+    }
+
+    /// Audio packets read from the input along with the frames returned so far
+    pub fn take_audio_packets(&mut self) -> Vec<Packet> {
+        mem::replace(&mut self.audio_packets, Vec::new())
+    }
+
+    /// Number of input frames each output frame is picked from
+    pub fn window_size(&self) -> u32 {
+        self.window_size
+    }
+
+    /// Number of frames left out so far because they failed to decode or convert
+    pub fn skipped_frames(&self) -> u64 {
+        self.skipped_frames
+    }
+
+    /// Number of frames at the start of the last window which were carried over from the window
+    /// before it, as `--window-overlap` asks for
+    pub fn carried_frames(&self) -> usize {
+        self.carried_frames
+    }
+
+    /// Stops the frames up to and including the given index of the last window from being carried
+    /// over into the next one, so that a frame which has been picked is not offered again
+    pub fn drop_overlap_through(&mut self, window_index: usize) {
+        if window_index >= self.overlap_start {
+            let dropped = (window_index + 1 - self.overlap_start).min(self.overlap.len());
+            self.overlap.drain(..dropped);
+            self.overlap_start += dropped;
+        }
+    }
+
+    /// Loads an image and converts it to the size and pixel format of the frames this decoder
+    /// returns, so that it can be compared against them
+    pub fn load_reference(&self, path: &Path) -> Result<VideoFrame, TimelapseError> {
+        let image = image::open(path)?.to_rgb();
+        let (width, height) = image.dimensions();
+
+        let mut rgb_frame = VideoFrame::new(Pixel::RGB24, width, height);
+        let row_len = width as usize * 3;
+        let stride = rgb_frame.stride(0);
+        for (y, row) in image.as_raw().chunks_exact(row_len).enumerate() {
+            rgb_frame.data_mut(0)[(y * stride)..(y * stride + row_len)].copy_from_slice(row);
+        }
+
+        let output = self.scaler.output();
+        let (output_width, output_height) = rotated_size(output.width, output.height, self.rotation);
+        let mut scaler = ScalingContext::get(
+            Pixel::RGB24,
+            width,
+            height,
+            output.format,
+            output_width,
+            output_height,
+            scaling_flags(self.request.scale_algo)
+        )?;
+        set_color_details(&mut scaler, self.decoder.color_space(), true, false);
+        let mut reference = VideoFrame::empty();
+        scaler.run(&rgb_frame, &mut reference)?;
+        Ok(reference)
+    }
+
+    pub fn next_window<'x>(&'x mut self) -> Result<Vec<VideoFrame>, TimelapseError> {
+        let mut window = mem::replace(&mut self.overlap, Vec::new());
+        self.carried_frames = window.len();
 
-    pub fn next_window<'x>(&'x mut self) -> Result<Vec<VideoFrame>, ffmpeg::Error> {
-        let mut window = Vec::<VideoFrame>::new();
+        let mut window_start = None;
+        loop {
+            let frame = match self.pending.take() {
+                Some(frame) => frame,
+                None => match self.next_frame() {
+                    Ok(frame) => frame,
+                    Err(TimelapseError::Ffmpeg(ffmpeg::Error::Eof)) => break,
+                    Err(e) => return Err(e)
+                },
+            };
 
-        while window.len() < self.request.window_size as usize {
-            match self.next_frame() {
-                Ok(frame) => window.push(frame),
-                Err(ffmpeg::Error::Eof) => break,
-                Err(e) => return Err(e)
+            // Windows of `--window-duration` cover the same length of time even when frames don't
+            // come at a steady rate; frames without a timestamp are counted instead
+            if let (Some(window_pts), Some(pts)) = (self.window_pts, frame.pts()) {
+                let start = *window_start.get_or_insert(pts);
+                if pts - start >= window_pts {
+                    self.pending = Some(frame);
+                    break;
+                }
+                window.push(frame);
+            } else {
+                window.push(frame);
+                if window.len() >= self.window_size as usize {
+                    break;
+                }
             }
         }
 
-        if window.is_empty() {
-            Err(ffmpeg::Error::Eof)
+        if window.len() == self.carried_frames {
+            Err(ffmpeg::Error::Eof.into())
         } else {
+            self.overlap_start = window.len() - (self.window_overlap as usize).min(window.len());
+            self.overlap = window[self.overlap_start..].to_vec();
             Ok(window)
         }
     }
 
-    pub fn next_frame<'x>(&'x mut self) -> Result<VideoFrame, ffmpeg::Error> {
+    /// Reads the next packet of the current input, or `None` once it has run out, trying again as
+    /// `--read-retries` allows
+    fn read_packet(&mut self) -> Result<Option<Packet>, TimelapseError> {
+        let input = &mut *self.input;
+        let read = read_with_retries(self.request.read_retries, READ_RETRY_DELAY, self.request.verbose > 0, || {
+            let mut packet = Packet::empty();
+            packet.read(input).map(|()| packet)
+        });
+        match read {
+            Ok(packet) => Ok(Some(packet)),
+            Err(ffmpeg::Error::Eof) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn next_frame<'x>(&'x mut self) -> Result<VideoFrame, TimelapseError> {
         let mut skip_count = self.request.frame_skip;
 
         loop {
-            match self.packet_iter.next() {
-                Some((s, packet)) => {
-                    if s.index() != self.video_stream_id {
-                        if self.request.verbose > 2 { println!("decoder::next_frame: skip packet {} (stream {} != video stream {})", packet.position(), s.index(), self.video_stream_id); }
+            let packet = if self.draining { None } else { self.read_packet()? };
+            let packet = match packet {
+                Some(packet) => {
+                    let stream_index = packet.stream();
+                    if self.audio.as_ref().map_or(false, |audio| audio.index == stream_index) {
+                        self.audio_packets.push(packet);
+                        continue;
+                    }
+
+                    if stream_index != self.video_stream_id {
+                        if self.request.verbose > 2 { log_message!("decoder::next_frame: skip packet {} (stream {} != video stream {})", packet.position(), stream_index, self.video_stream_id); }
                         continue;
                     }
 
                     let is_key = packet.flags().intersects(PacketFlags::KEY);
                     if self.request.key_frames_only && !is_key {
-                        if self.request.verbose > 2 { println!("decoder::next_frame: skip packet {} (not a key frame but --key-frames-only is set)", packet.position()); }
+                        if self.request.verbose > 2 { log_message!("decoder::next_frame: skip packet {} (not a key frame but --key-frames-only is set)", packet.position()); }
                         continue;
                     }
 
                     if skip_count > 0 {
-                        if self.request.verbose > 2 { println!("decoder::next_frame: skip packet {} (skip count = {})", packet.position(), skip_count); }
+                        if self.request.verbose > 2 { log_message!("decoder::next_frame: skip packet {} (skip count = {})", packet.position(), skip_count); }
                         skip_count -= 1;
                         continue;
                     }
 
-                    let mut frame = VideoFrame::empty();
-                    self.decoder.decode(&packet, &mut frame)?;
+                    packet
+                },
+                None => {
+                    // The decoder holds on to the last few frames of the input, all the more so
+                    // with frame threading, until it is given an empty packet to flush them out
+                    if self.request.verbose > 2 && !self.draining { log_message!("decoder::next_frame: input #{} has run out, draining the decoder", self.input_index); }
+                    self.draining = true;
+                    Packet::empty()
+                },
+            };
+
+            let mut frame = VideoFrame::empty();
+            let got_frame = match self.decoder.decode(&packet, &mut frame) {
+                Ok(got_frame) => got_frame,
+                Err(ffmpeg::Error::Eof) if self.draining => false,
+                Err(ffmpeg::Error::Eof) => return Err(ffmpeg::Error::Eof.into()),
+                Err(e) if self.request.skip_errors && !self.draining => {
+                    if self.request.verbose > 0 { log_message!("decoder::next_frame: skip packet {} (cannot decode it: {})", packet.position(), e); }
+                    self.skipped_frames += 1;
+                    continue;
+                },
+                Err(e) => return Err(e.into()),
+            };
+
+            if self.draining && !got_frame {
+                self.draining = false;
+                if !self.next_input()? {
+                    return Err(ffmpeg::Error::Eof.into());
+                }
+                continue;
+            }
+
+            if unsafe { frame.is_empty() } {
+                if self.request.verbose > 2 { log_message!("decoder::next_frame: skip empty frame at {}", packet.position()); }
+                continue;
+            }
+
+            let timestamp = frame.timestamp().map(|ts| ts.rescale(self.input_time_base, self.time_base) + self.pts_offset);
+            if let Some(timestamp) = timestamp {
+                self.last_timestamp = Some(timestamp);
+                if self.start_pts.map_or(false, |start| timestamp < start) {
+                    if self.request.verbose > 2 { log_message!("decoder::next_frame: skip frame at {} (before --start-time)", packet.position()); }
+                    continue;
+                }
+                if self.end_pts.map_or(false, |end| timestamp > end) {
+                    if self.request.verbose > 2 { log_message!("decoder::next_frame: stop at frame {} (after --end-time)", packet.position()); }
+                    return Err(ffmpeg::Error::Eof.into());
+                }
+            }
+
+            if frame.width() != self.scaler.input().width || frame.height() != self.scaler.input().height {
+                return Err(TimelapseError::InputMismatch(format!(
+                    "frame at {} is {}x{} but the input started out as {}x{}",
+                    packet.position(), frame.width(), frame.height(), self.scaler.input().width, self.scaler.input().height)));
+            }
+
+            // Frames still have to be decoded for the ones after them to decode, but those which
+            // aren't sampled needn't be scaled or compared
+            let sampled = self.sampled_frames % u64::from(self.request.sample_every) == 0;
+            self.sampled_frames += 1;
+            if !sampled {
+                if self.request.verbose > 2 { log_message!("decoder::next_frame: skip frame at {} (not sampled)", packet.position()); }
+                continue;
+            }
+
+            let mut scaled_frame = VideoFrame::empty();
+            if let Err(e) = self.scaler.run(&frame, &mut scaled_frame) {
+                if !self.request.skip_errors {
+                    return Err(e.into());
+                }
+                if self.request.verbose > 0 { log_message!("decoder::next_frame: skip frame at {} (cannot convert it: {})", packet.position(), e); }
+                self.skipped_frames += 1;
+                continue;
+            }
+            if self.rotation != 0 {
+                scaled_frame = rotate_frame(&scaled_frame, self.rotation);
+            }
+            scaled_frame.set_pts(timestamp);
 
-                    if unsafe { frame.is_empty() } {
-                        if self.request.verbose > 2 { println!("decoder::next_frame: skip empty frame at {}", packet.position()); }
+            if let Some(duplicate_filter) = self.duplicate_filter.as_mut() {
+                if duplicate_filter.is_duplicate(&scaled_frame) {
+                    if self.request.verbose > 2 { log_message!("decoder::next_frame: skip frame at {} (same as the previous frame)", packet.position()); }
+                    continue;
+                }
+            }
+
+            if let Some(static_trimmer) = self.static_trimmer.as_mut() {
+                match static_trimmer.check(&scaled_frame) {
+                    Trim::Keep => {},
+                    Trim::Skip => {
+                        if self.request.verbose > 2 { log_message!("decoder::next_frame: skip frame at {} (scene has not changed yet)", packet.position()); }
                         continue;
-                    }
+                    },
+                    Trim::Stop => {
+                        if self.request.verbose > 1 { log_message!("decoder::next_frame: stop at frame {} (scene has stayed still for {}s)", packet.position(), self.request.static_tail); }
+                        return Err(ffmpeg::Error::Eof.into());
+                    },
+                }
+            }
 
-                    let mut scaled_frame = VideoFrame::empty();
-                    self.scaler.run(&frame, &mut scaled_frame)?;
+            return Ok(scaled_frame);
+        }
+    }
+}
 
-                    return Ok(scaled_frame);
+/// Calls `read` until it succeeds or reaches the end of the input. With `retries`, a failed call is
+/// tried again after a pause which starts at `delay` and grows with every attempt, until that many
+/// have failed in a row; without, the first failure is returned.
+fn read_with_retries<T, F>(retries: Option<u32>, delay: Duration, verbose: bool, mut read: F) -> Result<T, ffmpeg::Error>
+where F: FnMut() -> Result<T, ffmpeg::Error> {
+    let mut failures = 0;
+    loop {
+        match read() {
+            Err(ffmpeg::Error::Eof) => return Err(ffmpeg::Error::Eof),
+            Err(e) => match retries {
+                Some(retries) if failures < retries => {
+                    failures += 1;
+                    if verbose { log_message!("decoder::read_packet: cannot read the input ({}), trying again ({} of {})", e, failures, retries); }
+                    thread::sleep(delay * failures);
                 },
-                None => return Err(ffmpeg::Error::Eof),
-            }
+                _ => return Err(e),
+            },
+            result => return result,
         }
     }
 }
 
-fn output_pixel_format(comparison_mode: ComparisonMode) -> Pixel {
-    match comparison_mode {
-        ComparisonMode::Blockhash | ComparisonMode::GradientHash | ComparisonMode::MeanHash => Pixel::RGB24,
-        ComparisonMode::MSE | ComparisonMode::SSIM | ComparisonMode::Noop => Pixel::YUV420P
+/// Clockwise rotation (0, 90, 180 or 270 degrees) a player would apply to the stream, taken from
+/// its display matrix or, failing that, its `rotate` tag
+fn stream_rotation(stream: &Stream) -> u32 {
+    let from_matrix = stream.side_data()
+        .find(|side_data| side_data.kind() == SideDataType::DisplayMatrix && side_data.data().len() >= 9 * 4)
+        // The display matrix holds the counter-clockwise rotation
+        .map(|side_data| -unsafe { av_display_rotation_get(side_data.data().as_ptr() as *const i32) });
+    let degrees = from_matrix
+        .or_else(|| stream.metadata().get("rotate").and_then(|rotate| rotate.parse::<f64>().ok()))
+        .unwrap_or(0.0);
+    if !degrees.is_finite() {
+        return 0;
+    }
+
+    // Snap to the nearest quarter turn
+    ((degrees / 90.0).round() as i64).rem_euclid(4) as u32 * 90
+}
+
+/// Size of a frame after it has been rotated clockwise by the given number of degrees
+fn rotated_size(width: u32, height: u32, rotation: u32) -> (u32, u32) {
+    match rotation {
+        90 | 270 => (height, width),
+        _ => (width, height),
     }
 }
 
+/// Pixel format frames are handed over in: the one the selector works on, so that eg. MSE reads the
+/// luma straight from a YUV plane rather than working it out from RGB
+fn output_pixel_format(request: &Request) -> Pixel {
+    if request.bit_depth > 8 {
+        return Pixel::YUV420P10LE;
+    }
+
+    required_pixel_format(request)
+}
+
+/// Works out the frame rate of the stream. Variable frame rate streams often don't have one set, in
+/// which case the average rate is used, or failing that the number of frames over the length of
+/// the stream.
+fn stream_frame_rate(decoder: &VideoDecoder, stream: &Stream) -> Option<Rational> {
+    let is_valid = |rate: &Rational| rate.numerator() > 0 && rate.denominator() > 0;
+    decoder.frame_rate().filter(is_valid)
+        .or_else(|| Some(stream.avg_frame_rate()).filter(is_valid))
+        .or_else(|| Some(stream.rate()).filter(is_valid))
+        .or_else(|| {
+            let seconds = stream.duration() as f64 * f64::from(stream.time_base());
+            if stream.frames() > 0 && stream.duration() > 0 && seconds > 0.0 {
+                Some(Rational::from(stream.frames() as f64 / seconds)).filter(is_valid)
+            } else {
+                None
+            }
+        })
+}
+
+/// Number of frames to pick for the output to play for the given number of seconds, taking the
+/// frames `--boomerang` and `--blend` add into account
+fn picked_frames_for_duration(request: &Request, duration: f64, output_fps: f64) -> u64 {
+    let output_frames = ((duration * output_fps).round() as u64).max(1);
+    // Blended frames only go between the frames written
+    let written_frames = (output_frames - 1) / (request.blend as u64 + 1) + 1;
+    // Boomerang writes every picked frame but the first and last again
+    if request.boomerang {
+        (written_frames + 2) / 2
+    } else {
+        written_frames
+    }
+}
+
+/// Window size which makes the output play `speed` times as fast as the input
+fn window_size_for_speed(request: &Request, speed: f64, input_fps: f64, output_fps: f64) -> u32 {
+    let frames_per_window = f64::from(request.frames_per_window.max(1));
+    // Blended frames make each picked frame last longer in the output, and each frame in a window
+    // stands for `--sample-every` input frames
+    let output_frames_per_picked_frame = (request.blend + 1) as f64;
+    let window_step = speed * input_fps * frames_per_window * output_frames_per_picked_frame / (output_fps * f64::from(request.sample_every));
+    // Each window has to move on by at least a frame and hold at least as many frames as get
+    // picked from it
+    let window_size = (window_step.round().max(1.0) + f64::from(request.window_overlap)).max(frames_per_window);
+    window_size.min(f64::from(u32::max_value())) as u32
+}
+
+/// Smallest window size which picks no more than `max_output_frames` from the given number of
+/// frames
+fn capped_window_size(num_frames: u64, max_output_frames: u64, frames_per_window: u32, window_overlap: u32) -> u32 {
+    let frames_per_window = u64::from(frames_per_window.max(1));
+    let max_windows = (max_output_frames / frames_per_window).max(1);
+    let window_step = num_frames / max_windows + if num_frames % max_windows > 0 { 1 } else { 0 };
+    // Each window has to hold at least as many frames as get picked from it
+    let window_size = (window_step + u64::from(window_overlap)).max(frames_per_window);
+    window_size.min(u64::from(u32::max_value())) as u32
+}
+
+/// Number of frames covering the given number of seconds at the frame rate, if it is at least one
+fn window_size_for_duration(duration: f64, frame_rate: Rational) -> Option<u32> {
+    if frame_rate.numerator() <= 0 || frame_rate.denominator() <= 0 {
+        return None;
+    }
+
+    let frames = (duration * f64::from(frame_rate)).round();
+    if frames >= 1.0 && frames <= f64::from(u32::max_value()) {
+        Some(frames as u32)
+    } else {
+        None
+    }
+}
+
+/// Estimates how many of the stream's frames fall between the start and end timestamps, assuming
+/// they are evenly spread across its duration. All three are in the stream's time base, the start
+/// timestamps being where the stream starts rather than 0.
+fn trimmed_frame_count(num_frames: u64, stream_start: i64, duration: i64, start_pts: Option<i64>, end_pts: Option<i64>) -> u64 {
+    if duration <= 0 {
+        return num_frames;
+    }
+
+    let stream_end = stream_start + duration;
+    let first = start_pts.unwrap_or(stream_start).max(stream_start);
+    let last = end_pts.unwrap_or(stream_end).min(stream_end);
+    let kept = (last - first).max(0);
+    ((num_frames as i128 * kept as i128) / duration as i128) as u64
+}
+
+/// Number of frames in the stream, if it says. Streams without a frame count report 0.
+fn frame_count(stream: &Stream) -> Option<u64> {
+    match stream.frames() {
+        frames if frames > 0 => Some(frames as u64),
+        _ => None,
+    }
+}
+
+/// An input audio stream which gets copied into the output without being decoded
+pub struct AudioStream {
+    pub index: usize,
+    pub parameters: CodecParameters,
+    pub time_base: Rational,
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct VideoInfo<R: Into<Rational> + Copy + Clone> {
     pub width: u32,
     pub height: u32,
     pub frame_rate: R,
+    /// Time base of the timestamps of decoded frames
     pub timebase: R,
-    pub total_frames: i64,
+    /// Number of frames in the input, if it can be told
+    pub total_frames: Option<u64>,
+    /// Number of input frames each output frame is picked from
+    pub window_size: u32,
+    /// Number of frames each window shares with the one before it
+    pub window_overlap: u32,
     pub decoded_pixel_format: Pixel,
+    /// Matrix the decoded YUV frames use. They always use the limited range of values.
+    pub color_space: color::Space,
+    pub color_primaries: color::Primaries,
+    pub color_transfer: color::TransferCharacteristic,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A read which fails the given number of times before it succeeds, counting its calls
+    fn flaky_read(failures: u32, calls: &mut u32) -> impl FnMut() -> Result<u32, ffmpeg::Error> + '_ {
+        move || {
+            *calls += 1;
+            if *calls <= failures { Err(ffmpeg::Error::Unknown) } else { Ok(*calls) }
+        }
+    }
+
+    #[test]
+    fn read_with_retries_succeeds_after_failing_a_couple_of_times() {
+        let mut calls = 0;
+        let read = read_with_retries(Some(3), Duration::from_millis(0), false, flaky_read(2, &mut calls));
+        assert_eq!(read.ok(), Some(3));
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn read_with_retries_gives_up_once_the_retries_run_out() {
+        let mut calls = 0;
+        let read = read_with_retries(Some(1), Duration::from_millis(0), false, flaky_read(2, &mut calls));
+        assert!(matches!(read, Err(ffmpeg::Error::Unknown)));
+        assert_eq!(calls, 2);
+    }
+
+    #[test]
+    fn read_with_retries_returns_the_first_failure_without_retries() {
+        let mut calls = 0;
+        let read = read_with_retries(None, Duration::from_millis(0), false, flaky_read(2, &mut calls));
+        assert!(matches!(read, Err(ffmpeg::Error::Unknown)));
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn read_with_retries_does_not_retry_the_end_of_the_input() {
+        let mut calls = 0;
+        let read = read_with_retries(Some(3), Duration::from_millis(0), false, || -> Result<(), ffmpeg::Error> {
+            calls += 1;
+            Err(ffmpeg::Error::Eof)
+        });
+        assert!(matches!(read, Err(ffmpeg::Error::Eof)));
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn window_size_for_speed_makes_the_output_that_much_faster() {
+        let mut request = Request::new();
+        assert_eq!(window_size_for_speed(&request, 10.0, 25.0, 25.0), 10);
+        // Playing back at twice the frame rate already doubles the speed
+        assert_eq!(window_size_for_speed(&request, 10.0, 25.0, 50.0), 5);
+        request.set_frames_per_window(2);
+        assert_eq!(window_size_for_speed(&request, 10.0, 30.0, 30.0), 20);
+
+        let mut request = Request::new();
+        request.blend = 1;
+        assert_eq!(window_size_for_speed(&request, 10.0, 25.0, 25.0), 20);
+
+        let mut request = Request::new();
+        request.set_sample_every(2);
+        assert_eq!(window_size_for_speed(&request, 10.0, 25.0, 25.0), 5);
+    }
+
+    #[test]
+    fn window_size_for_speed_moves_on_by_at_least_a_frame() {
+        let mut request = Request::new();
+        assert_eq!(window_size_for_speed(&request, 0.1, 25.0, 25.0), 1);
+        request.set_window_overlap(2);
+        assert_eq!(window_size_for_speed(&request, 10.0, 25.0, 25.0), 12);
+        request.set_window_overlap(0).set_frames_per_window(3);
+        assert_eq!(window_size_for_speed(&request, 0.1, 25.0, 25.0), 3);
+    }
+
+    #[test]
+    fn capped_window_size_rounds_up() {
+        assert_eq!(capped_window_size(1000, 100, 1, 0), 10);
+        assert_eq!(capped_window_size(1001, 100, 1, 0), 11);
+        assert_eq!(capped_window_size(1000, 100, 2, 0), 20);
+        assert_eq!(capped_window_size(1000, 100, 1, 5), 15);
+    }
+
+    #[test]
+    fn capped_window_size_holds_at_least_the_frames_picked_from_it() {
+        assert_eq!(capped_window_size(10, 100, 4, 0), 4);
+        // Fewer output frames than get picked from one window still leaves one window
+        assert_eq!(capped_window_size(1000, 1, 2, 0), 1000);
+    }
+
+    #[test]
+    fn picked_frames_for_duration_fill_the_duration() {
+        let mut request = Request::new();
+        assert_eq!(picked_frames_for_duration(&request, 10.0, 25.0), 250);
+        // A very short duration still needs a frame
+        assert_eq!(picked_frames_for_duration(&request, 0.01, 25.0), 1);
+
+        // 126 frames with a blended frame between each pair make 251
+        request.set_blend(1);
+        assert_eq!(picked_frames_for_duration(&request, 10.04, 25.0), 126);
+        assert_eq!(picked_frames_for_duration(&request, 10.0, 25.0), 125);
+
+        // 126 frames and then 124 of them again backwards make 250
+        request.set_blend(0).set_boomerang(true);
+        assert_eq!(picked_frames_for_duration(&request, 10.0, 25.0), 126);
+    }
+
+    #[test]
+    fn frames_are_decoded_to_the_format_of_the_selector() {
+        let mut request = Request::new();
+        request.set_comparison_mode(ComparisonMode::Histogram);
+        assert_eq!(output_pixel_format(&request), Pixel::RGB24);
+        request.set_comparison_mode(ComparisonMode::MSE);
+        assert_eq!(output_pixel_format(&request), Pixel::YUV420P);
+        // Keeping 10 bits takes over from whatever the selector asks for
+        request.set_bit_depth(10);
+        assert_eq!(output_pixel_format(&request), Pixel::YUV420P10LE);
+    }
+
+    #[test]
+    fn trimmed_frame_count_keeps_the_frames_between_start_and_end() {
+        // 100 frames over 4000 ticks
+        assert_eq!(trimmed_frame_count(100, 0, 4000, None, None), 100);
+        assert_eq!(trimmed_frame_count(100, 0, 4000, Some(1000), None), 75);
+        assert_eq!(trimmed_frame_count(100, 0, 4000, None, Some(1000)), 25);
+        assert_eq!(trimmed_frame_count(100, 0, 4000, Some(1000), Some(2000)), 25);
+        assert_eq!(trimmed_frame_count(100, 0, 4000, Some(3000), Some(1000)), 0);
+        // Without a duration there is nothing to go on
+        assert_eq!(trimmed_frame_count(100, 0, 0, Some(1000), None), 100);
+    }
+
+    #[test]
+    fn trimmed_frame_count_measures_from_the_start_of_the_stream() {
+        // A stream starting at 90000, as MPEG-TS ones often do, trimmed to its second quarter
+        assert_eq!(trimmed_frame_count(100, 90_000, 4000, Some(91_000), Some(92_000)), 25);
+        // Timestamps before the start of the stream don't count
+        assert_eq!(trimmed_frame_count(100, 90_000, 4000, Some(0), Some(91_000)), 25);
+        assert_eq!(trimmed_frame_count(100, 90_000, 4000, None, Some(1000)), 0);
+    }
 }
@@ -1,114 +1,496 @@
-use std::mem::MaybeUninit;
+use std::ffi::{CStr, CString};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::ptr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use ffmpeg::ffi::{avcodec_parameters_copy, avformat_alloc_output_context2, avformat_free_context, avformat_new_stream, avio_open, AVFMT_NOFILE, AVIO_FLAG_WRITE};
 use ffmpeg::format::Pixel;
-use ffmpeg::software::scaling::{flag::Flags};
-use ffmpeg::util::frame;
-use ffmpeg::format::{output_as, context::Output as OutputContext, context::output::dump as dump_format};
-use ffmpeg::codec::{Id as CodecId};
-use ffmpeg::codec::encoder::{find as find_codec};
+use ffmpeg::util::{color, frame};
+use ffmpeg::format::{context::Output as OutputContext, context::output::dump as dump_format};
+use ffmpeg::codec::{flag::Flags as CodecFlags, Id as CodecId};
+use ffmpeg::codec::encoder::{find as find_codec, find_by_name as find_codec_by_name};
 use ffmpeg::encoder::{Video as VideoEncoder};
-use ffmpeg::Rational;
+use ffmpeg::{Dictionary, Rational};
 use ffmpeg::Packet;
 
-use crate::request::Request;
-use crate::decoder::VideoInfo;
+use crate::error::TimelapseError;
+use crate::{output_frame_count, sampled_frame_count};
+use crate::request::{ffmpeg_path, is_stdio, ColorRange, ComparisonMode, HwAccel, Request, OutputCodec, Preset, Rect, ScaleAlgorithm};
+use crate::decoder::{check_unused_options, AudioStream, VideoInfo};
+use crate::frame_cache::{FrameCacheReader, FrameCacheWriter};
+use crate::hwaccel::{self, HwFrames};
+use crate::palette::Palette;
+use crate::overlay::TimestampOverlay;
+use crate::webvtt::WebVtt;
+use crate::post_processing::{blend_frames, crop_frame, desaturate, is_full_range_format, scaling_flags, set_color_details, Deflicker, Fade, Smoother};
 
 type ScalingContext = ffmpeg::software::scaling::Context;
 type VideoFrame = frame::Video;
 
-struct EncInit<'a, R: Into<Rational> + Copy + Clone> {
-    video_info: &'a VideoInfo<R>,
-    output: MaybeUninit<OutputContext>,
-    scaler: MaybeUninit<ScalingContext>,
-    encoder: MaybeUninit<VideoEncoder>,
-    stream_index: usize,
-}
-
-impl<'a, R> EncInit<'a, R>
-where R: Into<Rational> + Copy + Clone {
-    unsafe fn assume_init(self) -> Encoder<'a, R> {
-        Encoder {
-            video_info: self.video_info,
-            output: self.output.assume_init(),
-            scaler: self.scaler.assume_init(),
-            encoder: self.encoder.assume_init(),
-            stream_index: self.stream_index,
-            pts: 0,
-        }
-    }
+/// Which pass of a two-pass encode an encoder runs, with the file the statistics gathered in the
+/// first pass are written to and read back from in the second
+#[derive(Debug, Copy, Clone)]
+pub enum Pass<'p> {
+    First(&'p Path),
+    Second(&'p Path),
 }
 
-pub struct Encoder<'a, R: Into<Rational> + Copy + Clone> {
-    video_info: &'a VideoInfo<R>,
+pub struct Encoder {
     output: OutputContext,
     scaler: ScalingContext,
+    scale_algo: ScaleAlgorithm,
+    /// Matrix of the YUV frames going into and out of the scaler
+    color_space: color::Space,
+    /// Whether the scaler writes YUV frames in the full range of values
+    full_range: bool,
+    crop: Option<Rect>,
+    grayscale: bool,
     encoder: VideoEncoder,
+    /// First pass statistics handed to the encoder in the second pass, which must outlive it
+    stats_in: Option<CString>,
+    stats_file: Option<File>,
     stream_index: usize,
-    pts: i64,
+    /// Time base of the copied input audio stream and the index of the output stream it goes to
+    audio: Option<(Rational, usize)>,
+    frame_rate: Rational,
+    pacing: Pacing,
+    deflicker: Option<Deflicker>,
+    smoother: Option<Smoother>,
+    fade: Option<Fade>,
+    /// Number of frames to blend between each pair of frames, and the last frame given
+    blend: usize,
+    last_frame: Option<VideoFrame>,
+    timestamp: Option<TimestampOverlay>,
+    /// With `--boomerang`, the file every frame given is kept in until they are written again
+    /// backwards
+    boomerang: Option<(PathBuf, FrameCacheWriter)>,
+    gif_frames: Option<Vec<VideoFrame>>,
+    hw_frames: Option<HwFrames>,
+    /// With `--webvtt`, the path to write the track to and the thumbnails taken of the frames
+    /// written so far
+    webvtt: Option<(PathBuf, WebVtt)>,
 }
 
-impl<'a, R> Encoder<'a, R>
-where R: Into<Rational> + Copy + Clone {
-    const PIXEL_FORMAT: Pixel = Pixel::YUV420P;
-    pub fn new(request: &'a Request, video_info: &'a VideoInfo<R>) -> Result<Self, ffmpeg::Error> {
-        let mut this = EncInit {
-            video_info,
-            output: MaybeUninit::<OutputContext>::uninit(),
-            scaler: MaybeUninit::<ScalingContext>::uninit(),
-            encoder: MaybeUninit::<VideoEncoder>::uninit(),
-            stream_index: 0,
+impl Encoder {
+    pub fn new<R>(request: &Request, video_info: &VideoInfo<R>, audio_stream: Option<&AudioStream>, source_creation_time: Option<&str>, pass: Option<Pass>) -> Result<Self, TimelapseError>
+    where R: Into<Rational> + Copy + Clone {
+        let format = request.output_format()?;
+        let image_sequence = format == "image2";
+        let gif = format == "gif";
+        let (output_codec_id, pixel_format) = if image_sequence {
+            image_codec(request.output_path())?
+        } else if gif {
+            (CodecId::GIF, Pixel::PAL8)
+        } else {
+            (codec_id(request.codec), Pixel::YUV420P)
+        };
+
+        // Frames get cropped before they are scaled, so the scaler starts from the cropped size
+        let (input_width, input_height) = match request.crop {
+            Some(crop) => (crop.width, crop.height),
+            None => (video_info.width, video_info.height),
+        };
+        let (width, height) = match request.scale {
+            Some(scale) => (scale.width, scale.height),
+            None => (input_width, input_height),
+        };
+        let (width, height) = match request.gif_max_width {
+            Some(max_width) if gif => fit_to_width(width, height, max_width),
+            _ => (width, height),
+        };
+
+        if audio_stream.is_some() && (image_sequence || gif) {
+            return Err(TimelapseError::InvalidRequest(format!("--keep-audio cannot be used with {} output", format)));
+        }
+
+        let stdout = is_stdio(request.output_path());
+        if stdout && image_sequence {
+            return Err(TimelapseError::InvalidRequest(String::from("an image sequence cannot be written to standard output")));
+        }
+
+        if pass.is_some() && (image_sequence || gif) {
+            return Err(TimelapseError::InvalidRequest(format!("--two-pass cannot be used with {} output", format)));
+        }
+
+        let mut codec_options = Dictionary::new();
+        // Hardware encoders have presets of their own, which don't line up with these
+        if !image_sequence && !gif && request.hwaccel.is_none() {
+            let (option, value) = preset_option(request.codec, request.preset);
+            codec_options.set(option, &value);
+        }
+        let (stats_in, stats_file) = match pass {
+            Some(Pass::First(stats_path)) => {
+                codec_options.set("stats", &stats_path.to_string_lossy());
+                (None, Some(File::create(stats_path)?))
+            },
+            Some(Pass::Second(stats_path)) => {
+                codec_options.set("stats", &stats_path.to_string_lossy());
+                let stats = CString::new(fs::read(stats_path)?)
+                    .map_err(|_| TimelapseError::InvalidRequest(format!("{} does not hold first pass statistics", stats_path.display())))?;
+                (Some(stats), None)
+            },
+            None => (None, None),
+        };
+
+        if request.hwaccel.is_some() && (image_sequence || gif) {
+            return Err(TimelapseError::InvalidRequest(format!("--hwaccel cannot be used with {} output", format)));
+        }
+
+        let codec = match request.hwaccel {
+            Some(hwaccel) => {
+                let name = hwaccel::encoder_name(hwaccel, request.codec)
+                    .ok_or_else(|| TimelapseError::InvalidRequest(format!("{} cannot encode {}", hwaccel, request.codec)))?;
+                if request.verbose > 0 { log_message!("Encoding with {}", name); }
+                find_codec_by_name(name)
+                    .ok_or_else(|| TimelapseError::InvalidRequest(format!("the {} encoder is not available in this ffmpeg build", name)))?
+            },
+            None => find_codec(output_codec_id).ok_or(ffmpeg::Error::EncoderNotFound)?,
+        };
+        let hw_frames = if request.hwaccel == Some(HwAccel::Vaapi) { Some(HwFrames::vaapi(width, height)?) } else { None };
+
+        if image_sequence {
+            if let Some(parent) = request.output_path().parent() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+
+        let mut output = open_output(request.output_path(), format)?;
+
+        let mut scaler = ScalingContext::get(
+            video_info.decoded_pixel_format,
+            input_width,
+            input_height,
+            // GIF frames are kept as RGB until the palette is known
+            if gif { Pixel::RGB24 } else if hw_frames.is_some() { HwFrames::SOFTWARE_FORMAT } else { pixel_format },
+            width,
+            height,
+            scaling_flags(request.scale_algo))?;
+        // Images and GIFs have no say in their range, which follows from their pixel format
+        let video = !image_sequence && !gif;
+        let full_range = if video { request.color_range == ColorRange::Pc } else { is_full_range_format(pixel_format) };
+        set_color_details(&mut scaler, video_info.color_space, false, full_range);
+
+        let frame_rate = request.playback_fps().map(Rational::from).unwrap_or_else(|| video_info.frame_rate.into());
+
+        let (stream_index, encoder) = {
+            let mut stream = output.add_stream(codec)?;
+            stream.set_rate(frame_rate);
+            stream.set_time_base(frame_rate.invert());
+            let mut encoder = stream.codec().encoder().video()?;
+            encoder.set_width(width);
+            encoder.set_height(height);
+            encoder.set_format(hw_frames.as_ref().map_or(pixel_format, HwFrames::hardware_format));
+            encoder.set_frame_rate(Some(frame_rate));
+            encoder.set_time_base(frame_rate.invert());
+            if !image_sequence && !gif {
+                encoder.set_gop(request.gop);
+                if request.hwaccel.is_none() && supports_global_quality(request.codec) {
+                    encoder.set_global_quality(32);
+                }
+                encoder.set_bit_rate(request.bitrate);
+                encoder.set_max_bit_rate(request.max_bitrate);
+            }
+            if video {
+                encoder.set_color_range(if full_range { color::Range::JPEG } else { color::Range::MPEG });
+                encoder.set_colorspace(video_info.color_space);
+                unsafe {
+                    (*encoder.as_mut_ptr()).color_primaries = video_info.color_primaries.into();
+                    (*encoder.as_mut_ptr()).color_trc = video_info.color_transfer.into();
+                }
+            }
+            match pass {
+                Some(Pass::First(_)) => encoder.set_flags(CodecFlags::PASS1),
+                Some(Pass::Second(_)) => {
+                    encoder.set_flags(CodecFlags::PASS2);
+                    unsafe {
+                        (*encoder.as_mut_ptr()).stats_in = stats_in.as_ref().unwrap().as_ptr() as *mut _;
+                    }
+                },
+                None => {},
+            }
+            if let Some(hw_frames) = hw_frames.as_ref() {
+                hw_frames.attach(&mut encoder);
+            }
+            let encoder = encoder.open_as_with(codec, codec_options)?;
+            stream.set_parameters(&encoder);
+            (stream.index(), encoder)
+        };
+
+        let audio = match audio_stream {
+            Some(audio_stream) => Some((audio_stream.time_base, add_copy_stream(&mut output, audio_stream)?)),
+            None => None,
+        };
+
+        let mut metadata = Dictionary::new();
+        if let Some(title) = request.title.as_ref() {
+            metadata.set("title", title);
+        }
+        // Without a comment of their own, say how much the input got sped up if that can be worked out
+        let comment = request.comment.clone().or_else(|| {
+            if request.comparison_mode == ComparisonMode::Motion {
+                return None;
+            }
+            let total_frames = video_info.total_frames?;
+            let frames_per_window = u64::from(request.frames_per_window.min(video_info.window_size));
+            let window_step = video_info.window_size - video_info.window_overlap;
+            let output_frames = output_frame_count(sampled_frame_count(total_frames, request.sample_every), window_step)? * frames_per_window;
+            // Only as much of the input as it takes to pick that many frames gets read
+            let (total_frames, output_frames) = match request.max_output_frames {
+                Some(max_output_frames) if output_frames > max_output_frames => (total_frames * max_output_frames / output_frames, max_output_frames),
+                _ => (total_frames, output_frames),
+            };
+            // Blended frames only go between picked frames, not after the last one
+            let output_frames = output_frames.saturating_sub(1) * (request.blend as u64 + 1) + 1;
+            let input_frame_rate: Rational = video_info.frame_rate.into();
+            let input_seconds = total_frames as f64 / f64::from(input_frame_rate);
+            let output_seconds = output_frames as f64 / f64::from(frame_rate);
+            Some(describe_speedup(input_seconds, output_seconds))
+        });
+        if let Some(comment) = comment.as_ref() {
+            metadata.set("comment", comment);
+        }
+
+        // Frames come out as far apart on average as they would if they were evenly spaced
+        let pts_per_source_tick = if request.real_time_pacing {
+            let input_frame_rate: Rational = video_info.frame_rate.into();
+            let source_time_base: Rational = video_info.timebase.into();
+            let frames_per_window = request.frames_per_window.min(video_info.window_size).max(1);
+            let window_step = video_info.window_size - video_info.window_overlap;
+            let input_frames_per_output_frame = f64::from(window_step) * f64::from(request.sample_every) / f64::from(frames_per_window);
+            Some(f64::from(source_time_base) * f64::from(input_frame_rate) / input_frames_per_output_frame)
+        } else {
+            None
+        };
+        let creation_time = match (request.creation_time.as_ref(), source_creation_time) {
+            (Some(creation_time), _) => creation_time.clone(),
+            (None, Some(creation_time)) => String::from(creation_time),
+            (None, None) => iso8601_timestamp(SystemTime::now()),
         };
+        metadata.set("creation_time", &creation_time);
+        output.set_metadata(metadata);
+
+        if request.verbose > 0 { dump_format(&output, 0, request.output_path().to_str()); }
+        let mut options = Dictionary::new();
+        if stdout && (format == "mp4" || format == "mov") {
+            // The index normally goes at the start of the file once everything else is written,
+            // which needs seeking back, so write it in fragments along the way instead
+            options.set("movflags", "frag_keyframe+empty_moov");
+        }
+        for (key, value) in &request.output_options {
+            options.set(key, value);
+        }
+        let unused = output.write_header_with(options)?;
+        check_unused_options(&request.output_options, &unused, "--output-option")?;
+
+        let boomerang = if request.boomerang {
+            let path = std::env::temp_dir().join(format!("timelapse-rs-{}-boomerang.frames", process::id()));
+            let writer = FrameCacheWriter::create(&path)?;
+            Some((path, writer))
+        } else {
+            None
+        };
+
+        // The second pass writes the same frames again, which the first has taken thumbnails of
+        let webvtt = match (request.webvtt.as_ref(), pass) {
+            (Some(path), None) | (Some(path), Some(Pass::First(_))) => Some((path.clone(), WebVtt::new(request, f64::from(frame_rate)))),
+            _ => None,
+        };
+
+        Ok(Self {
+            output,
+            scaler,
+            scale_algo: request.scale_algo,
+            color_space: video_info.color_space,
+            full_range,
+            crop: request.crop,
+            grayscale: request.grayscale,
+            encoder,
+            stats_in,
+            stats_file,
+            stream_index,
+            audio,
+            frame_rate,
+            pacing: Pacing::new(pts_per_source_tick),
+            deflicker: if request.deflicker { Some(Deflicker::new(request.deflicker_window)) } else { None },
+            smoother: if request.smooth > 0 { Some(Smoother::new(request.smooth)) } else { None },
+            blend: request.blend,
+            last_frame: None,
+            fade: if request.fade_in > 0 || request.fade_out > 0 { Some(Fade::new(request.fade_in, request.fade_out)) } else { None },
+            timestamp: if request.timestamp { Some(TimestampOverlay::new(&request.timestamp_format, request.timestamp_pos, video_info.timebase.into())) } else { None },
+            boomerang,
+            gif_frames: if gif { Some(Vec::new()) } else { None },
+            hw_frames,
+            webvtt,
+        })
+    }
+
+    pub fn encode_frame<'x>(&'x mut self, frame: &'x VideoFrame) -> Result<(), TimelapseError> {
+        let source_pts = frame.pts();
+        if let Some((_, writer)) = self.boomerang.as_mut() {
+            writer.write_frame(frame)?;
+        }
+
+        let cropped = self.crop.map(|crop| crop_frame(frame, crop));
+        let frame = cropped.as_ref().unwrap_or(frame);
+        // Drawn before scaling, at the full size and precision of the input
+        let stamped = self.timestamp.as_mut().map(|timestamp| timestamp.apply(frame));
+        let frame = stamped.as_ref().unwrap_or(frame);
+
+        // The scaler starts out expecting frames the way the decoder hands them over; frames in any
+        // other format or size get a scaler of their own
+        let input = self.scaler.input();
+        if input.format != frame.format() || input.width != frame.width() || input.height != frame.height() {
+            let output = self.scaler.output();
+            self.scaler = ScalingContext::get(
+                frame.format(),
+                frame.width(),
+                frame.height(),
+                output.format,
+                output.width,
+                output.height,
+                scaling_flags(self.scale_algo))?;
+            set_color_details(&mut self.scaler, self.color_space, false, self.full_range);
+        }
 
-        let output = output_as(&request.output_path(), "webm")?;
-        unsafe { this.output.as_mut_ptr().write(output); }
-
-        let scaler = ScalingContext::get(
-            Pixel::RGB24,
-            video_info.width,
-            video_info.height,
-            Self::PIXEL_FORMAT,
-            video_info.width,
-            video_info.height,
-            Flags::BILINEAR)?;
-        unsafe { this.scaler.as_mut_ptr().write(scaler); }
-
-        let codec = find_codec(CodecId::VP9).ok_or(ffmpeg::Error::EncoderNotFound)?;
-
-        let mut stream = unsafe { this.output.as_mut_ptr().as_mut() }.unwrap().add_stream(codec)?;
-        stream.set_rate(video_info.frame_rate);
-        stream.set_time_base(video_info.frame_rate.into().invert());
-        let mut encoder = stream.codec().encoder().video()?;
-        encoder.set_width(video_info.width);
-        encoder.set_height(video_info.height);
-        encoder.set_format(Self::PIXEL_FORMAT);
-        encoder.set_gop(10);
-        encoder.set_global_quality(32);
-        encoder.set_frame_rate(Some(video_info.frame_rate));
-        encoder.set_time_base(video_info.frame_rate.into().invert());
-        encoder.set_bit_rate(5_000_000);
-        encoder.set_max_bit_rate(10_000_000);
-        let encoder = encoder.open_as(codec)?;
-        stream.set_parameters(&encoder);
-        this.stream_index = stream.index();
-
-        unsafe { this.encoder.as_mut_ptr().write(encoder); }
-
-        let mut this = unsafe { this.assume_init() };
-        if request.verbose > 0 { dump_format(&this.output, 0, request.output_path().to_str()); }
-        this.output.write_header()?;
-        Ok(this)
-    }
-
-    pub fn encode_frame<'x>(&'x mut self, frame: &'x VideoFrame) -> Result<(), ffmpeg::Error> {
         let mut out_frame = VideoFrame::empty();
         self.scaler.run(frame, &mut out_frame)?;
-        out_frame.set_pts(Some(self.pts));
-        self.pts += 1;
+        out_frame.set_pts(source_pts);
+        if self.grayscale {
+            desaturate(&mut out_frame);
+        }
+        if let Some(deflicker) = self.deflicker.as_mut() {
+            deflicker.apply(&mut out_frame);
+        }
+        if let Some(smoother) = self.smoother.as_mut() {
+            out_frame = smoother.apply(&out_frame);
+        }
+
+        if self.blend > 0 {
+            if let Some(last_frame) = self.last_frame.take() {
+                for i in 1..=self.blend {
+                    let weight = i as f64 / (self.blend + 1) as f64;
+                    self.fade_frame(blend_frames(&last_frame, &out_frame, weight))?;
+                }
+            }
+            self.last_frame = Some(out_frame.clone());
+        }
+
+        self.fade_frame(out_frame)
+    }
+
+    /// Fades the frame in or out if it is one of the first or last ones, and passes on any frames
+    /// which are ready
+    fn fade_frame(&mut self, out_frame: VideoFrame) -> Result<(), TimelapseError> {
+        let out_frame = match self.fade.as_mut() {
+            Some(fade) => match fade.push(out_frame) {
+                Some(out_frame) => out_frame,
+                None => return Ok(()),
+            },
+            None => out_frame,
+        };
+        self.emit_frame(out_frame)
+    }
+
+    /// Passes a finished frame on to the encoder, or holds on to it until the end for GIF output
+    fn emit_frame(&mut self, out_frame: VideoFrame) -> Result<(), TimelapseError> {
+        if let Some((_, webvtt)) = self.webvtt.as_mut() {
+            webvtt.add(&out_frame)?;
+        }
+
+        if let Some(gif_frames) = self.gif_frames.as_mut() {
+            gif_frames.push(out_frame);
+            return Ok(());
+        }
+
+        self.write_frame(out_frame)
+    }
+
+    /// Writes a packet of the copied audio stream into the output
+    pub fn write_audio_packet(&mut self, mut packet: Packet) -> Result<(), TimelapseError> {
+        if let Some((input_time_base, audio_index)) = self.audio {
+            packet.rescale_ts(input_time_base, self.output.stream(audio_index).unwrap().time_base());
+            packet.set_position(-1);
+            packet.set_stream(audio_index);
+            packet.write_interleaved(&mut self.output)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish<'x>(&'x mut self) -> Result<(), TimelapseError> {
+        if let Some((path, writer)) = self.boomerang.take() {
+            writer.finish()?;
+            let encoded = self.encode_backwards(&path);
+            let _ = fs::remove_file(&path);
+            encoded?;
+        }
+
+        if let Some(mut fade) = self.fade.take() {
+            for out_frame in fade.finish() {
+                self.emit_frame(out_frame)?;
+            }
+        }
+
+        if let Some(gif_frames) = self.gif_frames.take() {
+            let mut palette = Palette::from_frames(&gif_frames);
+            for frame in &gif_frames {
+                let mut indexed = palette.index_frame(frame);
+                indexed.set_pts(frame.pts());
+                self.write_frame(indexed)?;
+            }
+        }
+
+        // Encoders which only gather statistics in the first pass never return a packet, so keep
+        // going only while packets come out
+        let mut out_packet = Packet::empty();
+        loop {
+            let has_packet = self.encoder.flush(&mut out_packet)?;
+            self.write_stats()?;
+            if !has_packet {
+                break;
+            }
+            out_packet.rescale_ts(self.frame_rate.invert(), self.output.stream(self.stream_index).unwrap().time_base());
+            out_packet.set_stream(self.stream_index);
+            out_packet.write_interleaved(&mut self.output)?;
+        }
+
+        self.output.write_trailer()?;
+
+        if let Some((path, webvtt)) = self.webvtt.take() {
+            webvtt.save(&path)?;
+        }
+        Ok(())
+    }
+
+    /// Encodes the frames kept for `--boomerang` again from last to first, leaving out the last
+    /// one, which has just been written, and the first one, which the output loops back to
+    fn encode_backwards(&mut self, path: &Path) -> Result<(), TimelapseError> {
+        if let Some(mut frames) = FrameCacheReader::open(path)? {
+            for index in (1..frames.frame_count().saturating_sub(1)).rev() {
+                if let Some(frame) = frames.read_frame(index)? {
+                    self.encode_frame(&frame)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_frame(&mut self, out_frame: VideoFrame) -> Result<(), TimelapseError> {
+        let source_pts = out_frame.pts();
+        let mut out_frame = match self.hw_frames.as_ref() {
+            Some(hw_frames) => hw_frames.upload(&out_frame)?,
+            None => out_frame,
+        };
+        out_frame.set_pts(Some(self.pacing.next_pts(source_pts)));
 
         let mut out_packet = Packet::empty();
         let has_packet = self.encoder.encode(&out_frame, &mut out_packet)?;
+        self.write_stats()?;
         if has_packet {
-            out_packet.rescale_ts(self.video_info.frame_rate.into().invert(), self.output.stream(self.stream_index).unwrap().time_base());
+            out_packet.rescale_ts(self.frame_rate.invert(), self.output.stream(self.stream_index).unwrap().time_base());
             out_packet.set_stream(self.stream_index);
             out_packet.write_interleaved(&mut self.output)?;
         }
@@ -116,20 +498,243 @@ where R: Into<Rational> + Copy + Clone {
         Ok(())
     }
 
-    pub fn finish<'x>(&'x mut self) -> Result<(), ffmpeg::Error> {
-        let mut out_packet = Packet::empty();
-        let mut needs_to_flush = true;
-        while needs_to_flush {
-            let has_packet = self.encoder.flush(&mut out_packet)?;
-            if has_packet {
-                out_packet.rescale_ts(self.video_info.frame_rate.into().invert(), self.output.stream(self.stream_index).unwrap().time_base());
-                out_packet.set_stream(self.stream_index);
-                out_packet.write_interleaved(&mut self.output)?;
+    /// Saves the first pass statistics the encoder has come up with so far
+    fn write_stats(&mut self) -> Result<(), TimelapseError> {
+        if let Some(stats_file) = self.stats_file.as_mut() {
+            let stats_out = unsafe { (*self.encoder.as_ptr()).stats_out };
+            if !stats_out.is_null() {
+                stats_file.write_all(unsafe { CStr::from_ptr(stats_out) }.to_bytes())?;
             }
-            needs_to_flush = !has_packet;
         }
-
-        self.output.write_trailer()?;
         Ok(())
     }
 }
+
+/// Hands out the timestamps of the output frames
+struct Pacing {
+    /// Lowest timestamp the next output frame can have
+    pts: i64,
+    /// With `--real-time-pacing`, the number of output frames to every tick of the timestamps of
+    /// the input frames
+    pts_per_source_tick: Option<f64>,
+    /// Timestamp of the first input frame written, which the timestamps of the rest count from
+    first_source_pts: Option<i64>,
+}
+
+impl Pacing {
+    fn new(pts_per_source_tick: Option<f64>) -> Self {
+        Pacing {
+            pts: 0,
+            pts_per_source_tick,
+            first_source_pts: None,
+        }
+    }
+
+    /// Timestamp of the next output frame, which with `--real-time-pacing` follows from that of
+    /// the input frame it was made from
+    fn next_pts(&mut self, source_pts: Option<i64>) -> i64 {
+        let pts = match (self.pts_per_source_tick, source_pts) {
+            (Some(pts_per_source_tick), Some(source_pts)) => {
+                let first_source_pts = *self.first_source_pts.get_or_insert(source_pts);
+                ((source_pts - first_source_pts) as f64 * pts_per_source_tick).round() as i64
+            },
+            _ => self.pts,
+        };
+        // Frames shot closer together than output frames are still written one after the other
+        let pts = pts.max(self.pts);
+        self.pts = pts + 1;
+        pts
+    }
+}
+
+/// Scales the dimensions down to the given width, keeping the aspect ratio
+fn fit_to_width(width: u32, height: u32, max_width: u32) -> (u32, u32) {
+    if width <= max_width || max_width == 0 {
+        (width, height)
+    } else {
+        let scaled_height = (u64::from(height) * u64::from(max_width) / u64::from(width)).max(1);
+        (max_width, scaled_height as u32)
+    }
+}
+
+/// Describes how much real time the output covers, eg. `compressed 3h20m into 45s (267x)`
+fn describe_speedup(input_seconds: f64, output_seconds: f64) -> String {
+    if output_seconds > 0.0 {
+        format!("compressed {} into {} ({:.0}x)", format_duration(input_seconds), format_duration(output_seconds), input_seconds / output_seconds)
+    } else {
+        format!("compressed {} into {}", format_duration(input_seconds), format_duration(output_seconds))
+    }
+}
+
+/// Formats a number of seconds as eg. `3h20m`, `2m05s` or `45s`
+fn format_duration(seconds: f64) -> String {
+    let seconds = if seconds.is_finite() && seconds > 0.0 { seconds.round() as u64 } else { 0 };
+    let (hours, minutes, seconds) = (seconds / 3600, (seconds / 60) % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}h{:02}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m{:02}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+/// Formats the time as UTC in the form ffmpeg uses for `creation_time` tags, eg.
+/// `2020-06-01T12:00:00.000000Z`
+fn iso8601_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (hour, minute, second) = ((seconds / 3600) % 24, (seconds / 60) % 60, seconds % 60);
+
+    // Converts days since the epoch to a date in the proleptic Gregorian calendar, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (seconds / 86400) as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z", year, month, day, hour, minute, second, since_epoch.subsec_micros())
+}
+
+/// Creates the output context for the given muxer, opening the output file unless the muxer
+/// writes its own files (like image2 does for image sequences). `-` writes to standard output.
+fn open_output(path: &Path, format: &str) -> Result<OutputContext, TimelapseError> {
+    let url = if is_stdio(path) { String::from("pipe:1") } else { ffmpeg_path(path).to_string_lossy().into_owned() };
+    let path = CString::new(url)
+        .map_err(|_| TimelapseError::InvalidRequest(String::from("output path must not contain NUL bytes")))?;
+    let format = CString::new(format)
+        .map_err(|_| TimelapseError::InvalidRequest(String::from("output format must not contain NUL bytes")))?;
+
+    unsafe {
+        let mut ps = ptr::null_mut();
+        match avformat_alloc_output_context2(&mut ps, ptr::null_mut(), format.as_ptr(), path.as_ptr()) {
+            0 => {},
+            e => return Err(ffmpeg::Error::from(e).into()),
+        }
+
+        if (*(*ps).oformat).flags & AVFMT_NOFILE as c_int == 0 {
+            match avio_open(&mut (*ps).pb, path.as_ptr(), AVIO_FLAG_WRITE as c_int) {
+                0 => {},
+                e => {
+                    avformat_free_context(ps);
+                    return Err(ffmpeg::Error::from(e).into());
+                },
+            }
+        }
+
+        Ok(OutputContext::wrap(ps))
+    }
+}
+
+/// Adds a stream to the output which takes the packets of the input stream as they are, and
+/// returns its index
+fn add_copy_stream(output: &mut OutputContext, input_stream: &AudioStream) -> Result<usize, TimelapseError> {
+    unsafe {
+        let stream = avformat_new_stream(output.as_mut_ptr(), ptr::null());
+        if stream.is_null() {
+            return Err(io::Error::new(io::ErrorKind::Other, "out of memory adding the audio stream").into());
+        }
+
+        match avcodec_parameters_copy((*stream).codecpar, input_stream.parameters.as_ptr()) {
+            0 => {},
+            e => return Err(ffmpeg::Error::from(e).into()),
+        }
+        // The tag of the input container may not mean anything in the output one
+        (*(*stream).codecpar).codec_tag = 0;
+        (*stream).time_base = input_stream.time_base.into();
+
+        Ok((*stream).index as usize)
+    }
+}
+
+/// Picks the image codec and its pixel format for an image sequence output based on its extension
+fn image_codec(path: &Path) -> Result<(CodecId, Pixel), TimelapseError> {
+    let extension = path.extension().map(|e| e.to_string_lossy().to_ascii_lowercase()).unwrap_or_default();
+    match extension.as_str() {
+        "png" => Ok((CodecId::PNG, Pixel::RGB24)),
+        "jpg" | "jpeg" => Ok((CodecId::MJPEG, Pixel::YUVJ420P)),
+        "bmp" => Ok((CodecId::BMP, Pixel::BGR24)),
+        _ => Err(TimelapseError::InvalidRequest(format!("cannot write an image sequence as '.{}' files", extension))),
+    }
+}
+
+fn codec_id(codec: OutputCodec) -> CodecId {
+    match codec {
+        OutputCodec::Vp9 => CodecId::VP9,
+        OutputCodec::Vp8 => CodecId::VP8,
+        OutputCodec::H264 => CodecId::H264,
+        OutputCodec::Av1 => CodecId::AV1,
+    }
+}
+
+/// The private option of the codec's encoder which sets how fast it is, and its value for the preset
+fn preset_option(codec: OutputCodec, preset: Preset) -> (&'static str, String) {
+    match codec {
+        OutputCodec::H264 => ("preset", String::from(preset.name())),
+        OutputCodec::Vp9 | OutputCodec::Vp8 | OutputCodec::Av1 => ("cpu-used", preset.speed().to_string()),
+    }
+}
+
+fn supports_global_quality(codec: OutputCodec) -> bool {
+    match codec {
+        OutputCodec::Vp9 | OutputCodec::Vp8 => true,
+        OutputCodec::H264 | OutputCodec::Av1 => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_duration_uses_the_two_largest_units() {
+        assert_eq!(format_duration(45.0), "45s");
+        assert_eq!(format_duration(125.0), "2m05s");
+        assert_eq!(format_duration(12_000.0), "3h20m");
+        assert_eq!(format_duration(3600.0), "1h00m");
+        // Rounding can carry over into the next unit
+        assert_eq!(format_duration(59.6), "1m00s");
+    }
+
+    #[test]
+    fn format_duration_shows_nonsense_as_nothing() {
+        assert_eq!(format_duration(-5.0), "0s");
+        assert_eq!(format_duration(f64::NAN), "0s");
+        assert_eq!(format_duration(f64::INFINITY), "0s");
+    }
+
+    #[test]
+    fn describe_speedup_gives_the_ratio_when_there_is_one() {
+        assert_eq!(describe_speedup(12_000.0, 45.0), "compressed 3h20m into 45s (267x)");
+        assert_eq!(describe_speedup(12_000.0, 0.0), "compressed 3h20m into 0s");
+    }
+
+    #[test]
+    fn frames_are_numbered_one_after_the_other_without_pacing() {
+        let mut pacing = Pacing::new(None);
+        let pts = [Some(0), Some(500), None, Some(100)].iter().map(|&source_pts| pacing.next_pts(source_pts)).collect::<Vec<_>>();
+        assert_eq!(pts, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn gaps_in_the_source_widen_the_gaps_in_the_output() {
+        // One output frame for every 10 ticks of the source timestamps
+        let mut pacing = Pacing::new(Some(0.1));
+        let pts = [1000, 1010, 1020, 1100, 1110].iter().map(|&source_pts| pacing.next_pts(Some(source_pts))).collect::<Vec<_>>();
+        assert_eq!(pts, vec![0, 1, 2, 10, 11]);
+    }
+
+    #[test]
+    fn paced_timestamps_never_go_backwards() {
+        let mut pacing = Pacing::new(Some(0.1));
+        // Frames shot closer together than an output frame, and one shot before the frame
+        // written last, still come out in order
+        let pts = [0, 2, 4, 30, 10, 40].iter().map(|&source_pts| pacing.next_pts(Some(source_pts))).collect::<Vec<_>>();
+        assert_eq!(pts, vec![0, 1, 2, 3, 4, 5]);
+    }
+}
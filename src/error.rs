@@ -0,0 +1,84 @@
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::io;
+
+use crate::frame_selection::FrameSelectionError;
+use crate::request::{UnknownOutputFormatError, ValidationError};
+
+/// Any error that can happen while turning a video into a timelapse
+#[derive(Debug)]
+pub enum TimelapseError {
+    /// Opening, decoding or encoding a video failed inside ffmpeg
+    Ffmpeg(ffmpeg::Error),
+    /// No frame could be picked from a window
+    FrameSelection(FrameSelectionError),
+    /// Reading or writing a file failed
+    Io(io::Error),
+    /// An image given alongside the input could not be loaded
+    Image(image::ImageError),
+    /// The request asks for something that cannot be done
+    InvalidRequest(String),
+    /// Parts of the input don't fit together, eg. images of different sizes in a sequence
+    InputMismatch(String),
+}
+
+impl Display for TimelapseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimelapseError::Ffmpeg(e) => write!(f, "ffmpeg error: {}", e),
+            TimelapseError::FrameSelection(e) => write!(f, "frame selection error: {}", e),
+            TimelapseError::Io(e) => write!(f, "I/O error: {}", e),
+            TimelapseError::Image(e) => write!(f, "image error: {}", e),
+            TimelapseError::InvalidRequest(message) => write!(f, "invalid request: {}", message),
+            TimelapseError::InputMismatch(message) => write!(f, "inconsistent input: {}", message),
+        }
+    }
+}
+
+impl Error for TimelapseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TimelapseError::Ffmpeg(e) => Some(e),
+            TimelapseError::FrameSelection(e) => Some(e),
+            TimelapseError::Io(e) => Some(e),
+            TimelapseError::Image(e) => Some(e),
+            TimelapseError::InvalidRequest(_) | TimelapseError::InputMismatch(_) => None,
+        }
+    }
+}
+
+impl From<ffmpeg::Error> for TimelapseError {
+    fn from(e: ffmpeg::Error) -> Self {
+        TimelapseError::Ffmpeg(e)
+    }
+}
+
+impl From<FrameSelectionError> for TimelapseError {
+    fn from(e: FrameSelectionError) -> Self {
+        TimelapseError::FrameSelection(e)
+    }
+}
+
+impl From<io::Error> for TimelapseError {
+    fn from(e: io::Error) -> Self {
+        TimelapseError::Io(e)
+    }
+}
+
+impl From<image::ImageError> for TimelapseError {
+    fn from(e: image::ImageError) -> Self {
+        TimelapseError::Image(e)
+    }
+}
+
+impl From<UnknownOutputFormatError> for TimelapseError {
+    fn from(e: UnknownOutputFormatError) -> Self {
+        TimelapseError::InvalidRequest(e.to_string())
+    }
+}
+
+impl From<ValidationError> for TimelapseError {
+    fn from(e: ValidationError) -> Self {
+        TimelapseError::InvalidRequest(e.to_string())
+    }
+}
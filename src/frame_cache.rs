@@ -0,0 +1,287 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::time::UNIX_EPOCH;
+use std::path::Path;
+use std::str::FromStr;
+
+use ffmpeg::format::Pixel;
+use ffmpeg::util::frame::Video as VideoFrame;
+
+use crate::error::TimelapseError;
+use crate::post_processing::plane_row_len;
+use crate::request::Request;
+
+const MAGIC: &[u8; 4] = b"TLFC";
+
+/// Writes frames to a file as raw picture data so that they can be read back with
+/// `FrameCacheReader` without decoding and selecting them all over again. Every frame in the file
+/// must have the same format and size as the first one.
+pub struct FrameCacheWriter {
+    writer: BufWriter<File>,
+    header_written: bool,
+}
+
+impl FrameCacheWriter {
+    pub fn create(path: &Path) -> Result<Self, TimelapseError> {
+        Ok(Self {
+            writer: BufWriter::new(File::create(path)?),
+            header_written: false,
+        })
+    }
+
+    pub fn write_frame(&mut self, frame: &VideoFrame) -> Result<(), TimelapseError> {
+        if !self.header_written {
+            let format_name = frame.format().descriptor().map(|descriptor| descriptor.name()).unwrap_or("none");
+            self.writer.write_all(MAGIC)?;
+            write_u32(&mut self.writer, format_name.len() as u32)?;
+            self.writer.write_all(format_name.as_bytes())?;
+            write_u32(&mut self.writer, frame.width())?;
+            write_u32(&mut self.writer, frame.height())?;
+            self.header_written = true;
+        }
+
+        self.writer.write_all(&frame.pts().unwrap_or(i64::min_value()).to_le_bytes())?;
+        for plane in 0..frame.planes() {
+            let row_len = plane_row_len(frame, plane);
+            let stride = frame.stride(plane);
+            let data = frame.data(plane);
+            for y in 0..(frame.plane_height(plane) as usize) {
+                self.writer.write_all(&data[(y * stride)..(y * stride + row_len)])?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), TimelapseError> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads back the frames written by a `FrameCacheWriter`, in the same order or one by one
+pub struct FrameCacheReader {
+    reader: BufReader<File>,
+    format: Pixel,
+    width: u32,
+    height: u32,
+    /// Where the first frame starts in the file
+    frames_start: u64,
+    /// Length of every frame in the file, with its timestamp
+    frame_len: u64,
+    frame_count: u64,
+}
+
+impl FrameCacheReader {
+    /// Opens the cache, or returns `None` if no frames were ever written to it
+    pub fn open(path: &Path) -> Result<Option<Self>, TimelapseError> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut magic = [0u8; 4];
+        match reader.read_exact(&mut magic) {
+            Ok(()) => {},
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        if &magic != MAGIC {
+            return Err(invalid_cache(path));
+        }
+
+        let mut format_name = vec![0u8; read_u32(&mut reader)? as usize];
+        reader.read_exact(&mut format_name)?;
+        let format = String::from_utf8(format_name).ok()
+            .and_then(|name| Pixel::from_str(&name).ok())
+            .ok_or_else(|| invalid_cache(path))?;
+        let width = read_u32(&mut reader)?;
+        let height = read_u32(&mut reader)?;
+
+        let frames_start = reader.seek(SeekFrom::Current(0))?;
+        let template = VideoFrame::new(format, width, height);
+        let frame_len = 8 + (0..template.planes())
+            .map(|plane| (plane_row_len(&template, plane) * template.plane_height(plane) as usize) as u64)
+            .sum::<u64>();
+        let frame_count = (fs::metadata(path)?.len() - frames_start) / frame_len;
+
+        Ok(Some(Self {
+            reader,
+            format,
+            width,
+            height,
+            frames_start,
+            frame_len,
+            frame_count,
+        }))
+    }
+
+    /// Number of whole frames in the cache
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /// Reads the frame with the given index, counting from 0, or returns `None` if there is no
+    /// such frame. Reading carries on from the frame after it.
+    pub fn read_frame(&mut self, index: u64) -> Result<Option<VideoFrame>, TimelapseError> {
+        if index >= self.frame_count {
+            return Ok(None);
+        }
+        self.reader.seek(SeekFrom::Start(self.frames_start + index * self.frame_len))?;
+        self.next_frame()
+    }
+
+    /// Reads the next frame, or returns `None` once all frames have been read
+    pub fn next_frame(&mut self) -> Result<Option<VideoFrame>, TimelapseError> {
+        let mut pts = [0u8; 8];
+        match self.reader.read_exact(&mut pts) {
+            Ok(()) => {},
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let pts = i64::from_le_bytes(pts);
+
+        let mut frame = VideoFrame::new(self.format, self.width, self.height);
+        for plane in 0..frame.planes() {
+            let row_len = plane_row_len(&frame, plane);
+            let stride = frame.stride(plane);
+            for y in 0..(frame.plane_height(plane) as usize) {
+                self.reader.read_exact(&mut frame.data_mut(plane)[(y * stride)..(y * stride + row_len)])?;
+            }
+        }
+        frame.set_pts(if pts == i64::min_value() { None } else { Some(pts) });
+        Ok(Some(frame))
+    }
+}
+
+/// Name of the file in `--cache-dir` holding the frames picked for this request. It changes
+/// whenever the inputs or any of the options which affect which frames are picked change.
+pub fn cache_file_name(request: &Request) -> String {
+    let mut hasher = DefaultHasher::new();
+    for input_path in request.input_paths() {
+        input_path.hash(&mut hasher);
+        // Image sequence patterns don't name a file, but then there is nothing to tell whether
+        // the images have changed either
+        if let Ok(metadata) = fs::metadata(input_path) {
+            metadata.len().hash(&mut hasher);
+            if let Ok(modified) = metadata.modified() {
+                modified.duration_since(UNIX_EPOCH).unwrap_or_default().hash(&mut hasher);
+            }
+        }
+    }
+    format!("{:?}", (
+        request.window_size,
+        request.window_duration,
+        request.frames_per_window,
+        request.window_overlap,
+        request.frame_skip,
+        request.sample_every,
+        request.key_frames_only,
+        request.video_stream,
+        request.dedup,
+        request.dedup_epsilon,
+        request.start_time,
+        request.end_time,
+    )).hash(&mut hasher);
+    format!("{:?}", (
+        request.scale_algo,
+        request.bit_depth,
+        request.trim_static,
+        request.static_threshold,
+        request.static_tail,
+        request.max_output_frames,
+        &request.input_options,
+        // The window size worked out for a target duration depends on how the output plays back
+        request.target_duration.map(|target_duration| (target_duration, request.playback_fps(), request.blend, request.boomerang)),
+        request.speed.map(|speed| (speed, request.playback_fps(), request.blend)),
+    )).hash(&mut hasher);
+    format!("{:?}", (
+        request.comparison_mode,
+        request.select,
+        request.invert,
+        request.motion_threshold,
+        request.min_change,
+        request.hash_size,
+        request.hash_resolution,
+        request.hash_luma,
+        &request.reference,
+        request.roi,
+        request.target_luma,
+    )).hash(&mut hasher);
+    format!("{:016x}.frames", hasher.finish())
+}
+
+fn invalid_cache(path: &Path) -> TimelapseError {
+    TimelapseError::InvalidRequest(format!("{} is not a frame cache", path.display()))
+}
+
+fn write_u32<W: Write>(writer: &mut W, value: u32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    use crate::post_processing::{flat_frame, plane_samples};
+
+    fn cache_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("timelapse-rs-cache-{}-{}.frames", name, process::id()))
+    }
+
+    #[test]
+    fn frames_read_back_as_they_were_written() {
+        ffmpeg::init().unwrap();
+        let path = cache_path("round-trip");
+        let mut frames = (0..3).map(|i| flat_frame(Pixel::YUV420P, 6, 4, 40 * i as u8 + 10)).collect::<Vec<_>>();
+        frames[0].set_pts(Some(100));
+        frames[1].set_pts(None);
+        frames[2].set_pts(Some(300));
+
+        let mut writer = FrameCacheWriter::create(&path).unwrap();
+        for frame in frames.iter() {
+            writer.write_frame(frame).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let mut reader = FrameCacheReader::open(&path).unwrap().unwrap();
+        assert_eq!(reader.frame_count(), 3);
+        for expected in frames.iter() {
+            let frame = reader.next_frame().unwrap().unwrap();
+            assert_eq!((frame.format(), frame.width(), frame.height()), (Pixel::YUV420P, 6, 4));
+            assert_eq!(frame.pts(), expected.pts());
+            for plane in 0..frame.planes() {
+                assert_eq!(plane_samples(&frame, plane), plane_samples(expected, plane));
+            }
+        }
+        assert!(reader.next_frame().unwrap().is_none());
+
+        // Going back to a frame carries on from the one after it
+        assert_eq!(plane_samples(&reader.read_frame(1).unwrap().unwrap(), 0), vec![50; 24]);
+        assert_eq!(reader.next_frame().unwrap().unwrap().pts(), Some(300));
+        assert!(reader.read_frame(3).unwrap().is_none());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cache_without_frames_opens_as_nothing() {
+        let path = cache_path("empty");
+        FrameCacheWriter::create(&path).unwrap().finish().unwrap();
+        assert!(FrameCacheReader::open(&path).unwrap().is_none());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn other_files_are_not_taken_for_a_cache() {
+        let path = cache_path("not-a-cache");
+        fs::write(&path, b"not a frame cache").unwrap();
+        let result = FrameCacheReader::open(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(TimelapseError::InvalidRequest(_))));
+    }
+}
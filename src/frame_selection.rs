@@ -1,166 +1,979 @@
 use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::hash::Hasher;
 
+use ffmpeg::format::Pixel;
+use ffmpeg::software::scaling::{flag::Flags as ScalingFlags, Context as ScalingContext};
 use ffmpeg::util::frame::Video as VideoFrame;
 
 use rayon::prelude::*;
 
 use image;
 
-use img_hash::{HasherConfig, HashAlg, ImageHash};
+use img_hash::{Hasher as ImageHasher, HasherConfig, HashAlg, ImageHash};
 
-use crate::request::{Request, ComparisonMode};
+use crate::error::TimelapseError;
+use crate::post_processing::{crop_frame, plane_row_len};
+use crate::request::{Request, ComparisonMode, Rect, SelectMode};
 
 pub trait FrameSelector {
-    fn pick_best(&mut self, window: Vec<VideoFrame>) -> Result<VideoFrame, FrameSelectionError>;
+    /// Picks up to `count` frames out of the window, best first. Only the best one is remembered
+    /// for comparing against the next window.
+    fn pick_best_n(&mut self, window: Vec<VideoFrame>, count: usize) -> Result<Vec<Selection>, TimelapseError>;
+
+    fn pick_best(&mut self, window: Vec<VideoFrame>) -> Result<Selection, TimelapseError> {
+        Ok(self.pick_best_n(window, 1)?.remove(0))
+    }
+}
+
+/// A frame picked out of a window
+pub struct Selection {
+    pub frame: VideoFrame,
+    /// Position of the frame within its window
+    pub window_index: usize,
+    /// Value of the comparison metric for the frame (eg. its distance to the previous frame), if
+    /// the selector computed one
+    pub score: Option<f64>,
+}
+
+impl Selection {
+    fn new(frame: VideoFrame, window_index: usize, score: Option<f64>) -> Self {
+        Self {
+            frame,
+            window_index,
+            score,
+        }
+    }
+}
+
+/// Takes the first frame off the first window a selector is given, which it picks for want of
+/// anything to compare it with. The rest of the window is left to pick the other frames from,
+/// comparing them against the first one; none are left if only one frame was asked for.
+fn take_first_frame(window: &mut Vec<VideoFrame>, count: usize) -> Result<VideoFrame, TimelapseError> {
+    if window.is_empty() {
+        return Err(FrameSelectionError::EmptyInput.into());
+    }
+    let frame = window.remove(0);
+    if count <= 1 {
+        window.clear();
+    }
+    Ok(frame)
+}
+
+/// Puts the frame taken off with `take_first_frame`, if there was one, in front of those picked
+/// from the rest of the window, whose indices are moved along by one to count it
+fn with_first_frame(first_frame: Option<VideoFrame>, picked: Vec<Selection>) -> Result<Vec<Selection>, TimelapseError> {
+    match first_frame {
+        Some(frame) => {
+            let mut selections = vec![Selection::new(frame, 0, None)];
+            selections.extend(picked.into_iter().map(|selection| Selection { window_index: selection.window_index + 1, ..selection }));
+            Ok(selections)
+        },
+        None if picked.is_empty() => Err(FrameSelectionError::EmptyInput.into()),
+        None => Ok(picked),
+    }
+}
+
+/// Creates the selector for the request's comparison mode. If a reference frame is given, the MSE
+/// and hash selectors compare every candidate against it instead of against the last picked frame.
+pub fn get_frame_selector<'a>(request: &'a Request, reference: Option<VideoFrame>) -> Box<dyn FrameSelector + Send + 'a> {
+    match request.comparison_mode {
+        // Motion mode gets single frame windows and decides what to emit with a ChangeGate
+        ComparisonMode::Noop | ComparisonMode::Motion => Box::new(NoopFrameSelector),
+        ComparisonMode::Blockhash | ComparisonMode::GradientHash | ComparisonMode::MeanHash | ComparisonMode::DctHash => Box::new(HashFrameSelector::new(request, reference)),
+        ComparisonMode::MSE | ComparisonMode::MSERgb => Box::new(MSEFrameSelector::new(request, reference)),
+        ComparisonMode::SSIM => Box::new(SSIMFrameSelector::new(request, reference)),
+        ComparisonMode::Sharpest => Box::new(SharpestFrameSelector::new(request)),
+        ComparisonMode::BestExposure => Box::new(ExposureFrameSelector::new(request)),
+        ComparisonMode::Histogram => Box::new(HistogramFrameSelector::new(request)),
+    }
+}
+
+/// Pixel format the frames given to the selector for the request's comparison mode should be in,
+/// so that eg. MSE reads the luma straight from the Y plane rather than working it out from RGB.
+/// Frames with more than 8 bits per sample come in as `YUV420P10LE` regardless.
+pub fn required_pixel_format(request: &Request) -> Pixel {
+    match request.comparison_mode {
+        // With --hash-luma the Y plane is hashed as it is
+        ComparisonMode::Blockhash | ComparisonMode::GradientHash | ComparisonMode::MeanHash | ComparisonMode::DctHash => {
+            if request.hash_luma { Pixel::YUV420P } else { Pixel::RGB24 }
+        },
+        ComparisonMode::Histogram => Pixel::RGB24,
+        // The rest look at the luma, as does the ChangeGate of motion mode
+        ComparisonMode::Noop | ComparisonMode::Motion | ComparisonMode::MSE | ComparisonMode::MSERgb | ComparisonMode::SSIM
+            | ComparisonMode::Sharpest | ComparisonMode::BestExposure => Pixel::YUV420P,
+    }
 }
 
-pub fn get_frame_selector<'a>(request: &'a Request) -> Box<dyn FrameSelector + 'a> {
+/// Whether the request picks frames without looking at what was picked from the windows before,
+/// either because the comparison mode doesn't compare frames with each other or because every
+/// frame is compared with `--reference`. Windows can then be picked from in any order.
+pub fn is_stateless(request: &Request) -> bool {
     match request.comparison_mode {
-        ComparisonMode::Noop => Box::new(NoopFrameSelector),
-        ComparisonMode::Blockhash | ComparisonMode::GradientHash | ComparisonMode::MeanHash => Box::new(HashFrameSelector::new(request)),
-        ComparisonMode::MSE => Box::new(MSEFrameSelector::new(request)),
-        _ => panic!("Requested unsupported frame selector: {:?}", request.comparison_mode),
+        ComparisonMode::Noop | ComparisonMode::Sharpest | ComparisonMode::BestExposure => true,
+        ComparisonMode::Motion => false,
+        _ => request.reference.is_some(),
     }
 }
 
 struct MSEFrameSelector<'a> {
     request: &'a Request,
-    last_frame: RefCell<Option<Vec<u8>>>,
+    last_frame: Option<Vec<u16>>,
+    /// Whether `last_frame` holds a reference image which should be kept rather than replaced
+    /// with every picked frame
+    fixed_reference: bool,
 }
 
 impl<'a> FrameSelector for MSEFrameSelector<'a> {
-    fn pick_best(&mut self, window: Vec<VideoFrame>) -> Result<VideoFrame, FrameSelectionError> {
+    fn pick_best_n(&mut self, window: Vec<VideoFrame>, count: usize) -> Result<Vec<Selection>, TimelapseError> {
         let mut window = window;
-        if self.last_frame.borrow().is_none() {
-            let frame = window.remove(0);
-            self.last_frame.replace(Some(get_luma_data(&frame)));
-            return Ok(frame);
+        let first_frame = match self.last_frame {
+            Some(_) => None,
+            None => {
+                let frame = take_first_frame(&mut window, count)?;
+                self.last_frame = Some(get_mse_samples(&frame, self.request.comparison_mode, self.request.roi));
+                Some(frame)
+            },
+        };
+        let count = if first_frame.is_some() { count.saturating_sub(1) } else { count };
+        let previous_samples = self.last_frame.as_ref().unwrap();
+
+        let comparison_mode = self.request.comparison_mode;
+        let roi = self.request.roi;
+        let candidates = window.into_par_iter().enumerate().map(|(i, frame)| {
+            let samples = get_mse_samples(&frame, comparison_mode, roi);
+            let err = mse(&samples, previous_samples);
+            (i, frame, samples, err)
+        }).collect::<Vec<_>>();
+        let result = keep_selected(self.request.select, candidates, count, |(_, _, _, err1), (_, _, _, err2)| err1.partial_cmp(err2).unwrap_or(Ordering::Equal));
+
+        let mut result = result.into_iter();
+        let mut selections = Vec::new();
+        if let Some((i, frame, next_samples, err)) = result.next() {
+            if self.request.verbose > 2 { log_message!("mse = {}", err); }
+            if !self.fixed_reference {
+                self.last_frame = Some(next_samples);
+            }
+            selections.push(Selection::new(frame, i, Some(err)));
+            selections.extend(result.map(|(i, frame, _, err)| Selection::new(frame, i, Some(err))));
         }
+        with_first_frame(first_frame, selections)
+    }
+}
 
-        let result = {
-            let last_frame = self.last_frame.borrow();
-            let previous_luma = last_frame.as_ref().unwrap();
-            window.into_par_iter().map(|frame| {
-                let luma = get_luma_data(&frame);
-                let err = mse(&luma, previous_luma);
-                (frame, luma, err)
-            }).min_by(|(_, _, err1), (_, _, err2)| err1.partial_cmp(err2).unwrap_or(std::cmp::Ordering::Equal))
+/// Side of the square blocks SSIM is worked out over
+const SSIM_BLOCK_SIZE: usize = 8;
+
+/// The luma of a frame, cut down to the region of interest if there is one. It is always 8-bit,
+/// which the constants `ssim` uses are tuned for; `--bit-depth 10` is only taken by `mse`.
+struct LumaPlane {
+    data: Vec<u8>,
+    width: usize,
+    height: usize,
+}
+
+impl LumaPlane {
+    /// Extracts the luma within the region of interest once, so that every comparison made
+    /// against the frame can use it. With `invert`, dark becomes light and the other way around.
+    fn new(frame: &VideoFrame, roi: Option<Rect>, invert: bool) -> Self {
+        let cropped = roi.map(|roi| crop_frame(frame, roi));
+        let frame = cropped.as_ref().unwrap_or(frame);
+        let mut data = get_luma_data(frame);
+        if invert {
+            invert_luma(&mut data);
+        }
+        LumaPlane {
+            data,
+            width: frame.width() as usize,
+            height: frame.height() as usize,
+        }
+    }
+}
+
+/// Turns dark into light and the other way around, for `--invert`
+fn invert_luma(luma: &mut [u8]) {
+    for value in luma.iter_mut() {
+        *value = 255 - *value;
+    }
+}
+
+struct SSIMFrameSelector<'a> {
+    request: &'a Request,
+    last_luma: Option<LumaPlane>,
+    /// Whether `last_luma` is the luma of a reference image which should be kept rather than
+    /// replaced with every picked frame
+    fixed_reference: bool,
+}
+
+impl<'a> SSIMFrameSelector<'a> {
+    fn new(request: &'a Request, reference: Option<VideoFrame>) -> SSIMFrameSelector {
+        SSIMFrameSelector {
+            request,
+            fixed_reference: reference.is_some(),
+            last_luma: reference.map(|reference| LumaPlane::new(&reference, request.roi, request.invert)),
+        }
+    }
+}
+
+impl<'a> FrameSelector for SSIMFrameSelector<'a> {
+    fn pick_best_n(&mut self, window: Vec<VideoFrame>, count: usize) -> Result<Vec<Selection>, TimelapseError> {
+        let mut window = window;
+        let roi = self.request.roi;
+        let invert = self.request.invert;
+        let first_frame = match self.last_luma {
+            Some(_) => None,
+            None => {
+                let frame = take_first_frame(&mut window, count)?;
+                self.last_luma = Some(LumaPlane::new(&frame, roi, invert));
+                Some(frame)
+            },
         };
+        let count = if first_frame.is_some() { count.saturating_sub(1) } else { count };
+        let previous_luma = self.last_luma.as_ref().unwrap();
 
-        if let Some((frame, next_luma, err)) = result {
-            if self.request.verbose > 2 { println!("mse = {}", err); }
-            self.last_frame.replace(Some(next_luma));
-            Ok(frame)
-        } else {
-            Err(FrameSelectionError::EmptyInput)
+        let candidates = window.into_par_iter().enumerate().map(|(i, frame)| {
+            let luma = LumaPlane::new(&frame, roi, invert);
+            let similarity = ssim(&luma, previous_luma);
+            (i, frame, luma, similarity)
+        }).collect::<Vec<_>>();
+        let result = keep_selected(self.request.select, candidates, count, |(_, _, _, s1), (_, _, _, s2)| s2.partial_cmp(s1).unwrap_or(Ordering::Equal));
+
+        let mut result = result.into_iter();
+        let mut selections = Vec::new();
+        if let Some((i, frame, next_luma, similarity)) = result.next() {
+            if self.request.verbose > 2 { log_message!("ssim = {}", similarity); }
+            if !self.fixed_reference {
+                self.last_luma = Some(next_luma);
+            }
+            selections.push(Selection::new(frame, i, Some(similarity)));
+            selections.extend(result.map(|(i, frame, _, similarity)| Selection::new(frame, i, Some(similarity))));
+        }
+        with_first_frame(first_frame, selections)
+    }
+}
+
+/// Mean structural similarity of two luma planes of the same size, worked out over square blocks
+/// which don't overlap. 1 means the planes are the same; the less alike they are, the lower it is.
+fn ssim(luma1: &LumaPlane, luma2: &LumaPlane) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let width = luma1.width.min(luma2.width);
+    let height = luma1.height.min(luma2.height);
+    let block_size = SSIM_BLOCK_SIZE.min(width).min(height);
+    if block_size == 0 {
+        return 1.0;
+    }
+
+    let mut total = 0f64;
+    let mut blocks = 0usize;
+    for block_y in (0..=(height - block_size)).step_by(block_size) {
+        for block_x in (0..=(width - block_size)).step_by(block_size) {
+            let (mut sum1, mut sum2, mut sum1_sq, mut sum2_sq, mut sum12) = (0f64, 0f64, 0f64, 0f64, 0f64);
+            for y in block_y..(block_y + block_size) {
+                for x in block_x..(block_x + block_size) {
+                    let value1 = f64::from(luma1.data[y * luma1.width + x]);
+                    let value2 = f64::from(luma2.data[y * luma2.width + x]);
+                    sum1 += value1;
+                    sum2 += value2;
+                    sum1_sq += value1 * value1;
+                    sum2_sq += value2 * value2;
+                    sum12 += value1 * value2;
+                }
+            }
+
+            let n = (block_size * block_size) as f64;
+            let (mean1, mean2) = (sum1 / n, sum2 / n);
+            let variance1 = sum1_sq / n - mean1 * mean1;
+            let variance2 = sum2_sq / n - mean2 * mean2;
+            let covariance = sum12 / n - mean1 * mean2;
+            total += ((2.0 * mean1 * mean2 + C1) * (2.0 * covariance + C2))
+                / ((mean1 * mean1 + mean2 * mean2 + C1) * (variance1 + variance2 + C2));
+            blocks += 1;
         }
     }
+    total / blocks as f64
 }
 
+/// Puts the scored candidates of a window in order, best first, and keeps the first `count`
+fn keep_best<C, F>(mut candidates: Vec<C>, count: usize, compare: F) -> Vec<C>
+where F: FnMut(&C, &C) -> Ordering {
+    candidates.sort_by(compare);
+    candidates.truncate(count);
+    candidates
+}
+
+/// Keeps the candidates `--select` asks for: the `count` which come first by `compare`, or with
+/// `worst` the `count` which come last
+fn keep_selected<C, F>(select: SelectMode, candidates: Vec<C>, count: usize, mut compare: F) -> Vec<C>
+where F: FnMut(&C, &C) -> Ordering {
+    match select {
+        SelectMode::Best => keep_best(candidates, count, compare),
+        SelectMode::Worst => keep_best(candidates, count, |a, b| compare(b, a)),
+    }
+}
+
+/// The values the MSE selector compares between frames - just the luma for `mse`, at the full
+/// precision of `--bit-depth`, every colour channel for `msergb` - taken from the region of
+/// interest if there is one
+fn get_mse_samples(frame: &VideoFrame, comparison_mode: ComparisonMode, roi: Option<Rect>) -> Vec<u16> {
+    let cropped = roi.map(|roi| crop_frame(frame, roi));
+    let frame = cropped.as_ref().unwrap_or(frame);
+    match comparison_mode {
+        ComparisonMode::MSERgb => get_color_data(frame).into_iter().map(u16::from).collect(),
+        _ => get_luma_data_16(frame),
+    }
+}
+
+/// Extracts every colour sample in the frame, plane by plane and row by row, leaving out any
+/// padding at the end of each line. Subsampled chroma planes are taken at their own resolution.
+fn get_color_data(frame: &VideoFrame) -> Vec<u8> {
+    let mut color_data = Vec::<u8>::new();
+    for plane in 0..frame.planes() {
+        let row_len = match frame.format() {
+            Pixel::RGB24 | Pixel::BGR24 => frame.plane_width(plane) as usize * 3,
+            Pixel::NV12 if plane == 1 => frame.plane_width(plane) as usize * 2,
+            Pixel::YUV420P | Pixel::YUVJ420P | Pixel::YUV422P | Pixel::YUV444P | Pixel::NV12 | Pixel::GRAY8 => frame.plane_width(plane) as usize,
+            format => panic!("Cannot extract colour data from a frame in {:?} format", format),
+        };
+        let stride = frame.stride(plane);
+        let data = frame.data(plane);
+        for y in 0..(frame.plane_height(plane) as usize) {
+            color_data.extend_from_slice(&data[(y * stride)..(y * stride + row_len)]);
+        }
+    }
+    color_data
+}
+
+/// Extracts the luma of every pixel in the frame row by row, leaving out any padding at the end
+/// of each line. YUV frames already carry luma in their first plane; for RGB frames it is computed
+/// with the Rec. 601 weights. 10-bit luma is cut down to 8 bits.
 fn get_luma_data(frame: &VideoFrame) -> Vec<u8> {
-    let mut luma_data = Vec::<u8>::new();
-    for i in 0..(frame.data(0).len() / 3) {
-        luma_data.push(frame.data(0)[i * 3]);
+    let width = frame.width() as usize;
+    let height = frame.height() as usize;
+    let stride = frame.stride(0);
+    let data = frame.data(0);
+
+    let mut luma_data = Vec::<u8>::with_capacity(width * height);
+    match frame.format() {
+        Pixel::RGB24 => {
+            for y in 0..height {
+                let row = &data[(y * stride)..(y * stride + width * 3)];
+                luma_data.extend(row.chunks_exact(3).map(|rgb| rec601_luma(rgb[0], rgb[1], rgb[2])));
+            }
+        },
+        Pixel::YUV420P | Pixel::YUVJ420P | Pixel::YUV422P | Pixel::YUV444P | Pixel::NV12 | Pixel::GRAY8 => {
+            for y in 0..height {
+                luma_data.extend_from_slice(&data[(y * stride)..(y * stride + width)]);
+            }
+        },
+        Pixel::YUV420P10LE => {
+            for y in 0..height {
+                let row = &data[(y * stride)..(y * stride + width * 2)];
+                luma_data.extend(row.chunks_exact(2).map(|sample| (u16::from_le_bytes([sample[0], sample[1]]) >> 2) as u8));
+            }
+        },
+        format => panic!("Cannot extract luma from a frame in {:?} format", format),
     }
     luma_data
 }
 
-fn mse(vec1: &Vec<u8>, vec2: &Vec<u8>) -> f64 {
-    let sum: u32 = vec1.iter().zip(vec2.iter()).map(|(a, b)| {
-        u32::from((i16::from(*a) - i16::from(*b)).saturating_pow(2) as u16)
-    }).fold(0u32, |acc, x| acc.saturating_add(x));
-    f64::from(sum) / f64::from(vec1.len() as u32)
+/// Like `get_luma_data`, but keeps all 10 bits of the luma of 10-bit frames
+fn get_luma_data_16(frame: &VideoFrame) -> Vec<u16> {
+    if frame.format() != Pixel::YUV420P10LE {
+        return get_luma_data(frame).into_iter().map(u16::from).collect();
+    }
+
+    let width = frame.width() as usize;
+    let stride = frame.stride(0);
+    let data = frame.data(0);
+    let mut luma_data = Vec::<u16>::with_capacity(width * frame.height() as usize);
+    for y in 0..(frame.height() as usize) {
+        let row = &data[(y * stride)..(y * stride + width * 2)];
+        luma_data.extend(row.chunks_exact(2).map(|sample| u16::from_le_bytes([sample[0], sample[1]])));
+    }
+    luma_data
+}
+
+fn rec601_luma(r: u8, g: u8, b: u8) -> u8 {
+    ((299 * u32::from(r) + 587 * u32::from(g) + 114 * u32::from(b) + 500) / 1000) as u8
+}
+
+fn mse<T: Copy + Into<i32>>(vec1: &[T], vec2: &[T]) -> f64 {
+    let count = vec1.len().min(vec2.len());
+    if count == 0 {
+        return 0.0;
+    }
+
+    // A u64 can't overflow here: even a 1023 difference on every 10-bit sample of an 8K frame
+    // sums to well under 2^50
+    let sum: u64 = vec1.iter().zip(vec2.iter()).map(|(a, b)| {
+        let diff = (*a).into() - (*b).into();
+        (diff * diff) as u64
+    }).sum();
+    sum as f64 / count as f64
 }
 
 impl<'a> MSEFrameSelector<'a> {
-    fn new(request: &'a Request) -> MSEFrameSelector {
+    fn new(request: &'a Request, reference: Option<VideoFrame>) -> MSEFrameSelector {
+        let reference_samples = reference.map(|frame| get_mse_samples(&frame, request.comparison_mode, request.roi));
         MSEFrameSelector {
             request,
-            last_frame: RefCell::new(None),
+            fixed_reference: reference_samples.is_some(),
+            last_frame: reference_samples,
         }
     }
 }
 
 struct HashFrameSelector<'a> {
     request: &'a Request,
-    last_hash: RefCell<Option<ImageHash>>,
+    last_hash: Option<ImageHash>,
+    /// Reference image which hasn't been hashed yet
+    reference: Option<VideoFrame>,
+    /// Whether `last_hash` is the hash of a reference image which should be kept rather than
+    /// replaced with every picked frame
+    fixed_reference: bool,
 }
 
 impl<'a> HashFrameSelector<'a> {
-    fn new(request: &'a Request) -> HashFrameSelector {
+    fn new(request: &'a Request, reference: Option<VideoFrame>) -> HashFrameSelector {
+        if request.verbose > 2 { log_message!("hash size: {}x{}", request.hash_size, request.hash_size); }
         HashFrameSelector {
             request,
-            last_hash: RefCell::new(None),
+            last_hash: None,
+            fixed_reference: reference.is_some(),
+            reference,
         }
     }
 }
 
-fn hash_frame(frame: &VideoFrame, comparison_mode: ComparisonMode) -> ImageHash {
+thread_local! {
+    /// Buffer `hash_frame` packs frames into. img_hash only takes images which own their data, so
+    /// this gets handed to the image and taken back afterwards, which saves allocating a new one
+    /// for every candidate in the parallel hashing loop.
+    static HASH_SCRATCH: RefCell<Vec<u8>> = RefCell::new(Vec::new());
+
+    /// Hasher `hash_frame` last built, along with the comparison mode and hash size it was built
+    /// for. The DCT plans it holds can't be shared between threads, so each thread keeps its own
+    /// rather than building one for every candidate.
+    static HASHER: RefCell<Option<(ComparisonMode, u32, ImageHasher)>> = RefCell::new(None);
+
+    /// Scaler `downscale_for_hash` last built. Like the hasher, each thread keeps its own so that
+    /// candidates can be shrunk in the parallel hashing loop.
+    static HASH_DOWNSCALER: RefCell<Option<ScalingContext>> = RefCell::new(None);
+}
+
+/// Crops the frame to `--roi` and shrinks it to `--hash-resolution` pixels square before hashing,
+/// so the hasher doesn't have to go through every pixel of a large frame. The scaler is kept
+/// around for as long as the frames keep the same size.
+fn downscale_for_hash(frame: &VideoFrame, request: &Request) -> Result<Option<VideoFrame>, TimelapseError> {
+    let cropped = request.roi.map(|roi| crop_frame(frame, roi));
+    let size = request.hash_resolution;
+    if size == 0 {
+        return Ok(cropped);
+    }
+    let frame = cropped.as_ref().unwrap_or(frame);
+    let format = if request.hash_luma { Pixel::GRAY8 } else { Pixel::RGB24 };
+
+    HASH_DOWNSCALER.with(|downscaler| {
+        let mut downscaler = downscaler.borrow_mut();
+        let reusable = downscaler.as_ref().map_or(false, |scaler| {
+            let (input, output) = (scaler.input(), scaler.output());
+            input.format == frame.format() && input.width == frame.width() && input.height == frame.height()
+                && output.format == format && output.width == size
+        });
+        if !reusable {
+            *downscaler = Some(ScalingContext::get(
+                frame.format(),
+                frame.width(),
+                frame.height(),
+                format,
+                size,
+                size,
+                ScalingFlags::AREA
+            )?);
+        }
+
+        let mut small_frame = VideoFrame::empty();
+        downscaler.as_mut().unwrap().run(frame, &mut small_frame)?;
+        Ok(Some(small_frame))
+    })
+}
+
+fn hash_frame(frame: &VideoFrame, comparison_mode: ComparisonMode, hash_size: u32) -> ImageHash {
+    HASHER.with(|hasher| {
+        let mut hasher = hasher.borrow_mut();
+        let reusable = hasher.as_ref().map_or(false, |(mode, size, _)| *mode == comparison_mode && *size == hash_size);
+        if !reusable {
+            *hasher = Some((comparison_mode, hash_size, build_hasher(comparison_mode, hash_size)));
+        }
+        let (_, _, hasher) = hasher.as_ref().unwrap();
+
+        HASH_SCRATCH.with(|scratch| {
+            let mut data = scratch.replace(Vec::new());
+            let (hash, data) = match frame.format() {
+                Pixel::RGB24 => {
+                    pack_plane_data(frame, 3, &mut data);
+                    let img_buffer = image::ImageBuffer::<image::Rgb<u8>, _>::from_raw(frame.width(), frame.height(), data).unwrap();
+                    (hasher.hash_image(&img_buffer), img_buffer.into_raw())
+                },
+                // GRAY8 from the downscaler, or the Y plane of a YUV frame with --hash-luma
+                _ => {
+                    pack_plane_data(frame, 1, &mut data);
+                    let img_buffer = image::ImageBuffer::<image::Luma<u8>, _>::from_raw(frame.width(), frame.height(), data).unwrap();
+                    (hasher.hash_image(&img_buffer), img_buffer.into_raw())
+                },
+            };
+            scratch.replace(data);
+            hash
+        })
+    })
+}
+
+fn build_hasher(comparison_mode: ComparisonMode, hash_size: u32) -> ImageHasher {
     // Blockhash is fast but might not work in all cases
-    let hasher = HasherConfig::new().hash_alg(get_hash_alg(comparison_mode)).to_hasher();
-    let data = frame.data(0).to_vec();
+    let mut config = HasherConfig::new()
+        .hash_alg(get_hash_alg(comparison_mode))
+        .hash_size(hash_size, hash_size);
+    // Mean hash over the DCT of the image is what's usually called pHash
+    if comparison_mode == ComparisonMode::DctHash {
+        config = config.preproc_dct();
+    }
+    config.to_hasher()
+}
 
-    let buffer = image::FlatSamples {
-        samples: data,
-        layout: image::flat::SampleLayout::row_major_packed(3, frame.width(), frame.height()),
-        color_hint: Some(image::ColorType::Rgb8),
-    };
+/// Copies the first plane of the frame, with the given number of bytes per pixel, into a tightly
+/// packed buffer, dropping the padding ffmpeg may add at the end of each line
+fn pack_plane_data(frame: &VideoFrame, bytes_per_pixel: usize, packed: &mut Vec<u8>) {
+    let row_len = frame.width() as usize * bytes_per_pixel;
+    let stride = frame.stride(0);
+    let data = frame.data(0);
 
-    let img_buffer = buffer.try_into_buffer::<image::Rgb<u8>>().unwrap();
-    hasher.hash_image(&img_buffer)
+    packed.clear();
+    packed.reserve(row_len * frame.height() as usize);
+    for y in 0..(frame.height() as usize) {
+        packed.extend_from_slice(&data[(y * stride)..(y * stride + row_len)]);
+    }
 }
 
 fn get_hash_alg(comparison_mode: ComparisonMode) -> HashAlg {
     match comparison_mode {
         ComparisonMode::Blockhash => HashAlg::Blockhash,
         ComparisonMode::GradientHash => HashAlg::DoubleGradient,
-        ComparisonMode::MeanHash => HashAlg::Mean,
+        ComparisonMode::MeanHash | ComparisonMode::DctHash => HashAlg::Mean,
         _ => panic!("Invalid comparison mode given to HashFrameSelector: {:?}", comparison_mode)
     }
 }
 
 impl<'a> FrameSelector for HashFrameSelector<'a> {
-    fn pick_best(&mut self, window: Vec<VideoFrame>) -> Result<VideoFrame, FrameSelectionError> {
+    fn pick_best_n(&mut self, window: Vec<VideoFrame>, count: usize) -> Result<Vec<Selection>, TimelapseError> {
         let mut window = window;
-        if self.last_hash.borrow().is_none() {
-            let frame = window.remove(0);
-            let hash = hash_frame(&frame, self.request.comparison_mode);
-            self.last_hash.replace(Some(hash));
-            return Ok(frame);
+        if let Some(reference) = self.reference.take() {
+            let small_reference = downscale_for_hash(&reference, self.request)?;
+            let hash = hash_frame(small_reference.as_ref().unwrap_or(&reference), self.request.comparison_mode, self.request.hash_size);
+            if self.request.verbose > 2 { log_message!("reference hash: {}", hash.to_base64()); }
+            self.last_hash = Some(hash);
         }
 
-        let last_hash = self.last_hash.borrow().clone().unwrap();
-        if self.request.verbose > 2 { println!("last hash: {}", last_hash.to_base64()); }
+        let first_frame = match self.last_hash {
+            Some(_) => None,
+            None => {
+                let frame = take_first_frame(&mut window, count)?;
+                let small_frame = downscale_for_hash(&frame, self.request)?;
+                self.last_hash = Some(hash_frame(small_frame.as_ref().unwrap_or(&frame), self.request.comparison_mode, self.request.hash_size));
+                Some(frame)
+            },
+        };
+        let count = if first_frame.is_some() { count.saturating_sub(1) } else { count };
+        let last_hash = self.last_hash.as_ref().unwrap();
+        if self.request.verbose > 2 { log_message!("last hash: {}", last_hash.to_base64()); }
 
-        let verbose = self.request.verbose;
-        let comparison_mode = self.request.comparison_mode;
-        let hashing_result = window.into_par_iter().map(|frame| {
-            let hash = hash_frame(&frame, comparison_mode);
+        let request = self.request;
+        let hashed = window.into_par_iter().enumerate().map(|(i, frame)| {
+            let small_frame = downscale_for_hash(&frame, request)?;
+            let hash = hash_frame(small_frame.as_ref().unwrap_or(&frame), request.comparison_mode, request.hash_size);
             let dist = last_hash.dist(&hash);
-            if verbose > 5 { println!("    candidate hash: {} (distance {})", hash.to_base64(), dist); }
-            (frame, hash, dist)
-        }).min_by_key(|&(_, _, dist)| dist);
-
-        if let Some((frame, hash, dist)) = hashing_result {
-            if self.request.verbose > 2 { println!("    selected hash: {} (distance {})", hash.to_base64(), dist); }
-            self.last_hash.replace(Some(hash));
-            Ok(frame)
+            if request.verbose > 5 { log_message!("    candidate hash: {} (distance {})", hash.to_base64(), dist); }
+            Ok((i, frame, hash, dist))
+        }).collect::<Result<Vec<_>, TimelapseError>>()?;
+        let mut hashing_result = keep_selected(self.request.select, hashed, count, |(_, _, _, dist1), (_, _, _, dist2)| dist1.cmp(dist2)).into_iter();
+
+        let mut selections = Vec::new();
+        if let Some((i, frame, hash, dist)) = hashing_result.next() {
+            if self.request.verbose > 2 { log_message!("    selected hash: {} (distance {})", hash.to_base64(), dist); }
+            if !self.fixed_reference {
+                self.last_hash = Some(hash);
+            }
+            selections.push(Selection::new(frame, i, Some(dist as f64)));
+            selections.extend(hashing_result.map(|(i, frame, _, dist)| Selection::new(frame, i, Some(dist as f64))));
+        } else if first_frame.is_none() && self.request.verbose > 0 {
+            log_message!("end of file reached");
+        }
+        with_first_frame(first_frame, selections)
+    }
+}
+
+/// Picks the frame with the highest focus measure in each window, regardless of the previous frame
+struct SharpestFrameSelector<'a> {
+    request: &'a Request,
+}
+
+impl<'a> SharpestFrameSelector<'a> {
+    fn new(request: &'a Request) -> SharpestFrameSelector {
+        SharpestFrameSelector {
+            request,
+        }
+    }
+}
+
+impl<'a> FrameSelector for SharpestFrameSelector<'a> {
+    fn pick_best_n(&mut self, window: Vec<VideoFrame>, count: usize) -> Result<Vec<Selection>, TimelapseError> {
+        let verbose = self.request.verbose;
+        let candidates = window.into_par_iter().enumerate().map(|(i, frame)| {
+            let luma = get_luma_data(&frame);
+            let sharpness = laplacian_variance(&luma, frame.width() as usize, frame.height() as usize);
+            if verbose > 5 { log_message!("    candidate sharpness: {}", sharpness); }
+            (i, frame, sharpness)
+        }).collect::<Vec<_>>();
+        let result = keep_best(candidates, count, |(_, _, s1), (_, _, s2)| s2.partial_cmp(s1).unwrap_or(Ordering::Equal));
+
+        if let Some((_, _, sharpness)) = result.first() {
+            if self.request.verbose > 2 { log_message!("sharpness = {}", sharpness); }
+            Ok(result.into_iter().map(|(i, frame, sharpness)| Selection::new(frame, i, Some(sharpness))).collect())
         } else {
-            if self.request.verbose > 0 { println!("end of file reached"); }
-            Err(FrameSelectionError::EmptyInput)
+            Err(FrameSelectionError::EmptyInput.into())
         }
     }
 }
 
+/// Variance of the 4-neighbour Laplacian of the luma plane - the more fine detail the frame has,
+/// the higher this is
+fn laplacian_variance(luma: &[u8], width: usize, height: usize) -> f64 {
+    if width < 3 || height < 3 {
+        return 0.0;
+    }
+
+    let mut sum = 0f64;
+    let mut sum_sq = 0f64;
+    for y in 1..(height - 1) {
+        for x in 1..(width - 1) {
+            let i = y * width + x;
+            let laplacian = i32::from(luma[i - width]) + i32::from(luma[i + width])
+                + i32::from(luma[i - 1]) + i32::from(luma[i + 1])
+                - 4 * i32::from(luma[i]);
+            let laplacian = f64::from(laplacian);
+            sum += laplacian;
+            sum_sq += laplacian * laplacian;
+        }
+    }
+
+    let count = ((width - 2) * (height - 2)) as f64;
+    let mean = sum / count;
+    sum_sq / count - mean * mean
+}
+
+/// Picks the frame whose mean luma is closest to the requested target in each window
+struct ExposureFrameSelector<'a> {
+    request: &'a Request,
+}
+
+impl<'a> ExposureFrameSelector<'a> {
+    fn new(request: &'a Request) -> ExposureFrameSelector {
+        ExposureFrameSelector {
+            request,
+        }
+    }
+}
+
+impl<'a> FrameSelector for ExposureFrameSelector<'a> {
+    fn pick_best_n(&mut self, window: Vec<VideoFrame>, count: usize) -> Result<Vec<Selection>, TimelapseError> {
+        let verbose = self.request.verbose;
+        let target = f64::from(self.request.target_luma);
+        let invert = self.request.invert;
+        let candidates = window.into_par_iter().enumerate().map(|(i, frame)| {
+            let mut luma = get_luma_data(&frame);
+            if invert {
+                invert_luma(&mut luma);
+            }
+            let brightness = mean_luma(&luma);
+            if verbose > 5 { log_message!("    candidate mean luma: {}", brightness); }
+            (i, frame, brightness)
+        }).collect::<Vec<_>>();
+        let result = keep_best(candidates, count, |(_, _, b1), (_, _, b2)| (b1 - target).abs().partial_cmp(&(b2 - target).abs()).unwrap_or(Ordering::Equal));
+
+        if let Some((_, _, brightness)) = result.first() {
+            if self.request.verbose > 2 { log_message!("mean luma = {}", brightness); }
+            Ok(result.into_iter().map(|(i, frame, brightness)| Selection::new(frame, i, Some(brightness))).collect())
+        } else {
+            Err(FrameSelectionError::EmptyInput.into())
+        }
+    }
+}
+
+fn mean_luma(luma: &[u8]) -> f64 {
+    if luma.is_empty() {
+        return 0.0;
+    }
+
+    let sum: u64 = luma.iter().map(|&y| u64::from(y)).sum();
+    sum as f64 / luma.len() as f64
+}
+
+/// Picks the frame whose colour histogram is closest to that of the previous frame. Histograms
+/// don't care where in the frame the colours are, so camera shake barely affects them.
+struct HistogramFrameSelector<'a> {
+    request: &'a Request,
+    last_histogram: Option<Vec<f64>>,
+}
+
+impl<'a> HistogramFrameSelector<'a> {
+    fn new(request: &'a Request) -> HistogramFrameSelector {
+        HistogramFrameSelector {
+            request,
+            last_histogram: None,
+        }
+    }
+}
+
+impl<'a> FrameSelector for HistogramFrameSelector<'a> {
+    fn pick_best_n(&mut self, window: Vec<VideoFrame>, count: usize) -> Result<Vec<Selection>, TimelapseError> {
+        let mut window = window;
+        let first_frame = match self.last_histogram {
+            Some(_) => None,
+            None => {
+                let frame = take_first_frame(&mut window, count)?;
+                self.last_histogram = Some(get_histogram(&frame, self.request.roi));
+                Some(frame)
+            },
+        };
+        let count = if first_frame.is_some() { count.saturating_sub(1) } else { count };
+
+        let verbose = self.request.verbose;
+        let roi = self.request.roi;
+        let result = {
+            let previous_histogram = self.last_histogram.as_ref().unwrap();
+            let candidates = window.into_par_iter().enumerate().map(|(i, frame)| {
+                let histogram = get_histogram(&frame, roi);
+                let distance = chi_squared_distance(&histogram, previous_histogram);
+                if verbose > 5 { log_message!("    candidate histogram distance: {}", distance); }
+                (i, frame, histogram, distance)
+            }).collect::<Vec<_>>();
+            keep_selected(self.request.select, candidates, count, |(_, _, _, d1), (_, _, _, d2)| d1.partial_cmp(d2).unwrap_or(Ordering::Equal))
+        };
+
+        let mut result = result.into_iter();
+        let mut selections = Vec::new();
+        if let Some((i, frame, histogram, distance)) = result.next() {
+            if self.request.verbose > 2 { log_message!("histogram distance = {}", distance); }
+            self.last_histogram = Some(histogram);
+            selections.push(Selection::new(frame, i, Some(distance)));
+            selections.extend(result.map(|(i, frame, _, distance)| Selection::new(frame, i, Some(distance))));
+        }
+        with_first_frame(first_frame, selections)
+    }
+}
+
+/// Builds a 256 bin histogram for each channel of the frame (R, G, B for RGB frames, or each plane
+/// for planar YUV frames), normalised so that frames of different sizes can be compared
+fn get_histogram(frame: &VideoFrame, roi: Option<Rect>) -> Vec<f64> {
+    let cropped = roi.map(|roi| crop_frame(frame, roi));
+    let frame = cropped.as_ref().unwrap_or(frame);
+
+    let mut counts = vec![0u64; 3 * 256];
+    match frame.format() {
+        Pixel::RGB24 => {
+            let row_len = frame.width() as usize * 3;
+            let stride = frame.stride(0);
+            let data = frame.data(0);
+            for y in 0..(frame.height() as usize) {
+                for rgb in data[(y * stride)..(y * stride + row_len)].chunks_exact(3) {
+                    counts[usize::from(rgb[0])] += 1;
+                    counts[256 + usize::from(rgb[1])] += 1;
+                    counts[512 + usize::from(rgb[2])] += 1;
+                }
+            }
+        },
+        Pixel::YUV420P | Pixel::YUVJ420P | Pixel::YUV422P | Pixel::YUV444P => {
+            for plane in 0..3 {
+                let row_len = frame.plane_width(plane) as usize;
+                let stride = frame.stride(plane);
+                let data = frame.data(plane);
+                for y in 0..(frame.plane_height(plane) as usize) {
+                    for &value in &data[(y * stride)..(y * stride + row_len)] {
+                        counts[plane * 256 + usize::from(value)] += 1;
+                    }
+                }
+            }
+        },
+        format => panic!("Cannot build a histogram of a frame in {:?} format", format),
+    }
+
+    counts.chunks(256).flat_map(|channel| {
+        let total = channel.iter().sum::<u64>().max(1) as f64;
+        channel.iter().map(move |&count| count as f64 / total)
+    }).collect()
+}
+
+/// Chi-squared distance between two normalised histograms - 0 for identical histograms, up to the
+/// number of channels for completely disjoint ones
+fn chi_squared_distance(histogram1: &[f64], histogram2: &[f64]) -> f64 {
+    histogram1.iter().zip(histogram2.iter()).map(|(a, b)| {
+        let sum = a + b;
+        if sum > 0.0 { (a - b) * (a - b) / sum } else { 0.0 }
+    }).sum()
+}
+
+/// Lets a frame through only if it differs enough from the last frame it let through, measured as
+/// the mean square error of their luma. The first frame always gets through.
+pub struct ChangeGate {
+    threshold: f64,
+    last_luma: Option<Vec<u8>>,
+    last_difference: Option<f64>,
+}
+
+impl ChangeGate {
+    pub fn new(threshold: f64) -> Self {
+        Self {
+            threshold,
+            last_luma: None,
+            last_difference: None,
+        }
+    }
+
+    /// Returns whether the frame should be emitted
+    pub fn check(&mut self, frame: &VideoFrame) -> bool {
+        let luma = get_luma_data(frame);
+        let difference = self.last_luma.as_ref().map(|last_luma| mse(&luma, last_luma));
+        if difference.map_or(true, |difference| difference >= self.threshold) {
+            self.last_luma = Some(luma);
+            self.last_difference = difference;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Difference between the last frame let through and the one before it
+    pub fn last_difference(&self) -> Option<f64> {
+        self.last_difference
+    }
+}
+
+/// What to do with a frame given to a `StaticTrimmer`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Trim {
+    Skip,
+    Keep,
+    Stop,
+}
+
+/// Leaves out the frames at the start of the input until the scene first changes, and tells when
+/// the scene has stayed still for long enough that the rest of the input can be left out too
+pub struct StaticTrimmer {
+    gate: ChangeGate,
+    /// How long the scene must stay still, in the time base of the frame timestamps
+    tail_pts: i64,
+    started: bool,
+    stopped: bool,
+    last_change_pts: Option<i64>,
+}
+
+impl StaticTrimmer {
+    pub fn new(threshold: f64, tail_pts: i64) -> Self {
+        Self {
+            gate: ChangeGate::new(threshold),
+            tail_pts,
+            started: false,
+            stopped: false,
+            last_change_pts: None,
+        }
+    }
+
+    /// Once a frame has been told to stop at, every frame after it is too
+    pub fn check(&mut self, frame: &VideoFrame) -> Trim {
+        if self.stopped {
+            return Trim::Stop;
+        }
+
+        // The first frame always gets through the gate, but only sets the scene to compare against
+        if self.gate.check(frame) && self.gate.last_difference().is_some() {
+            self.started = true;
+            self.last_change_pts = frame.pts();
+            return Trim::Keep;
+        }
+
+        if !self.started {
+            return Trim::Skip;
+        }
+        match (self.last_change_pts, frame.pts()) {
+            (Some(last_change_pts), Some(pts)) if pts - last_change_pts > self.tail_pts => {
+                self.stopped = true;
+                Trim::Stop
+            },
+            _ => Trim::Keep,
+        }
+    }
+}
+
+/// Spots frames which are the same as the frame before them - byte for byte, or with a non-zero
+/// epsilon, to within that mean square error of their luma
+pub struct DuplicateFilter {
+    epsilon: f64,
+    last_hash: Option<u64>,
+    last_luma: Option<Vec<u8>>,
+}
+
+impl DuplicateFilter {
+    pub fn new(epsilon: f64) -> Self {
+        Self {
+            epsilon,
+            last_hash: None,
+            last_luma: None,
+        }
+    }
+
+    /// Returns whether the frame is a duplicate of the one given before it
+    pub fn is_duplicate(&mut self, frame: &VideoFrame) -> bool {
+        if self.epsilon > 0.0 {
+            let luma = get_luma_data(frame);
+            let duplicate = self.last_luma.as_ref().map_or(false, |last_luma| mse(&luma, last_luma) <= self.epsilon);
+            self.last_luma = Some(luma);
+            duplicate
+        } else {
+            let hash = hash_picture(frame);
+            let duplicate = self.last_hash == Some(hash);
+            self.last_hash = Some(hash);
+            duplicate
+        }
+    }
+}
+
+/// Hashes the picture data of every plane of the frame, leaving out the padding at the end of each
+/// line
+fn hash_picture(frame: &VideoFrame) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for plane in 0..frame.planes() {
+        let row_len = plane_row_len(frame, plane);
+        let stride = frame.stride(plane);
+        let data = frame.data(plane);
+        for y in 0..(frame.plane_height(plane) as usize) {
+            hasher.write(&data[(y * stride)..(y * stride + row_len)]);
+        }
+    }
+    hasher.finish()
+}
+
 struct NoopFrameSelector;
 
 impl FrameSelector for NoopFrameSelector {
-    fn pick_best(&mut self, window: Vec<VideoFrame>) -> Result<VideoFrame, FrameSelectionError> {
-        let mut window = window;
+    fn pick_best_n(&mut self, window: Vec<VideoFrame>, count: usize) -> Result<Vec<Selection>, TimelapseError> {
         if window.is_empty() {
-            Err(FrameSelectionError::EmptyInput)
+            Err(FrameSelectionError::EmptyInput.into())
         } else {
-            Ok(window.remove(0))
+            Ok(window.into_iter().take(count).enumerate().map(|(i, frame)| Selection::new(frame, i, None)).collect())
         }
     }
 }
@@ -169,3 +982,489 @@ impl FrameSelector for NoopFrameSelector {
 pub enum FrameSelectionError {
     EmptyInput,
 }
+
+impl Display for FrameSelectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameSelectionError::EmptyInput => write!(f, "no frames to select from"),
+        }
+    }
+}
+
+impl Error for FrameSelectionError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post_processing::{flat_frame, luma_frame};
+
+    #[test]
+    fn ten_bit_luma_keeps_values_above_255() {
+        ffmpeg::init().unwrap();
+        let mut frame = VideoFrame::new(Pixel::YUV420P10LE, 4, 4);
+        let stride = frame.stride(0);
+        for y in 0..4 {
+            for sample in frame.data_mut(0)[(y * stride)..(y * stride + 8)].chunks_exact_mut(2) {
+                sample.copy_from_slice(&1000u16.to_le_bytes());
+            }
+        }
+
+        let samples = get_mse_samples(&frame, ComparisonMode::MSE, None);
+        assert_eq!(samples, vec![1000; 16]);
+        assert_eq!(mse(&samples, &[0; 16]), 1_000_000.0);
+        // Cut down to 8 bits, it would have been 250
+        assert_eq!(get_luma_data(&frame), vec![250; 16]);
+    }
+
+    #[test]
+    fn inverting_twice_gives_the_luma_back() {
+        let luma = (0..=255).collect::<Vec<u8>>();
+        let mut inverted = luma.clone();
+        invert_luma(&mut inverted);
+        assert_ne!(inverted, luma);
+        invert_luma(&mut inverted);
+        assert_eq!(inverted, luma);
+    }
+
+    #[test]
+    fn inverting_changes_ssim() {
+        let plane = |data: Vec<u8>| LumaPlane { data, width: 8, height: 8 };
+        let dark = (0..64).map(|i| (i % 8) as u8 * 4).collect::<Vec<_>>();
+        let darker = dark.iter().map(|&value| value / 2).collect::<Vec<_>>();
+        let (mut dark_inverted, mut darker_inverted) = (dark.clone(), darker.clone());
+        invert_luma(&mut dark_inverted);
+        invert_luma(&mut darker_inverted);
+
+        let similarity = ssim(&plane(dark), &plane(darker));
+        let inverted_similarity = ssim(&plane(dark_inverted), &plane(darker_inverted));
+        assert!((similarity - inverted_similarity).abs() > 1e-6, "{} vs {}", similarity, inverted_similarity);
+    }
+
+    #[test]
+    fn first_window_gives_as_many_frames_as_asked_for() {
+        ffmpeg::init().unwrap();
+        let modes = [ComparisonMode::MSE, ComparisonMode::SSIM, ComparisonMode::MeanHash, ComparisonMode::Histogram];
+        for &mode in modes.iter() {
+            let mut request = Request::new();
+            request.set_comparison_mode(mode);
+            let mut selector = get_frame_selector(&request, None);
+            let format = required_pixel_format(&request);
+            let window = [0, 60, 120, 180].iter().map(|&value| flat_frame(format, 32, 32, value)).collect();
+
+            let picked = selector.pick_best_n(window, 3).unwrap();
+            let mut indices = picked.iter().map(|selection| selection.window_index).collect::<Vec<_>>();
+            assert_eq!(indices[0], 0, "{:?}", mode);
+            indices.sort();
+            indices.dedup();
+            assert_eq!(indices.len(), 3, "{:?}", mode);
+            assert!(indices.iter().all(|&i| i < 4), "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn first_window_gives_only_the_first_frame_when_one_is_asked_for() {
+        ffmpeg::init().unwrap();
+        let mut request = Request::new();
+        request.set_comparison_mode(ComparisonMode::MSE);
+        let mut selector = get_frame_selector(&request, None);
+        let window = [0, 60, 120].iter().map(|&value| flat_frame(Pixel::YUV420P, 32, 32, value)).collect();
+
+        let picked = selector.pick_best_n(window, 1).unwrap();
+        assert_eq!(picked.len(), 1);
+        assert_eq!(picked[0].window_index, 0);
+    }
+
+    #[test]
+    fn mse_of_the_largest_difference_does_not_wrap() {
+        assert_eq!(mse(&[255u8; 4], &[0u8; 4]), 65025.0);
+        assert_eq!(mse(&[0u8; 4], &[255u8; 4]), 65025.0);
+        assert_eq!(mse(&[10u8, 20, 30, 40], &[10u8, 20, 30, 40]), 0.0);
+        assert_eq!(mse(&[10u8, 20], &[13u8, 16]), 12.5);
+    }
+
+    #[test]
+    fn mse_sums_past_what_a_u32_holds() {
+        // 5000 differences of 1023 squared add up to more than 2^32
+        let samples = vec![1023u16; 5000];
+        assert_eq!(mse(&samples, &[0u16; 5000]), 1_046_529.0);
+    }
+
+    #[test]
+    fn mse_compares_as_many_samples_as_both_have() {
+        assert_eq!(mse(&[2u8, 2, 100], &[0u8, 0]), 4.0);
+        assert_eq!(mse::<u8>(&[], &[]), 0.0);
+    }
+
+    #[test]
+    fn luma_data_skips_the_padding_at_the_end_of_each_row() {
+        ffmpeg::init().unwrap();
+        let mut frame = flat_frame(Pixel::YUV420P, 4, 4, 99);
+        let stride = frame.stride(0);
+        assert!(stride > 4);
+        for y in 0..4 {
+            for x in 0..4 {
+                frame.data_mut(0)[y * stride + x] = (y * 4 + x) as u8;
+            }
+        }
+        assert_eq!(get_luma_data(&frame), (0..16).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn luma_data_of_rgb_frames_is_weighted_like_rec601() {
+        ffmpeg::init().unwrap();
+        let mut frame = flat_frame(Pixel::RGB24, 4, 4, 99);
+        let stride = frame.stride(0);
+        let rows: [[u8; 3]; 4] = [[0, 0, 0], [255, 255, 255], [255, 0, 0], [0, 255, 0]];
+        for (y, rgb) in rows.iter().enumerate() {
+            for x in 0..4 {
+                frame.data_mut(0)[(y * stride + x * 3)..(y * stride + x * 3 + 3)].copy_from_slice(rgb);
+            }
+        }
+
+        let mut expected = vec![0; 4];
+        expected.extend_from_slice(&[255; 4]);
+        expected.extend_from_slice(&[76; 4]);
+        expected.extend_from_slice(&[150; 4]);
+        assert_eq!(get_luma_data(&frame), expected);
+    }
+
+    #[test]
+    fn keep_selected_keeps_the_first_or_the_last_in_order() {
+        let compare = |a: &u32, b: &u32| a.cmp(b);
+        assert_eq!(keep_selected(SelectMode::Best, vec![3, 1, 4, 2], 2, compare), vec![1, 2]);
+        assert_eq!(keep_selected(SelectMode::Worst, vec![3, 1, 4, 2], 2, compare), vec![4, 3]);
+        assert_eq!(keep_selected(SelectMode::Worst, vec![3, 1], 5, compare), vec![3, 1]);
+    }
+
+    #[test]
+    fn selecting_the_worst_picks_the_frame_least_like_the_last_one() {
+        ffmpeg::init().unwrap();
+        let mut request = Request::new();
+        request.set_comparison_mode(ComparisonMode::MSE);
+        let window = || vec![
+            flat_frame(Pixel::YUV420P, 8, 8, 100),
+            flat_frame(Pixel::YUV420P, 8, 8, 150),
+            flat_frame(Pixel::YUV420P, 8, 8, 110),
+        ];
+
+        let reference = Some(flat_frame(Pixel::YUV420P, 8, 8, 100));
+        let best = get_frame_selector(&request, reference).pick_best(window()).unwrap();
+        assert_eq!(best.window_index, 0);
+
+        request.set_select(SelectMode::Worst);
+        let reference = Some(flat_frame(Pixel::YUV420P, 8, 8, 100));
+        let worst = get_frame_selector(&request, reference).pick_best(window()).unwrap();
+        assert_eq!(worst.window_index, 1);
+        assert_eq!(worst.score, Some(2500.0));
+    }
+
+    fn flat_window(values: &[u8]) -> Vec<VideoFrame> {
+        values.iter().map(|&value| flat_frame(Pixel::YUV420P, 8, 8, value)).collect()
+    }
+
+    #[test]
+    fn mse_selector_compares_with_the_frame_it_picked_last() {
+        ffmpeg::init().unwrap();
+        let mut request = Request::new();
+        request.set_comparison_mode(ComparisonMode::MSE);
+        let mut selector = get_frame_selector(&request, None);
+
+        assert_eq!(selector.pick_best(flat_window(&[10, 50, 90])).unwrap().window_index, 0);
+        assert_eq!(selector.pick_best(flat_window(&[80, 20])).unwrap().window_index, 1);
+        // 35 is nearer to the 20 picked last, 0 to the 10 picked before that
+        assert_eq!(selector.pick_best(flat_window(&[35, 0])).unwrap().window_index, 0);
+    }
+
+    #[test]
+    fn mse_selector_keeps_comparing_with_the_reference() {
+        ffmpeg::init().unwrap();
+        let mut request = Request::new();
+        request.set_comparison_mode(ComparisonMode::MSE);
+        let mut selector = get_frame_selector(&request, Some(flat_frame(Pixel::YUV420P, 8, 8, 10)));
+
+        assert_eq!(selector.pick_best(flat_window(&[80, 20])).unwrap().window_index, 1);
+        // Had the 20 replaced the reference, the 35 would have been picked
+        assert_eq!(selector.pick_best(flat_window(&[35, 0])).unwrap().window_index, 1);
+    }
+
+    #[test]
+    fn selectors_ask_for_the_format_they_read_directly() {
+        let mut request = Request::new();
+        let modes = [
+            (ComparisonMode::Noop, Pixel::YUV420P),
+            (ComparisonMode::Blockhash, Pixel::RGB24),
+            (ComparisonMode::GradientHash, Pixel::RGB24),
+            (ComparisonMode::MeanHash, Pixel::RGB24),
+            (ComparisonMode::DctHash, Pixel::RGB24),
+            (ComparisonMode::MSE, Pixel::YUV420P),
+            (ComparisonMode::MSERgb, Pixel::YUV420P),
+            (ComparisonMode::SSIM, Pixel::YUV420P),
+            (ComparisonMode::Sharpest, Pixel::YUV420P),
+            (ComparisonMode::BestExposure, Pixel::YUV420P),
+            (ComparisonMode::Motion, Pixel::YUV420P),
+            (ComparisonMode::Histogram, Pixel::RGB24),
+        ];
+        for &(comparison_mode, format) in modes.iter() {
+            request.set_comparison_mode(comparison_mode);
+            assert_eq!(required_pixel_format(&request), format, "{:?}", comparison_mode);
+        }
+
+        // With --hash-luma the hash selectors only need the Y plane
+        request.set_comparison_mode(ComparisonMode::DctHash).set_hash_luma(true);
+        assert_eq!(required_pixel_format(&request), Pixel::YUV420P);
+    }
+
+    #[test]
+    fn sharp_frame_beats_a_blurred_copy() {
+        ffmpeg::init().unwrap();
+        let sharp = |x: u32, y: u32| -> u8 { if (x / 2 + y / 2) % 2 == 0 { 40 } else { 220 } };
+        // 3x3 box blur of the sharp frame, repeating the edge pixels
+        let blurred = |x: u32, y: u32| {
+            let mut sum = 0u32;
+            for dy in 0..3 {
+                for dx in 0..3 {
+                    let sx = (x + dx).saturating_sub(1).min(31);
+                    let sy = (y + dy).saturating_sub(1).min(31);
+                    sum += u32::from(sharp(sx, sy));
+                }
+            }
+            (sum / 9) as u8
+        };
+        let mut request = Request::new();
+        request.set_comparison_mode(ComparisonMode::Sharpest);
+        let window = vec![luma_frame(32, 32, blurred), luma_frame(32, 32, sharp), luma_frame(32, 32, blurred)];
+
+        let picked = get_frame_selector(&request, None).pick_best(window).unwrap();
+        assert_eq!(picked.window_index, 1);
+    }
+
+    #[test]
+    fn exposure_selector_picks_the_frame_closest_to_the_target() {
+        ffmpeg::init().unwrap();
+        let mut request = Request::new();
+        request.set_comparison_mode(ComparisonMode::BestExposure).set_target_luma(128);
+        let window = vec![
+            flat_frame(Pixel::YUV420P, 16, 16, 40),
+            flat_frame(Pixel::YUV420P, 16, 16, 120),
+            flat_frame(Pixel::YUV420P, 16, 16, 210),
+        ];
+
+        let picked = get_frame_selector(&request, None).pick_best(window).unwrap();
+        assert_eq!(picked.window_index, 1);
+        assert_eq!(picked.score, Some(120.0));
+    }
+
+    #[test]
+    fn msergb_tells_apart_frames_which_only_differ_in_colour() {
+        ffmpeg::init().unwrap();
+        let grey = flat_frame(Pixel::YUV420P, 16, 16, 128);
+        let mut tinted = flat_frame(Pixel::YUV420P, 16, 16, 128);
+        for sample in tinted.data_mut(1).iter_mut() {
+            *sample = 200;
+        }
+
+        let distance = |mode: ComparisonMode| mse(&get_mse_samples(&grey, mode, None), &get_mse_samples(&tinted, mode, None));
+        assert_eq!(distance(ComparisonMode::MSE), 0.0);
+        assert!(distance(ComparisonMode::MSERgb) > 0.0);
+    }
+
+    #[test]
+    fn shifted_frame_has_the_same_histogram() {
+        ffmpeg::init().unwrap();
+        let pattern = |x: u32, y: u32| ((x * 7 + y * 3) % 256) as u8;
+        let frame = luma_frame(32, 32, pattern);
+        // Moved along by 5 pixels, with what falls off one side coming back on the other
+        let shifted = luma_frame(32, 32, |x, y| pattern((x + 5) % 32, y));
+        let other = luma_frame(32, 32, |x, y| 255 - pattern(x, y) / 4);
+
+        let histogram = get_histogram(&frame, None);
+        assert!(chi_squared_distance(&histogram, &get_histogram(&shifted, None)) < 1e-9);
+        assert!(chi_squared_distance(&histogram, &get_histogram(&other, None)) > 0.5);
+    }
+
+    #[test]
+    fn duplicate_filter_drops_repeated_frames_and_keeps_changed_ones() {
+        ffmpeg::init().unwrap();
+        let mut exact = DuplicateFilter::new(0.0);
+        let frames = [100, 100, 101, 101, 100].iter().map(|&value| flat_frame(Pixel::YUV420P, 16, 16, value)).collect::<Vec<_>>();
+        let duplicates = frames.iter().map(|frame| exact.is_duplicate(frame)).collect::<Vec<_>>();
+        assert_eq!(duplicates, vec![false, true, false, true, false]);
+
+        // Within an epsilon, frames which are nearly the same count as duplicates too
+        let mut near = DuplicateFilter::new(2.0);
+        let frames = [100, 101, 102, 120, 121].iter().map(|&value| flat_frame(Pixel::YUV420P, 16, 16, value)).collect::<Vec<_>>();
+        let duplicates = frames.iter().map(|frame| near.is_duplicate(frame)).collect::<Vec<_>>();
+        assert_eq!(duplicates, vec![false, true, true, false, true]);
+    }
+
+    #[test]
+    fn change_gate_skips_frames_under_the_threshold() {
+        ffmpeg::init().unwrap();
+        let mut gate = ChangeGate::new(50.0);
+        // Each frame is compared with the last one let through: 107 is only 7 away from the 100
+        // let through before it, but 108 is 8 away
+        let let_through = [100, 105, 107, 108, 108, 100].iter()
+            .map(|&value| gate.check(&flat_frame(Pixel::YUV420P, 16, 16, value)))
+            .collect::<Vec<_>>();
+        assert_eq!(let_through, vec![true, false, false, true, false, true]);
+        assert_eq!(gate.last_difference(), Some(64.0));
+    }
+
+    #[test]
+    fn static_trimmer_stops_once_the_scene_has_been_still_for_the_tail() {
+        ffmpeg::init().unwrap();
+        let mut trimmer = StaticTrimmer::new(50.0, 3);
+        let trims = [100, 100, 150, 150, 150, 150, 150, 200].iter().enumerate()
+            .map(|(pts, &value)| {
+                let mut frame = flat_frame(Pixel::YUV420P, 16, 16, value);
+                frame.set_pts(Some(pts as i64));
+                trimmer.check(&frame)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(trims, vec![
+            Trim::Skip, Trim::Skip,
+            Trim::Keep, Trim::Keep, Trim::Keep, Trim::Keep,
+            Trim::Stop,
+            // Once stopped, a change in the scene does not start it again
+            Trim::Stop,
+        ]);
+    }
+
+    #[test]
+    fn changes_outside_the_roi_are_ignored() {
+        ffmpeg::init().unwrap();
+        let mut request = Request::new();
+        request.set_comparison_mode(ComparisonMode::MSE);
+        // Only the top left corner is like the reference, but that is all the ROI covers
+        let window = || vec![
+            luma_frame(32, 32, |x, y| if x < 16 && y < 16 { 100 } else { 250 }),
+            flat_frame(Pixel::YUV420P, 32, 32, 110),
+        ];
+
+        let reference = Some(flat_frame(Pixel::YUV420P, 32, 32, 100));
+        assert_eq!(get_frame_selector(&request, reference).pick_best(window()).unwrap().window_index, 1);
+
+        request.set_roi(Some(Rect { x: 0, y: 0, width: 16, height: 16 }));
+        let reference = Some(flat_frame(Pixel::YUV420P, 32, 32, 100));
+        let picked = get_frame_selector(&request, reference).pick_best(window()).unwrap();
+        assert_eq!(picked.window_index, 0);
+        assert_eq!(picked.score, Some(0.0));
+    }
+
+    #[test]
+    fn ssim_with_a_roi_ignores_changes_to_the_background() {
+        ffmpeg::init().unwrap();
+        let texture = |x: u32, y: u32| ((x * 37 + y * 91) % 200 + 20) as u8;
+        let mut request = Request::new();
+        request.set_comparison_mode(ComparisonMode::SSIM);
+        // The first frame only keeps the texture within the ROI, the second has a little noise all
+        // over it
+        let window = || vec![
+            luma_frame(32, 32, |x, y| if x < 16 && y < 16 { texture(x, y) } else { 128 }),
+            luma_frame(32, 32, |x, y| texture(x, y) + ((x * 13 + y * 7) % 5) as u8 - 2),
+        ];
+
+        let reference = Some(luma_frame(32, 32, texture));
+        assert_eq!(get_frame_selector(&request, reference).pick_best(window()).unwrap().window_index, 1);
+
+        request.set_roi(Some(Rect { x: 0, y: 0, width: 16, height: 16 }));
+        let reference = Some(luma_frame(32, 32, texture));
+        let picked = get_frame_selector(&request, reference).pick_best(window()).unwrap();
+        assert_eq!(picked.window_index, 0);
+        assert!((picked.score.unwrap() - 1.0).abs() < 1e-9, "{:?}", picked.score);
+    }
+
+    #[test]
+    fn larger_hash_sizes_give_larger_distances() {
+        ffmpeg::init().unwrap();
+        let left = luma_frame(64, 64, |x, _| if x < 32 { 50 } else { 200 });
+        let top = luma_frame(64, 64, |_, y| if y < 32 { 50 } else { 200 });
+
+        let distance = |hash_size| hash_frame(&left, ComparisonMode::MeanHash, hash_size)
+            .dist(&hash_frame(&top, ComparisonMode::MeanHash, hash_size));
+        // The frames differ in half of the hash either way, but there are four times as many bits
+        assert_eq!(distance(8), 32);
+        assert_eq!(distance(16), 128);
+    }
+
+    #[test]
+    fn dct_hash_of_a_noisy_copy_is_closer_than_a_different_frame() {
+        ffmpeg::init().unwrap();
+        let base = |x: u32, y: u32| (x * 2 + y + 10) as u8;
+        let frame = luma_frame(64, 64, base);
+        let noisy = luma_frame(64, 64, |x, y| base(x, y) + ((x * 13 + y * 7) % 5) as u8 - 2);
+        let different = luma_frame(64, 64, |x, y| 255 - base(x, y));
+
+        let hash = hash_frame(&frame, ComparisonMode::DctHash, 8);
+        let noisy_distance = hash.dist(&hash_frame(&noisy, ComparisonMode::DctHash, 8));
+        let different_distance = hash.dist(&hash_frame(&different, ComparisonMode::DctHash, 8));
+        assert!(noisy_distance < different_distance, "{} vs {}", noisy_distance, different_distance);
+    }
+
+    #[test]
+    fn luma_hashes_give_stable_distances() {
+        ffmpeg::init().unwrap();
+        let mut request = Request::new();
+        request.set_comparison_mode(ComparisonMode::MeanHash)
+            .set_hash_luma(true);
+        let hash = |frame: &VideoFrame| {
+            let small_frame = downscale_for_hash(frame, &request).unwrap().unwrap();
+            assert_eq!(small_frame.format(), Pixel::GRAY8);
+            hash_frame(&small_frame, request.comparison_mode, request.hash_size)
+        };
+        let frame = luma_frame(128, 96, |x, y| ((x * 2 + y) % 256) as u8);
+        let near = luma_frame(128, 96, |x, y| ((x * 2 + y + 4) % 256) as u8);
+        let far = luma_frame(128, 96, |x, y| 255 - ((x * 2 + y) % 256) as u8);
+
+        assert_eq!(hash(&frame).dist(&hash(&frame)), 0);
+        let near_distance = hash(&frame).dist(&hash(&near));
+        let far_distance = hash(&frame).dist(&hash(&far));
+        assert!(near_distance < far_distance, "{} vs {}", near_distance, far_distance);
+        // Hashing other frames in between doesn't change what a frame hashes to
+        assert_eq!(hash(&frame).dist(&hash(&near)), near_distance);
+        assert_eq!(hash(&far).dist(&hash(&frame)), far_distance);
+    }
+
+    #[test]
+    fn hashing_leaves_out_the_padding_at_the_end_of_each_row() {
+        ffmpeg::init().unwrap();
+        // 50 pixels of RGB is 150 bytes, which ffmpeg pads each row out from
+        let (width, height) = (50, 30);
+        let pixel = |x: u32, y: u32| [(x * 5) as u8, (y * 8) as u8, ((x + y) * 3) as u8];
+        let mut frame = flat_frame(Pixel::RGB24, width, height, 0xab);
+        let stride = frame.stride(0);
+        assert!(stride > width as usize * 3);
+        for y in 0..height {
+            for x in 0..width {
+                let offset = y as usize * stride + x as usize * 3;
+                frame.data_mut(0)[offset..(offset + 3)].copy_from_slice(&pixel(x, y));
+            }
+        }
+
+        let packed = image::RgbImage::from_fn(width, height, |x, y| image::Rgb(pixel(x, y)));
+        let expected = build_hasher(ComparisonMode::MeanHash, 8).hash_image(&packed);
+        assert_eq!(hash_frame(&frame, ComparisonMode::MeanHash, 8), expected);
+        assert_eq!(hash_frame(&frame, ComparisonMode::MeanHash, 8), expected);
+    }
+
+    #[test]
+    fn hasher_is_kept_between_frames() {
+        ffmpeg::init().unwrap();
+        let frame = luma_frame(32, 32, |x, y| ((x * 7 + y * 3) % 256) as u8);
+        let cached = || HASHER.with(|hasher| hasher.borrow().as_ref().map(|(mode, size, _)| (*mode, *size)));
+
+        hash_frame(&frame, ComparisonMode::MeanHash, 8);
+        assert_eq!(cached(), Some((ComparisonMode::MeanHash, 8)));
+
+        // Swap in a 16x16 hasher under the same key: as long as it is reused, the hashes keep
+        // coming out 16x16
+        HASHER.with(|hasher| *hasher.borrow_mut() = Some((ComparisonMode::MeanHash, 8, build_hasher(ComparisonMode::MeanHash, 16))));
+        assert_eq!(hash_frame(&frame, ComparisonMode::MeanHash, 8).as_bytes().len(), 32);
+        assert_eq!(hash_frame(&frame, ComparisonMode::MeanHash, 8).as_bytes().len(), 32);
+
+        // A different hash size gets a hasher of its own
+        assert_eq!(hash_frame(&frame, ComparisonMode::MeanHash, 16).as_bytes().len(), 32);
+        assert_eq!(cached(), Some((ComparisonMode::MeanHash, 16)));
+        assert_eq!(hash_frame(&frame, ComparisonMode::MeanHash, 8).as_bytes().len(), 8);
+    }
+}
@@ -0,0 +1,103 @@
+use std::io;
+use std::os::raw::c_int;
+use std::ptr;
+
+use ffmpeg::ffi::{
+    av_buffer_ref, av_buffer_unref, av_hwdevice_ctx_create, av_hwframe_ctx_alloc, av_hwframe_ctx_init,
+    av_hwframe_get_buffer, av_hwframe_transfer_data, AVBufferRef, AVHWDeviceType, AVHWFramesContext, AVPixelFormat,
+};
+use ffmpeg::encoder::Video as VideoEncoder;
+use ffmpeg::format::Pixel;
+use ffmpeg::util::frame::Video as VideoFrame;
+
+use crate::error::TimelapseError;
+use crate::request::{HwAccel, OutputCodec};
+
+/// Name of the ffmpeg encoder which encodes the codec on the given hardware, if there is one
+pub fn encoder_name(hwaccel: HwAccel, codec: OutputCodec) -> Option<&'static str> {
+    match (hwaccel, codec) {
+        (HwAccel::Vaapi, OutputCodec::H264) => Some("h264_vaapi"),
+        (HwAccel::Vaapi, OutputCodec::Vp9) => Some("vp9_vaapi"),
+        (HwAccel::Vaapi, OutputCodec::Vp8) => Some("vp8_vaapi"),
+        (HwAccel::Nvenc, OutputCodec::H264) => Some("h264_nvenc"),
+        _ => None,
+    }
+}
+
+/// A pool of frames in GPU memory, for hardware encoders which cannot take frames from main memory
+/// (like the VAAPI ones). Frames are converted to `SOFTWARE_FORMAT` and then uploaded into the
+/// pool before being encoded.
+pub struct HwFrames {
+    device: *mut AVBufferRef,
+    frames: *mut AVBufferRef,
+}
+
+impl HwFrames {
+    pub const SOFTWARE_FORMAT: Pixel = Pixel::NV12;
+
+    /// Opens the default VAAPI device and sets up a pool of frames of the given size on it
+    pub fn vaapi(width: u32, height: u32) -> Result<Self, TimelapseError> {
+        unsafe {
+            let mut device = ptr::null_mut();
+            match av_hwdevice_ctx_create(&mut device, AVHWDeviceType::AV_HWDEVICE_TYPE_VAAPI, ptr::null(), ptr::null_mut(), 0) {
+                0 => {},
+                e => return Err(TimelapseError::InvalidRequest(format!("cannot open a VAAPI device: {}", ffmpeg::Error::from(e)))),
+            }
+
+            let frames = av_hwframe_ctx_alloc(device);
+            if frames.is_null() {
+                av_buffer_unref(&mut device);
+                return Err(io::Error::new(io::ErrorKind::Other, "out of memory setting up the VAAPI frame pool").into());
+            }
+            let hw_frames = HwFrames { device, frames };
+
+            let context = (*frames).data as *mut AVHWFramesContext;
+            (*context).format = AVPixelFormat::AV_PIX_FMT_VAAPI;
+            (*context).sw_format = Self::SOFTWARE_FORMAT.into();
+            (*context).width = width as c_int;
+            (*context).height = height as c_int;
+            (*context).initial_pool_size = 20;
+            match av_hwframe_ctx_init(frames) {
+                0 => Ok(hw_frames),
+                e => Err(ffmpeg::Error::from(e).into()),
+            }
+        }
+    }
+
+    /// Pixel format the encoder has to be set to in order to take frames from this pool
+    pub fn hardware_format(&self) -> Pixel {
+        Pixel::VAAPI
+    }
+
+    /// Makes the encoder take its frames from this pool. Must be done before the encoder is opened.
+    pub fn attach(&self, encoder: &mut VideoEncoder) {
+        unsafe {
+            (*encoder.as_mut_ptr()).hw_frames_ctx = av_buffer_ref(self.frames);
+        }
+    }
+
+    /// Copies a frame in `SOFTWARE_FORMAT` into a frame from the pool
+    pub fn upload(&self, frame: &VideoFrame) -> Result<VideoFrame, TimelapseError> {
+        let mut hw_frame = VideoFrame::empty();
+        unsafe {
+            match av_hwframe_get_buffer(self.frames, hw_frame.as_mut_ptr(), 0) {
+                0 => {},
+                e => return Err(ffmpeg::Error::from(e).into()),
+            }
+            match av_hwframe_transfer_data(hw_frame.as_mut_ptr(), frame.as_ptr(), 0) {
+                0 => {},
+                e => return Err(ffmpeg::Error::from(e).into()),
+            }
+        }
+        Ok(hw_frame)
+    }
+}
+
+impl Drop for HwFrames {
+    fn drop(&mut self) {
+        unsafe {
+            av_buffer_unref(&mut self.frames);
+            av_buffer_unref(&mut self.device);
+        }
+    }
+}
@@ -0,0 +1,444 @@
+extern crate ffmpeg_next as ffmpeg;
+
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::time::{Duration, Instant};
+
+use ffmpeg::ffi::{av_log_set_callback, av_log_set_level, AV_LOG_ERROR, AV_LOG_INFO, AV_LOG_DEBUG};
+use ffmpeg::util::frame::Video as VideoFrame;
+
+use rayon::prelude::*;
+
+#[macro_use]
+pub mod log;
+pub mod request;
+pub mod config;
+pub mod contact_sheet;
+pub mod error;
+pub mod decoder;
+pub mod encoder;
+pub mod frame_cache;
+pub mod frame_selection;
+pub mod hwaccel;
+pub mod post_processing;
+pub mod palette;
+pub mod manifest;
+pub mod overlay;
+pub mod preview;
+pub mod webvtt;
+pub use crate::request::Request;
+pub use crate::error::TimelapseError;
+use crate::frame_selection::{ChangeGate, FrameSelector, Selection};
+use crate::request::ComparisonMode;
+use crate::encoder::{Encoder, Pass};
+use crate::decoder::{Decoder, VideoInfo};
+use crate::frame_cache::{FrameCacheReader, FrameCacheWriter};
+use crate::preview::Preview;
+use crate::contact_sheet::ContactSheet;
+
+/// Summary of a finished timelapse run
+#[derive(Debug, Clone)]
+pub struct ProcessStats {
+    /// Number of frames decoded from the input and considered for selection
+    pub frames_read: u64,
+    /// Number of frames written to the output
+    pub frames_written: u64,
+    /// Number of input frames left out by `--skip-errors` because they failed to decode
+    pub frames_skipped: u64,
+    /// Wall-clock time taken by the whole run
+    pub elapsed: Duration,
+    /// Which input frame was picked for each output frame
+    pub selections: Vec<SelectionRecord>,
+    /// Wall-clock time spent in each stage of the run
+    pub timings: StageTimings,
+}
+
+/// Wall-clock time spent decoding, picking and encoding frames over a whole run
+#[derive(Debug, Copy, Clone, Default)]
+pub struct StageTimings {
+    /// Time spent decoding the input, or reading the picked frames back from `--cache-dir`
+    pub decode: Duration,
+    /// Time spent comparing frames and picking the best one of every window
+    pub selection: Duration,
+    /// Time spent encoding and writing the output, including the second pass of `--two-pass`
+    pub encode: Duration,
+}
+
+/// Describes the input frame picked for one output frame
+#[derive(Debug, Copy, Clone)]
+pub struct SelectionRecord {
+    /// Index of the frame in the output
+    pub output_index: u64,
+    /// Index of the frame among the input frames considered for selection, ie. not counting any
+    /// frames left out by `--frame-skip` or `--key-frames-only`
+    pub input_index: u64,
+    /// Timestamp of the frame in the input, in the input's time base
+    pub source_pts: Option<i64>,
+    /// Timestamp of the frame in the input, in seconds
+    pub source_time: Option<f64>,
+    /// Value of the comparison metric for the frame, if the comparison mode has one
+    pub score: Option<f64>,
+}
+
+/// Snapshot of how far along a run is, passed to the progress callback of [`run`]
+#[derive(Debug, Copy, Clone)]
+pub struct ProgressInfo {
+    /// Number of frames written to the output so far
+    pub frames_written: u64,
+    /// Number of frames the output is expected to have, if the input frame count is known
+    pub estimated_total: Option<u64>,
+    /// Number of frames in the input, if known
+    pub input_frames: Option<u64>,
+    /// Wall-clock time since the run started
+    pub elapsed: Duration,
+}
+
+/// Makes a timelapse as described by the request: decodes the input, picks one frame out of every
+/// window and encodes the picked frames into the output file. If given, `progress` is called
+/// before each output frame is written. With `--dry-run`, frames are picked but nothing is written.
+/// With `--two-pass`, the picked frames are cached in a temporary file while the first pass is
+/// encoded and read back from it for the second pass. With `--cache-dir`, the picked frames are
+/// kept there and later runs which would pick the same frames read them back instead of decoding
+/// the input. Frames are compared on the Rayon thread pool `run` is called from, so `--threads`
+/// is left for the caller to apply.
+///
+/// The request is expected to have been checked with [`Request::validate`] already, and logging
+/// set up with [`log::init`] if messages are to go anywhere but standard output, as `main` does
+/// before anything else.
+pub fn run(request: &Request, mut progress: Option<&mut dyn FnMut(ProgressInfo)>) -> Result<ProcessStats, TimelapseError> {
+    let start = Instant::now();
+    init_ffmpeg(request)?;
+
+    let mut inputs = decoder::open_inputs(request)?;
+    let mut decoder = Decoder::new(request, &mut inputs)?;
+
+    let vid_info = decoder.get_info();
+    let two_pass = request.two_pass && !request.dry_run;
+    let stats_path = std::env::temp_dir().join(format!("timelapse-rs-{}-2pass.log", process::id()));
+    let selection_cache_path = match request.cache_dir.as_ref() {
+        // There is no telling whether standard input or a network stream will be the same next time
+        Some(cache_dir) if !request.dry_run && !request.reads_stdin() && !request.reads_url() => {
+            fs::create_dir_all(cache_dir)?;
+            Some(cache_dir.join(frame_cache::cache_file_name(request)))
+        },
+        _ => None,
+    };
+    let frame_cache_path = selection_cache_path.clone()
+        .unwrap_or_else(|| std::env::temp_dir().join(format!("timelapse-rs-{}.frames", process::id())));
+    // The cache only holds the picked frames, so anything that needs more than that from the
+    // input has to go through it again
+    let cached_frames = match selection_cache_path.as_ref() {
+        Some(path) if path.exists() && request.manifest.is_none() && !request.keep_audio => FrameCacheReader::open(path)?,
+        _ => None,
+    };
+    let partial_cache_path = frame_cache_path.with_extension("partial");
+    let mut frame_cache = if cached_frames.is_none() && (two_pass || selection_cache_path.is_some()) {
+        Some(FrameCacheWriter::create(&partial_cache_path)?)
+    } else {
+        None
+    };
+
+    let first_pass = if two_pass { Some(Pass::First(&stats_path)) } else { None };
+    let mut encoder = if request.dry_run { None } else { Some(Encoder::new(request, &vid_info, decoder.audio_stream(), decoder.creation_time(), first_pass)?) };
+    let mut preview = if request.preview { Some(Preview::new()?) } else { None };
+
+    // In motion mode frames are looked at one by one and only emitted when the scene changes, so
+    // there is no telling how many there will be
+    let motion = request.comparison_mode == ComparisonMode::Motion;
+    let (input_frames, estimated_total) = match vid_info.total_frames {
+        Some(total_frames) if !motion => {
+            let window_size = decoder.window_size();
+            let frames_per_window = u64::from(request.frames_per_window.min(window_size));
+            // Each window after the first only brings in the frames it doesn't share with the last
+            let window_step = window_size - vid_info.window_overlap;
+            let sampled_frames = sampled_frame_count(total_frames, request.sample_every);
+            (Some(total_frames), output_frame_count(sampled_frames, window_step).map(|windows| windows * frames_per_window))
+        },
+        total_frames => (total_frames, None),
+    };
+    let max_output_frames = request.max_output_frames.unwrap_or(u64::max_value());
+    let estimated_total = estimated_total.map(|total| total.min(max_output_frames));
+    let mut contact_sheet = request.contact_sheet.as_ref().map(|_| ContactSheet::new(request, estimated_total));
+
+    let reference = match request.reference.as_ref() {
+        Some(path) => Some(decoder.load_reference(path)?),
+        None => None,
+    };
+    let mut selector = frame_selection::get_frame_selector(request, reference.clone());
+    let mut change_gate = if motion { Some(ChangeGate::new(request.motion_threshold)) } else { request.min_change.map(ChangeGate::new) };
+
+    let mut frames_read = 0u64;
+    let mut frames_written = 0u64;
+    let mut selections = Vec::<SelectionRecord>::new();
+    let mut timings = StageTimings::default();
+    if let Some(mut cached_frames) = cached_frames {
+        if request.verbose > 0 {
+            log_message!("Reading the picked frames from the cache at {}", frame_cache_path.display());
+        }
+        loop {
+            let decode_start = Instant::now();
+            let frame = match cached_frames.next_frame()? {
+                Some(frame) => frame,
+                None => break,
+            };
+            timings.decode += decode_start.elapsed();
+
+            if let Some(progress) = progress.as_mut() {
+                progress(ProgressInfo {
+                    frames_written,
+                    estimated_total,
+                    input_frames,
+                    elapsed: start.elapsed(),
+                });
+            }
+            if let Some(preview) = preview.as_mut() {
+                preview.show(&frame)?;
+            }
+            if let Some(contact_sheet) = contact_sheet.as_mut() {
+                contact_sheet.add(&frame)?;
+            }
+            let encode_start = Instant::now();
+            if let Some(encoder) = encoder.as_mut() {
+                encoder.encode_frame(&frame)?;
+            }
+            timings.encode += encode_start.elapsed();
+            frames_written += 1;
+        }
+    } else {
+        // Picking from a window doesn't always depend on what was picked from the one before, in
+        // which case a window is decoded for every thread and they are all picked from at once
+        let batch_size = if !motion && vid_info.window_overlap == 0 && frame_selection::is_stateless(request) {
+            rayon::current_num_threads().max(1)
+        } else {
+            1
+        };
+        // Each thread keeps a selector of its own for the whole run
+        let mut batch_selectors = if batch_size > 1 {
+            (0..batch_size).map(|_| frame_selection::get_frame_selector(request, reference.clone())).collect()
+        } else {
+            Vec::new()
+        };
+        let count = request.frames_per_window as usize;
+        let mut eof = false;
+        while !eof && frames_written < max_output_frames {
+            let mut windows = Vec::with_capacity(batch_size);
+            while windows.len() < batch_size {
+                let decode_start = Instant::now();
+                let next = if motion { decoder.next_frame().map(|frame| vec![frame]) } else { decoder.next_window() };
+                timings.decode += decode_start.elapsed();
+                let audio_packets = decoder.take_audio_packets();
+                let encode_start = Instant::now();
+                if let Some(encoder) = encoder.as_mut() {
+                    for packet in audio_packets {
+                        encoder.write_audio_packet(packet)?;
+                    }
+                }
+                timings.encode += encode_start.elapsed();
+                match next {
+                    Ok(window) => {
+                        // Frames carried over from the last window have been counted already
+                        let carried = decoder.carried_frames() as u64;
+                        let window_len = window.len() as u64;
+                        windows.push((frames_read - carried, window));
+                        frames_read += window_len - carried;
+                    },
+                    Err(TimelapseError::Ffmpeg(ffmpeg::Error::Eof)) => {
+                        eof = true;
+                        break;
+                    },
+                    Err(e) => return Err(e),
+                }
+            }
+            if windows.is_empty() {
+                break;
+            }
+
+            let selection_start = Instant::now();
+            let picked_windows = if batch_size == 1 {
+                let (window_start, window) = windows.pop().unwrap();
+                let picked = pick_in_order(selector.as_mut(), window, count)?;
+                if let Some(last_picked) = picked.last() {
+                    decoder.drop_overlap_through(last_picked.window_index);
+                }
+                vec![(window_start, picked)]
+            } else {
+                windows.into_par_iter().zip(batch_selectors.par_iter_mut()).map(|((window_start, window), selector)| {
+                    Ok((window_start, pick_in_order(selector.as_mut(), window, count)?))
+                }).collect::<Result<Vec<_>, TimelapseError>>()?
+            };
+            timings.selection += selection_start.elapsed();
+
+            for (window_start, mut picked) in picked_windows {
+                if let Some(change_gate) = change_gate.as_mut() {
+                    let gate_start = Instant::now();
+                    let mut changed = Vec::with_capacity(picked.len());
+                    for mut selection in picked {
+                        if change_gate.check(&selection.frame) {
+                            selection.score = selection.score.or_else(|| change_gate.last_difference());
+                            changed.push(selection);
+                        }
+                    }
+                    picked = changed;
+                    timings.selection += gate_start.elapsed();
+                }
+
+                for selection in picked {
+                    if frames_written >= max_output_frames {
+                        break;
+                    }
+                    if let Some(progress) = progress.as_mut() {
+                        progress(ProgressInfo {
+                            frames_written,
+                            estimated_total,
+                            input_frames,
+                            elapsed: start.elapsed(),
+                        });
+                    }
+                    selections.push(SelectionRecord {
+                        output_index: frames_written,
+                        input_index: window_start + selection.window_index as u64,
+                        source_pts: selection.frame.pts(),
+                        source_time: selection.frame.pts().map(|pts| pts as f64 * f64::from(vid_info.timebase)),
+                        score: selection.score,
+                    });
+                    if let Some(preview) = preview.as_mut() {
+                        preview.show(&selection.frame)?;
+                    }
+                    if let Some(contact_sheet) = contact_sheet.as_mut() {
+                        contact_sheet.add(&selection.frame)?;
+                    }
+                    let encode_start = Instant::now();
+                    if let Some(encoder) = encoder.as_mut() {
+                        encoder.encode_frame(&selection.frame)?;
+                    }
+                    if let Some(frame_cache) = frame_cache.as_mut() {
+                        frame_cache.write_frame(&selection.frame)?;
+                    }
+                    timings.encode += encode_start.elapsed();
+                    frames_written += 1;
+                }
+            }
+        }
+        if !eof && request.verbose > 0 {
+            log_message!("Stopped after {} frames as asked for by --max-output-frames", frames_written);
+        }
+    }
+
+    let encode_start = Instant::now();
+    if let Some(encoder) = encoder.as_mut() {
+        encoder.finish()?;
+    }
+
+    if let Some(frame_cache) = frame_cache {
+        frame_cache.finish()?;
+        fs::rename(&partial_cache_path, &frame_cache_path)?;
+    }
+
+    if two_pass {
+        let second_pass = encode_second_pass(request, &vid_info, decoder.creation_time(), &stats_path, &frame_cache_path);
+        if selection_cache_path.is_none() {
+            let _ = fs::remove_file(&frame_cache_path);
+        }
+        let _ = fs::remove_file(&stats_path);
+        second_pass?;
+    }
+    timings.encode += encode_start.elapsed();
+
+    if let Some(manifest_path) = request.manifest.as_ref() {
+        manifest::write_manifest(manifest_path, &selections)?;
+    }
+    if let (Some(contact_sheet), Some(path)) = (contact_sheet, request.contact_sheet.as_ref()) {
+        contact_sheet.save(path)?;
+    }
+
+    Ok(ProcessStats {
+        frames_read,
+        frames_written,
+        frames_skipped: decoder.skipped_frames(),
+        elapsed: start.elapsed(),
+        selections,
+        timings,
+    })
+}
+
+/// Picks frames out of the window and puts them in the order they were shot, so that motion runs
+/// forwards
+fn pick_in_order(selector: &mut dyn FrameSelector, window: Vec<VideoFrame>, count: usize) -> Result<Vec<Selection>, TimelapseError> {
+    let mut picked = selector.pick_best_n(window, count)?;
+    picked.sort_by_key(|selection| selection.window_index);
+    Ok(picked)
+}
+
+/// Encodes the frames cached during the first pass again, using the statistics it gathered
+fn encode_second_pass<R>(request: &Request, vid_info: &VideoInfo<R>, creation_time: Option<&str>, stats_path: &Path, frame_cache_path: &Path) -> Result<(), TimelapseError>
+where R: Into<ffmpeg::Rational> + Copy + Clone {
+    let mut encoder = Encoder::new(request, vid_info, None, creation_time, Some(Pass::Second(stats_path)))?;
+    if let Some(mut frame_cache) = FrameCacheReader::open(frame_cache_path)? {
+        while let Some(frame) = frame_cache.next_frame()? {
+            encoder.encode_frame(&frame)?;
+        }
+    }
+    encoder.finish()
+}
+
+/// Number of frames left of the given number of input frames when only every `sample_every`th one
+/// is considered
+pub fn sampled_frame_count(total_frames: u64, sample_every: u32) -> u64 {
+    let sample_every = u64::from(sample_every.max(1));
+    total_frames / sample_every + if total_frames % sample_every > 0 { 1 } else { 0 }
+}
+
+/// Number of output frames made from the given number of input frames, counting a partial window
+/// at the end as a whole one. `None` if the window size is 0.
+pub fn output_frame_count(total_frames: u64, window_size: u32) -> Option<u64> {
+    let window_size = u64::from(window_size);
+    if window_size == 0 {
+        None
+    } else {
+        Some(total_frames / window_size + if total_frames % window_size > 0 { 1 } else { 0 })
+    }
+}
+
+fn init_ffmpeg(request: &Request) -> Result<(), ffmpeg::Error> {
+    let log_level = match request.verbose {
+        0 => AV_LOG_ERROR,
+        1 => AV_LOG_INFO,
+        _ => AV_LOG_DEBUG,
+    };
+    unsafe { av_log_set_level(log_level) };
+    // ffmpeg prints straight to standard error unless there is a log file or a logger has been set
+    // up for the `log` crate to send its messages to
+    if request.log_file.is_some() || ::log::max_level() != ::log::LevelFilter::Off {
+        unsafe { av_log_set_callback(Some(log::ffmpeg_log_callback)) };
+    }
+
+    ffmpeg::init()?;
+    if request.reads_url() {
+        ffmpeg::format::network::init();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_frame_count_counts_a_partial_window_as_a_whole_one() {
+        assert_eq!(output_frame_count(100, 10), Some(10));
+        assert_eq!(output_frame_count(101, 10), Some(11));
+        assert_eq!(output_frame_count(9, 10), Some(1));
+        assert_eq!(output_frame_count(0, 10), Some(0));
+        assert_eq!(output_frame_count(100, 0), None);
+    }
+
+    #[test]
+    fn sampled_frame_count_keeps_the_first_frame_of_each_step() {
+        assert_eq!(sampled_frame_count(100, 1), 100);
+        assert_eq!(sampled_frame_count(100, 3), 34);
+        assert_eq!(sampled_frame_count(99, 3), 33);
+        // 0 reads every frame, like 1
+        assert_eq!(sampled_frame_count(100, 0), 100);
+    }
+}
@@ -0,0 +1,210 @@
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::raw::{c_char, c_int, c_void};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use ffmpeg::ffi::{av_log_format_line2, av_log_get_level, AV_LOG_ERROR, AV_LOG_INFO, AV_LOG_VERBOSE, AV_LOG_WARNING};
+
+use crate::SelectionRecord;
+use crate::manifest;
+use crate::request::LogFormat;
+
+/// Whether messages are printed as JSON objects rather than as text
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Whether messages go to standard error rather than standard output, which is needed when the
+/// output is written to standard output
+static STDERR: AtomicBool = AtomicBool::new(false);
+
+/// File given with `--log-file`, which messages go to instead of standard output
+static LOG_FILE: Mutex<Option<File>> = Mutex::new(None);
+
+/// The `va_list` ffmpeg hands to log callbacks, which bindgen turns into a pointer where the C type
+/// is an array
+#[cfg(all(target_arch = "x86_64", not(windows)))]
+type VaList = *mut ffmpeg::ffi::__va_list_tag;
+#[cfg(not(all(target_arch = "x86_64", not(windows))))]
+type VaList = ffmpeg::ffi::va_list;
+
+/// Prints a message like `println!` does, or as a JSON object with `--log-format json`
+#[macro_export]
+macro_rules! log_message {
+    ($($arg:tt)*) => {
+        $crate::log::message(&format!($($arg)*))
+    };
+}
+
+/// Sets how and where everything logged from now on is printed. A log file is appended to, so
+/// that it can be kept across runs. Without one, messages go to standard error if `stderr` is set,
+/// eg. because the output is being written to standard output, or to standard output otherwise.
+pub fn init(format: LogFormat, log_file: Option<&Path>, stderr: bool) -> io::Result<()> {
+    JSON.store(format == LogFormat::Json, Ordering::Relaxed);
+    STDERR.store(stderr, Ordering::Relaxed);
+    let file = match log_file {
+        Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+        None => None,
+    };
+    *LOG_FILE.lock().unwrap_or_else(|e| e.into_inner()) = file;
+    Ok(())
+}
+
+/// Whether messages go to a `--log-file`
+fn has_log_file() -> bool {
+    LOG_FILE.lock().unwrap_or_else(|e| e.into_inner()).is_some()
+}
+
+fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Prints a line to the log file if there is one, or to standard output or standard error
+fn write_line(line: &str) {
+    match LOG_FILE.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        // There is nowhere left to report a failure to write the log to
+        Some(file) => {
+            let _ = writeln!(file, "{}", line);
+        },
+        None if STDERR.load(Ordering::Relaxed) => eprintln!("{}", line),
+        None => println!("{}", line),
+    }
+}
+
+/// Prints a message, such as the ones asked for with `-v`
+pub fn message(text: &str) {
+    if is_json() {
+        write_line(&format!("{{\"event\":\"message\",\"message\":{}}}", json_string(text)));
+    } else {
+        write_line(text);
+    }
+}
+
+/// Prints how far along the run is
+pub fn progress(frames_written: u64, estimated_total: Option<u64>, input_frames: Option<u64>) {
+    if is_json() {
+        write_line(&format!("{{\"event\":\"progress\",\"written\":{},\"total\":{},\"input_frames\":{}}}",
+            frames_written, optional(estimated_total), optional(input_frames)));
+        return;
+    }
+
+    if frames_written == 0 {
+        match (input_frames, estimated_total) {
+            (Some(input_frames), Some(total)) => write_line(&format!("Will process {} input frames into {} output frames", input_frames, total)),
+            _ => write_line("Note: Cannot determine number of frames in the input, progress information will not be provided"),
+        }
+    }
+    match estimated_total {
+        Some(total) => {
+            let percentage = if total > 0 {
+                (frames_written as f64 / total as f64 * 100.0).min(100.0)
+            } else {
+                100.0
+            };
+            write_line(&format!("{}/{} written ({:.1}% done)", frames_written, total, percentage));
+        },
+        None => write_line(&format!("{}/? written (unknown progress)", frames_written)),
+    }
+}
+
+/// Prints which input frame was picked for an output frame. `json` asks for the record on its
+/// own as JSON, the way `--dry-run --json` has always printed it.
+pub fn selection(selection: &SelectionRecord, json: bool) {
+    if is_json() {
+        // The record is a JSON object of its own, which the event name goes in front of
+        write_line(&format!("{{\"event\":\"selection\",{}", &manifest::to_json(selection)[1..]));
+    } else if json {
+        write_line(&manifest::to_json(selection));
+    } else {
+        let score = selection.score.map_or_else(|| String::from("n/a"), |score| score.to_string());
+        write_line(&format!("output frame {}: input frame {} (score {})", selection.output_index, selection.input_index, score));
+    }
+}
+
+/// Prints that the output has been written
+pub fn done(output_path: &Path, frames_written: u64) {
+    if is_json() {
+        write_line(&format!("{{\"event\":\"done\",\"output\":{},\"written\":{}}}", json_string(&output_path.to_string_lossy()), frames_written));
+    } else {
+        write_line(&format!("All done - check {}!", output_path.display()));
+    }
+}
+
+/// Log callback for ffmpeg which sends its messages to the log file like any other message if there
+/// is one, or otherwise to the `log` crate under the `ffmpeg` target, so that programs using this
+/// library get them through the logger they have set up
+pub unsafe extern "C" fn ffmpeg_log_callback(avcl: *mut c_void, level: c_int, fmt: *const c_char, vl: VaList) {
+    if level > av_log_get_level() {
+        return;
+    }
+
+    let mut line = [0 as c_char; 1024];
+    let mut print_prefix = 1;
+    av_log_format_line2(avcl, level, fmt, vl, line.as_mut_ptr(), line.len() as c_int, &mut print_prefix);
+    let text = CStr::from_ptr(line.as_ptr()).to_string_lossy();
+    let text = text.trim_end();
+    if text.is_empty() {
+        return;
+    }
+
+    if has_log_file() {
+        message(text);
+    } else {
+        ::log::log!(target: "ffmpeg", log_level(level), "{}", text);
+    }
+}
+
+/// The `log` crate's level for an ffmpeg log level
+fn log_level(level: c_int) -> ::log::Level {
+    match level {
+        level if level <= AV_LOG_ERROR as c_int => ::log::Level::Error,
+        level if level <= AV_LOG_WARNING as c_int => ::log::Level::Warn,
+        level if level <= AV_LOG_INFO as c_int => ::log::Level::Info,
+        level if level <= AV_LOG_VERBOSE as c_int => ::log::Level::Debug,
+        _ => ::log::Level::Trace,
+    }
+}
+
+fn optional(value: Option<u64>) -> String {
+    value.map_or_else(|| String::from("null"), |value| value.to_string())
+}
+
+/// Quotes the text as a JSON string
+fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_string_escapes_what_json_requires() {
+        assert_eq!(json_string("plain"), r#""plain""#);
+        assert_eq!(json_string(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(json_string(r"C:\videos"), r#""C:\\videos""#);
+        assert_eq!(json_string("one\ntwo\r\tthree"), r#""one\ntwo\r\tthree""#);
+        assert_eq!(json_string("bell\u{7}"), r#""bell\u0007""#);
+    }
+
+    #[test]
+    fn json_string_leaves_other_characters_alone() {
+        assert_eq!(json_string(""), r#""""#);
+        assert_eq!(json_string("zoë's café 🎞"), "\"zoë's café 🎞\"");
+    }
+}
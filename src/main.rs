@@ -1,73 +1,114 @@
-extern crate ffmpeg_next as ffmpeg;
+use std::process;
 
-use ffmpeg::format::input;
-use ffmpeg::ffi::{av_log_set_level, AV_LOG_ERROR, AV_LOG_INFO, AV_LOG_DEBUG};
-use structopt::StructOpt;
-
-mod request;
-mod decoder;
-mod encoder;
-mod frame_selection;
-use crate::request::Request;
-use crate::encoder::Encoder;
-use crate::decoder::Decoder;
+use timelapse_rs::{config, log, ProcessStats, ProgressInfo, SelectionRecord};
+use timelapse_rs::request::is_stdio;
 
 fn main() {
-    let request = Request::from_args();
-    init_ffmpeg(&request);
+    let request = match config::request_from_args() {
+        Ok(request) => request,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(2);
+        },
+    };
+    if let Err(e) = request.validate() {
+        eprintln!("error: {}", e);
+        process::exit(2);
+    }
+
+    if request.threads > 0 {
+        if let Err(e) = rayon::ThreadPoolBuilder::new().num_threads(request.threads).build_global() {
+            eprintln!("error: cannot start {} threads: {}", request.threads, e);
+            process::exit(1);
+        }
+    }
 
-    // let mut ictx = input(&request.input_path()).unwrap();
-    // if request.verbose > 0 { dump_format(&ictx, 0, request.input_path().to_str()); }
+    // Nothing but the output may go to standard output when the output is written there
+    let stdout_output = is_stdio(request.output_path());
+    if let Err(e) = log::init(request.log_format, request.log_file.as_deref(), stdout_output && !request.dry_run) {
+        eprintln!("error: cannot open the log file: {}", e);
+        process::exit(1);
+    }
 
-    let mut ictx = input(&request.input_path()).unwrap();
-    let mut decoder = Decoder::new(&request, &mut ictx).unwrap();
+    let mut report_progress = |progress: ProgressInfo| {
+        if progress.frames_written % 5 == 0 {
+            log::progress(progress.frames_written, progress.estimated_total, progress.input_frames);
+        }
+    };
 
-    let vid_info = decoder.get_info();
-    let mut encoder = Encoder::new(&request, &vid_info).unwrap();
+    // Keep stdout clean for the JSON report and for output written to it
+    let progress: Option<&mut dyn FnMut(ProgressInfo)> = if request.quiet || request.json || stdout_output { None } else { Some(&mut report_progress) };
 
-    let num_output_frames = vid_info.total_frames / request.window_size as i64;
-    if vid_info.total_frames > 0 {
-        println!("Will process {} input frames into {} output frames", vid_info.total_frames, num_output_frames);
-    } else {
-        println!("Note: Cannot determine number of frames in the input, progress information will not be provided");
+    match timelapse_rs::run(&request, progress) {
+        Ok(stats) => {
+            if request.dry_run {
+                print_selections(&stats.selections, request.json);
+            } else if !request.quiet && !stdout_output {
+                if stats.frames_skipped > 0 {
+                    log::message(&format!("Left out {} frames which could not be decoded", stats.frames_skipped));
+                }
+                log::done(request.output_path(), stats.frames_written);
+            }
+            // Goes to stderr so that it stays out of anything piped from stdout
+            if request.stats {
+                print_timings(&stats);
+            }
+            if request.score_histogram {
+                print_score_histogram(&stats.selections);
+            }
+        },
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        },
     }
+}
 
-    let mut selector = frame_selection::get_frame_selector(&request);
+fn print_timings(stats: &ProcessStats) {
+    let stages = [
+        // Nothing is read from the input when the picked frames come from the cache
+        ("decoding", stats.timings.decode, stats.frames_read.max(stats.frames_written)),
+        ("picking", stats.timings.selection, stats.frames_read),
+        ("encoding", stats.timings.encode, stats.frames_written),
+    ];
+    eprintln!("total: {:.3}s", stats.elapsed.as_secs_f64());
+    for (stage, time, frames) in stages.iter() {
+        let per_frame = if *frames > 0 { time.as_secs_f64() * 1000.0 / *frames as f64 } else { 0.0 };
+        eprintln!("{}: {:.3}s ({:.3}ms per frame)", stage, time.as_secs_f64(), per_frame);
+    }
+}
 
-    let mut i = 0u32;
-    loop {
-        match decoder.next_window() {
-            Ok(window) => {
-                if i % 5 == 0 {
-                    if vid_info.total_frames > 0 {
-                        let percentage = (i as f64 / num_output_frames as f64) * 100.0;
-                        println!("{}/{} written ({:.1}% done)", i, num_output_frames, percentage);
-                    } else {
-                        println!("{}/? written (unknown progress)", i);
-                    }
-                }
+/// Number of bins in `--score-histogram`, and the width of the longest bar
+const HISTOGRAM_BINS: usize = 10;
+const HISTOGRAM_WIDTH: usize = 40;
 
-                let frame = selector.pick_best(window).unwrap();
-                encoder.encode_frame(&frame).unwrap();
-                i += 1;
-            },
-            Err(ffmpeg::Error::Eof) => break,
-            Err(e) => panic!("main: error processing frame at {}: {:#?}", i, e),
-        }
+fn print_score_histogram(selections: &[SelectionRecord]) {
+    let scores = selections.iter().filter_map(|selection| selection.score).filter(|score| score.is_finite()).collect::<Vec<_>>();
+    if scores.is_empty() {
+        eprintln!("score histogram: no scores (the comparison mode doesn't score frames)");
+        return;
     }
 
-    encoder.finish().unwrap();
+    let min = scores.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = scores.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let bin_width = (max - min) / HISTOGRAM_BINS as f64;
+    let mut counts = [0usize; HISTOGRAM_BINS];
+    for score in &scores {
+        let bin = if bin_width > 0.0 { ((score - min) / bin_width) as usize } else { 0 };
+        counts[bin.min(HISTOGRAM_BINS - 1)] += 1;
+    }
 
-    println!("All done - check {}!", request.output_path().display());
+    let most = counts.iter().cloned().max().unwrap_or(0).max(1);
+    eprintln!("score histogram of {} picked frames:", scores.len());
+    for (bin, count) in counts.iter().enumerate() {
+        let low = min + bin_width * bin as f64;
+        let bar = "#".repeat((count * HISTOGRAM_WIDTH + most - 1) / most);
+        eprintln!("{:>12.4} - {:<12.4} {:>6} {}", low, low + bin_width, count, bar);
+    }
 }
 
-fn init_ffmpeg(request: &Request) {
-    let log_level = match request.verbose {
-        0 => AV_LOG_ERROR,
-        1 => AV_LOG_INFO,
-        _ => AV_LOG_DEBUG,
-    };
-    unsafe { av_log_set_level(log_level) };
-
-    ffmpeg::init().unwrap();
+fn print_selections(selections: &[SelectionRecord], json: bool) {
+    for selection in selections {
+        log::selection(selection, json);
+    }
 }
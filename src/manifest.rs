@@ -0,0 +1,54 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::SelectionRecord;
+use crate::error::TimelapseError;
+use crate::request::ManifestFormat;
+
+/// Writes one row (CSV) or object (JSON) per output frame describing which input frame it came
+/// from. The format is picked from the extension of the path.
+pub fn write_manifest(path: &Path, selections: &[SelectionRecord]) -> Result<(), TimelapseError> {
+    let format = ManifestFormat::from_path(path)
+        .ok_or_else(|| TimelapseError::InvalidRequest(format!("cannot tell the format of manifest {}", path.display())))?;
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    match format {
+        ManifestFormat::Csv => {
+            writeln!(writer, "output_frame,input_frame,source_pts,source_time,score")?;
+            for selection in selections {
+                writeln!(writer, "{},{},{},{},{}",
+                    selection.output_index,
+                    selection.input_index,
+                    optional(selection.source_pts, ""),
+                    optional(selection.source_time, ""),
+                    optional(selection.score, ""))?;
+            }
+        },
+        ManifestFormat::Json => {
+            writeln!(writer, "[")?;
+            for (i, selection) in selections.iter().enumerate() {
+                let separator = if i + 1 < selections.len() { "," } else { "" };
+                writeln!(writer, "  {}{}", to_json(selection), separator)?;
+            }
+            writeln!(writer, "]")?;
+        },
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+/// Formats the record as a single-line JSON object
+pub fn to_json(selection: &SelectionRecord) -> String {
+    format!("{{\"output_frame\":{},\"input_frame\":{},\"source_pts\":{},\"source_time\":{},\"score\":{}}}",
+        selection.output_index,
+        selection.input_index,
+        optional(selection.source_pts, "null"),
+        optional(selection.source_time.filter(|t| t.is_finite()), "null"),
+        optional(selection.score.filter(|s| s.is_finite()), "null"))
+}
+
+fn optional<T: ToString>(value: Option<T>, missing: &str) -> String {
+    value.map_or_else(|| String::from(missing), |v| v.to_string())
+}
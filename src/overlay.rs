@@ -0,0 +1,190 @@
+use ffmpeg::format::Pixel;
+use ffmpeg::util::frame::Video as VideoFrame;
+use ffmpeg::Rational;
+
+use crate::post_processing::plane_row_len;
+use crate::request::Corner;
+
+const GLYPH_WIDTH: usize = 5;
+const GLYPH_HEIGHT: usize = 7;
+
+/// Draws how far into the input each frame was taken onto the frame, counting from the first frame
+/// it is given
+pub struct TimestampOverlay {
+    format: String,
+    corner: Corner,
+    time_base: Rational,
+    first_pts: Option<i64>,
+}
+
+impl TimestampOverlay {
+    pub fn new(format: &str, corner: Corner, time_base: Rational) -> Self {
+        Self {
+            format: String::from(format),
+            corner,
+            time_base,
+            first_pts: None,
+        }
+    }
+
+    /// Returns a copy of the frame with the timestamp drawn on it. Frames without a timestamp are
+    /// copied as they are.
+    pub fn apply(&mut self, frame: &VideoFrame) -> VideoFrame {
+        let mut stamped = frame.clone();
+        let pts = match frame.pts() {
+            Some(pts) => pts,
+            None => return stamped,
+        };
+        let first_pts = *self.first_pts.get_or_insert(pts);
+        let seconds = (pts - first_pts) as f64 * f64::from(self.time_base);
+        draw_text(&mut stamped, &format_timestamp(&self.format, seconds), self.corner);
+        stamped
+    }
+}
+
+/// Fills in the format with the given number of seconds: `%H` is replaced with whole hours, `%M`
+/// and `%S` with the minutes and seconds past them, `%s` with the whole number of seconds and `%%`
+/// with a percent sign
+fn format_timestamp(format: &str, seconds: f64) -> String {
+    let total_seconds = seconds.max(0.0) as u64;
+    let mut formatted = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            formatted.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('H') => formatted.push_str(&format!("{:02}", total_seconds / 3600)),
+            Some('M') => formatted.push_str(&format!("{:02}", total_seconds / 60 % 60)),
+            Some('S') => formatted.push_str(&format!("{:02}", total_seconds % 60)),
+            Some('s') => formatted.push_str(&total_seconds.to_string()),
+            Some('%') => formatted.push('%'),
+            Some(other) => {
+                formatted.push('%');
+                formatted.push(other);
+            },
+            None => formatted.push('%'),
+        }
+    }
+    formatted
+}
+
+/// Rows of the 5x7 glyph for the character, most significant of the 5 bits on the left. Only
+/// digits and a handful of separators are drawn; anything else leaves a gap.
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c {
+        '0' => [0b01110, 0b10001, 0b10011, 0b10101, 0b11001, 0b10001, 0b01110],
+        '1' => [0b00100, 0b01100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110],
+        '2' => [0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b01000, 0b11111],
+        '3' => [0b11111, 0b00010, 0b00100, 0b00010, 0b00001, 0b10001, 0b01110],
+        '4' => [0b00010, 0b00110, 0b01010, 0b10010, 0b11111, 0b00010, 0b00010],
+        '5' => [0b11111, 0b10000, 0b11110, 0b00001, 0b00001, 0b10001, 0b01110],
+        '6' => [0b00110, 0b01000, 0b10000, 0b11110, 0b10001, 0b10001, 0b01110],
+        '7' => [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b01000, 0b01000],
+        '8' => [0b01110, 0b10001, 0b10001, 0b01110, 0b10001, 0b10001, 0b01110],
+        '9' => [0b01110, 0b10001, 0b10001, 0b01111, 0b00001, 0b00010, 0b01100],
+        ':' => [0b00000, 0b01100, 0b01100, 0b00000, 0b01100, 0b01100, 0b00000],
+        '.' => [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b01100, 0b01100],
+        '-' => [0b00000, 0b00000, 0b00000, 0b11111, 0b00000, 0b00000, 0b00000],
+        '/' => [0b00000, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b00000],
+        _ => [0; GLYPH_HEIGHT],
+    }
+}
+
+/// Draws the text in white on a black box in the given corner of the frame. The glyphs are scaled
+/// up with the frame so that they stay readable on large frames.
+fn draw_text(frame: &mut VideoFrame, text: &str, corner: Corner) {
+    let frame_width = frame.width() as usize;
+    let frame_height = frame.height() as usize;
+    let scale = (frame_height / 240).max(1);
+    let margin = 2 * scale;
+    let glyphs = text.chars().map(glyph).collect::<Vec<_>>();
+    if glyphs.is_empty() {
+        return;
+    }
+
+    // One blank column between glyphs, and a margin of blank space around the text
+    let text_width = glyphs.len() * (GLYPH_WIDTH + 1) * scale - scale + 2 * margin;
+    let text_height = GLYPH_HEIGHT * scale + 2 * margin;
+    if text_width > frame_width || text_height > frame_height {
+        return;
+    }
+
+    let (left, top) = match corner {
+        Corner::TopLeft => (0, 0),
+        Corner::TopRight => (frame_width - text_width, 0),
+        Corner::BottomLeft => (0, frame_height - text_height),
+        Corner::BottomRight => (frame_width - text_width, frame_height - text_height),
+    };
+
+    for y in 0..text_height {
+        for x in 0..text_width {
+            let glyph_x = x as isize - margin as isize;
+            let glyph_y = y as isize - margin as isize;
+            let lit = glyph_x >= 0 && glyph_y >= 0 && {
+                let (glyph_x, glyph_y) = (glyph_x as usize / scale, glyph_y as usize / scale);
+                let column = glyph_x % (GLYPH_WIDTH + 1);
+                let row = glyph_y;
+                row < GLYPH_HEIGHT && column < GLYPH_WIDTH && glyphs.get(glyph_x / (GLYPH_WIDTH + 1))
+                    .map_or(false, |glyph| glyph[row] & (1 << (GLYPH_WIDTH - 1 - column)) != 0)
+            };
+            set_pixel(frame, left + x, top + y, lit);
+        }
+    }
+}
+
+/// Sets the pixel to white if lit, black otherwise. The chroma of YUV frames is made neutral so
+/// that the box comes out grey rather than tinted.
+fn set_pixel(frame: &mut VideoFrame, x: usize, y: usize, lit: bool) {
+    match frame.format() {
+        Pixel::RGB24 | Pixel::BGR24 => {
+            let stride = frame.stride(0);
+            let value = if lit { 255 } else { 0 };
+            let offset = y * stride + x * 3;
+            for byte in &mut frame.data_mut(0)[offset..(offset + 3)] {
+                *byte = value;
+            }
+        },
+        Pixel::YUV420P10LE => {
+            let luma_stride = frame.stride(0);
+            let luma: u16 = if lit { 940 } else { 64 };
+            frame.data_mut(0)[(y * luma_stride + x * 2)..(y * luma_stride + x * 2 + 2)].copy_from_slice(&luma.to_le_bytes());
+            for plane in 1..frame.planes() {
+                let (chroma_x, chroma_y) = (x / 2, y / 2);
+                let stride = frame.stride(plane);
+                frame.data_mut(plane)[(chroma_y * stride + chroma_x * 2)..(chroma_y * stride + chroma_x * 2 + 2)].copy_from_slice(&512u16.to_le_bytes());
+            }
+        },
+        _ => {
+            let luma_stride = frame.stride(0);
+            frame.data_mut(0)[y * luma_stride + x] = if lit { 235 } else { 16 };
+            for plane in 1..frame.planes() {
+                let chroma_x = x * plane_row_len(frame, plane) / frame.width() as usize;
+                let chroma_y = y * frame.plane_height(plane) as usize / frame.height() as usize;
+                let stride = frame.stride(plane);
+                frame.data_mut(plane)[chroma_y * stride + chroma_x] = 128;
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_timestamp_fills_in_each_field() {
+        assert_eq!(format_timestamp("%H:%M:%S", 3725.9), "01:02:05");
+        assert_eq!(format_timestamp("%ss", 3725.9), "3725s");
+        assert_eq!(format_timestamp("day %H", 90_000.0), "day 25");
+        assert_eq!(format_timestamp("100%%", 0.0), "100%");
+    }
+
+    #[test]
+    fn format_timestamp_keeps_unknown_fields_as_they_are() {
+        assert_eq!(format_timestamp("%Y-%S", 7.0), "%Y-07");
+        assert_eq!(format_timestamp("50%", 7.0), "50%");
+        assert_eq!(format_timestamp("%S", -3.0), "00");
+    }
+}
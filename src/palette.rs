@@ -0,0 +1,165 @@
+use std::cmp::Reverse;
+use std::slice;
+
+use ffmpeg::format::Pixel;
+use ffmpeg::util::frame::Video as VideoFrame;
+
+/// A colour palette of up to 256 entries for a set of RGB24 frames, used to turn them into the
+/// indexed frames GIF output needs. Colours are bucketed at 5 bits per channel and the most
+/// common buckets across the frames make up the palette.
+pub struct Palette {
+    colors: Vec<[u8; 3]>,
+    lookup: Vec<Option<u8>>,
+}
+
+impl Palette {
+    const MAX_COLORS: usize = 256;
+    const MAX_SAMPLED_FRAMES: usize = 32;
+
+    /// Builds the palette from an even sample of the frames
+    pub fn from_frames(frames: &[VideoFrame]) -> Self {
+        let mut histogram = vec![0u32; 1 << 15];
+        let step = (frames.len() / Self::MAX_SAMPLED_FRAMES).max(1);
+        for frame in frames.iter().step_by(step) {
+            for_each_pixel(frame, |r, g, b| {
+                let bucket = bucket(r, g, b);
+                histogram[bucket] = histogram[bucket].saturating_add(1);
+            });
+        }
+
+        let mut buckets: Vec<usize> = (0..histogram.len()).filter(|&i| histogram[i] > 0).collect();
+        buckets.sort_by_key(|&i| Reverse(histogram[i]));
+        buckets.truncate(Self::MAX_COLORS);
+
+        let mut colors: Vec<[u8; 3]> = buckets.into_iter().map(bucket_color).collect();
+        if colors.is_empty() {
+            colors.push([0, 0, 0]);
+        }
+
+        Self {
+            colors,
+            lookup: vec![None; 1 << 15],
+        }
+    }
+
+    /// Maps an RGB24 frame onto the palette, producing a PAL8 frame carrying the palette
+    pub fn index_frame(&mut self, frame: &VideoFrame) -> VideoFrame {
+        let width = frame.width() as usize;
+        let mut indexed = VideoFrame::new(Pixel::PAL8, frame.width(), frame.height());
+        let stride = indexed.stride(0);
+
+        let mut indices = Vec::<u8>::with_capacity(width * frame.height() as usize);
+        for_each_pixel(frame, |r, g, b| indices.push(self.index_of(r, g, b)));
+        let data = indexed.data_mut(0);
+        for (y, row) in indices.chunks(width).enumerate() {
+            data[(y * stride)..(y * stride + width)].copy_from_slice(row);
+        }
+
+        // The palette plane is 256 native-endian 0xAARRGGBB entries, but ffmpeg reports its size
+        // as if it were a regular plane, so it is addressed directly
+        let palette = unsafe { slice::from_raw_parts_mut((*indexed.as_mut_ptr()).data[1], Self::MAX_COLORS * 4) };
+        for (i, entry) in palette.chunks_exact_mut(4).enumerate() {
+            let [r, g, b] = self.colors.get(i).cloned().unwrap_or([0, 0, 0]);
+            let argb = 0xFF00_0000 | (u32::from(r) << 16) | (u32::from(g) << 8) | u32::from(b);
+            entry.copy_from_slice(&argb.to_ne_bytes());
+        }
+
+        indexed
+    }
+
+    fn index_of(&mut self, r: u8, g: u8, b: u8) -> u8 {
+        let bucket = bucket(r, g, b);
+        if let Some(index) = self.lookup[bucket] {
+            return index;
+        }
+
+        let [r, g, b] = bucket_color(bucket);
+        let distance = |c: &[u8; 3]| {
+            let dr = i32::from(c[0]) - i32::from(r);
+            let dg = i32::from(c[1]) - i32::from(g);
+            let db = i32::from(c[2]) - i32::from(b);
+            dr * dr + dg * dg + db * db
+        };
+        let index = self.colors.iter().enumerate()
+            .min_by_key(|(_, c)| distance(c))
+            .map(|(i, _)| i as u8)
+            .unwrap_or(0);
+        self.lookup[bucket] = Some(index);
+        index
+    }
+}
+
+fn for_each_pixel<F: FnMut(u8, u8, u8)>(frame: &VideoFrame, mut f: F) {
+    let row_len = frame.width() as usize * 3;
+    let stride = frame.stride(0);
+    let data = frame.data(0);
+    for y in 0..(frame.height() as usize) {
+        for rgb in data[(y * stride)..(y * stride + row_len)].chunks_exact(3) {
+            f(rgb[0], rgb[1], rgb[2]);
+        }
+    }
+}
+
+fn bucket(r: u8, g: u8, b: u8) -> usize {
+    (usize::from(r >> 3) << 10) | (usize::from(g >> 3) << 5) | usize::from(b >> 3)
+}
+
+/// The colour in the middle of a bucket
+fn bucket_color(bucket: usize) -> [u8; 3] {
+    [
+        (((bucket >> 10) & 31) << 3 | 4) as u8,
+        (((bucket >> 5) & 31) << 3 | 4) as u8,
+        ((bucket & 31) << 3 | 4) as u8,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post_processing::plane_samples;
+
+    /// An RGB24 frame with each row filled with the colour given for it
+    fn striped_frame(width: u32, rows: &[[u8; 3]]) -> VideoFrame {
+        let mut frame = VideoFrame::new(Pixel::RGB24, width, rows.len() as u32);
+        let stride = frame.stride(0);
+        let data = frame.data_mut(0);
+        for (y, color) in rows.iter().enumerate() {
+            for pixel in data[(y * stride)..(y * stride + width as usize * 3)].chunks_exact_mut(3) {
+                pixel.copy_from_slice(color);
+            }
+        }
+        frame
+    }
+
+    fn palette_entry(frame: &VideoFrame, index: usize) -> u32 {
+        let palette = unsafe { slice::from_raw_parts((*frame.as_ptr()).data[1], Palette::MAX_COLORS * 4) };
+        let entry = &palette[(index * 4)..(index * 4 + 4)];
+        u32::from_ne_bytes([entry[0], entry[1], entry[2], entry[3]])
+    }
+
+    const RED: [u8; 3] = [255, 0, 0];
+    const BLUE: [u8; 3] = [0, 0, 255];
+
+    #[test]
+    fn indexed_frame_carries_the_most_common_colours_first() {
+        ffmpeg::init().unwrap();
+        let frame = striped_frame(4, &[RED, RED, RED, BLUE]);
+        let mut palette = Palette::from_frames(&[frame.clone()]);
+
+        let indexed = palette.index_frame(&frame);
+        assert_eq!(indexed.format(), Pixel::PAL8);
+        assert_eq!(plane_samples(&indexed, 0), [vec![0; 12], vec![1; 4]].concat());
+        assert_eq!(palette_entry(&indexed, 0), 0xFFFC_0404);
+        assert_eq!(palette_entry(&indexed, 1), 0xFF04_04FC);
+        // Unused entries are opaque black
+        assert_eq!(palette_entry(&indexed, 2), 0xFF00_0000);
+    }
+
+    #[test]
+    fn colours_missing_from_the_palette_get_the_nearest_one() {
+        ffmpeg::init().unwrap();
+        let mut palette = Palette::from_frames(&[striped_frame(4, &[RED, RED, BLUE])]);
+        let indexed = palette.index_frame(&striped_frame(4, &[[240, 10, 10], [20, 0, 200]]));
+        assert_eq!(plane_samples(&indexed, 0), [vec![0; 4], vec![1; 4]].concat());
+    }
+}
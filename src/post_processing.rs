@@ -0,0 +1,492 @@
+use std::collections::VecDeque;
+
+use ffmpeg::ffi::{sws_getCoefficients, sws_setColorspaceDetails, AVColorSpace};
+use ffmpeg::format::Pixel;
+use ffmpeg::software::scaling::{flag::Flags as ScalingFlags, Context as ScalingContext};
+use ffmpeg::util::color;
+use ffmpeg::util::frame::Video as VideoFrame;
+
+use crate::request::{Rect, ScaleAlgorithm};
+
+/// Evens out brightness between consecutive output frames by pulling the luma of each frame
+/// towards the rolling average of the frames before it
+pub struct Deflicker {
+    window: usize,
+    history: VecDeque<f64>,
+}
+
+impl Deflicker {
+    const MIN_GAIN: f64 = 0.5;
+    const MAX_GAIN: f64 = 2.0;
+
+    pub fn new(window: usize) -> Self {
+        Self {
+            window: window.max(1),
+            history: VecDeque::with_capacity(window.max(1)),
+        }
+    }
+
+    /// Scales the Y plane of a planar YUV frame, or all channels of a packed RGB frame, in place
+    pub fn apply(&mut self, frame: &mut VideoFrame) {
+        let mean = mean_plane_value(frame, 0);
+
+        if !self.history.is_empty() && mean > 0.0 {
+            let target = self.history.iter().sum::<f64>() / self.history.len() as f64;
+            let gain = (target / mean).max(Self::MIN_GAIN).min(Self::MAX_GAIN);
+            scale_plane(frame, 0, gain);
+        }
+
+        if self.history.len() == self.window {
+            self.history.pop_front();
+        }
+        self.history.push_back(mean);
+    }
+}
+
+/// Evens out jumps between consecutive output frames by replacing each frame with the average of
+/// it and the frames before it
+pub struct Smoother {
+    window: usize,
+    history: VecDeque<VideoFrame>,
+}
+
+impl Smoother {
+    /// Averages each frame with up to `frames` frames before it
+    pub fn new(frames: usize) -> Self {
+        Self {
+            window: frames + 1,
+            history: VecDeque::with_capacity(frames + 1),
+        }
+    }
+
+    /// Returns the average of the frame and the ones given before it. Frames come from the
+    /// encoder's scaler, so they always have 8 bits per sample.
+    pub fn apply(&mut self, frame: &VideoFrame) -> VideoFrame {
+        if self.history.len() == self.window {
+            self.history.pop_front();
+        }
+        self.history.push_back(frame.clone());
+
+        let count = self.history.len() as u32;
+        let mut smoothed = frame.clone();
+        for plane in 0..frame.planes() {
+            let width = plane_row_len(frame, plane);
+            let height = frame.plane_height(plane) as usize;
+            let mut sums = vec![0u32; width];
+            for y in 0..height {
+                for sum in sums.iter_mut() {
+                    *sum = 0;
+                }
+                for past in &self.history {
+                    let row = &past.data(plane)[(y * past.stride(plane))..(y * past.stride(plane) + width)];
+                    for (sum, &value) in sums.iter_mut().zip(row) {
+                        *sum += u32::from(value);
+                    }
+                }
+
+                let stride = smoothed.stride(plane);
+                let row = &mut smoothed.data_mut(plane)[(y * stride)..(y * stride + width)];
+                for (value, sum) in row.iter_mut().zip(&sums) {
+                    *value = ((sum + count / 2) / count) as u8;
+                }
+            }
+        }
+        smoothed
+    }
+}
+
+fn mean_plane_value(frame: &VideoFrame, plane: usize) -> f64 {
+    let width = plane_row_len(frame, plane);
+    let height = frame.plane_height(plane) as usize;
+    let stride = frame.stride(plane);
+    let data = frame.data(plane);
+
+    let mut sum = 0u64;
+    for y in 0..height {
+        sum += data[(y * stride)..(y * stride + width)].iter().map(|&v| u64::from(v)).sum::<u64>();
+    }
+    sum as f64 / (width * height).max(1) as f64
+}
+
+fn scale_plane(frame: &mut VideoFrame, plane: usize, gain: f64) {
+    let width = plane_row_len(frame, plane);
+    let height = frame.plane_height(plane) as usize;
+    let stride = frame.stride(plane);
+    let data = frame.data_mut(plane);
+
+    for y in 0..height {
+        for value in &mut data[(y * stride)..(y * stride + width)] {
+            *value = (f64::from(*value) * gain).round().max(0.0).min(255.0) as u8;
+        }
+    }
+}
+
+/// Fades the output in from black over its first frames and out to black over its last ones.
+/// There is no telling which frames are the last until the input runs out, so that many frames
+/// are held back until `finish`.
+pub struct Fade {
+    fade_in: usize,
+    fade_out: usize,
+    frames_seen: usize,
+    tail: VecDeque<VideoFrame>,
+}
+
+impl Fade {
+    pub fn new(fade_in: usize, fade_out: usize) -> Self {
+        Self {
+            fade_in,
+            fade_out,
+            frames_seen: 0,
+            tail: VecDeque::with_capacity(fade_out + 1),
+        }
+    }
+
+    /// Fades the frame in if it is one of the first ones, and returns the frame which is now
+    /// ready to be written, if any
+    pub fn push(&mut self, mut frame: VideoFrame) -> Option<VideoFrame> {
+        if self.frames_seen < self.fade_in {
+            fade_frame(&mut frame, self.frames_seen as f64 / self.fade_in as f64);
+        }
+        self.frames_seen += 1;
+
+        if self.fade_out == 0 {
+            return Some(frame);
+        }
+        self.tail.push_back(frame);
+        if self.tail.len() > self.fade_out { self.tail.pop_front() } else { None }
+    }
+
+    /// Fades out the frames held back and returns them, the last one being completely black
+    pub fn finish(&mut self) -> Vec<VideoFrame> {
+        let fade_out = self.fade_out as f64;
+        let count = self.tail.len();
+        self.tail.drain(..).enumerate().map(|(i, mut frame)| {
+            fade_frame(&mut frame, (count - 1 - i) as f64 / fade_out);
+            frame
+        }).collect()
+    }
+}
+
+/// Takes the colour out of the frame in place. YUV frames get neutral chroma; the pixels of RGB
+/// frames are set to their Rec. 601 luma.
+pub fn desaturate(frame: &mut VideoFrame) {
+    match frame.format() {
+        Pixel::RGB24 | Pixel::BGR24 => {
+            let row_len = plane_row_len(frame, 0);
+            let height = frame.height() as usize;
+            let stride = frame.stride(0);
+            let data = frame.data_mut(0);
+            for y in 0..height {
+                for pixel in data[(y * stride)..(y * stride + row_len)].chunks_exact_mut(3) {
+                    let luma = ((299 * u32::from(pixel[0]) + 587 * u32::from(pixel[1]) + 114 * u32::from(pixel[2]) + 500) / 1000) as u8;
+                    for channel in pixel.iter_mut() {
+                        *channel = luma;
+                    }
+                }
+            }
+        },
+        _ => {
+            for plane in 1..frame.planes() {
+                let row_len = plane_row_len(frame, plane);
+                let height = frame.plane_height(plane) as usize;
+                let stride = frame.stride(plane);
+                let data = frame.data_mut(plane);
+                for y in 0..height {
+                    for value in &mut data[(y * stride)..(y * stride + row_len)] {
+                        *value = 128;
+                    }
+                }
+            }
+        },
+    }
+}
+
+/// Mixes two frames of the same format and size, `weight` being how much of the second one goes
+/// into the result
+pub fn blend_frames(from: &VideoFrame, to: &VideoFrame, weight: f64) -> VideoFrame {
+    let mut blended = VideoFrame::new(from.format(), from.width(), from.height());
+    for plane in 0..from.planes() {
+        let width = plane_row_len(from, plane);
+        let from_stride = from.stride(plane);
+        let to_stride = to.stride(plane);
+        let blended_stride = blended.stride(plane);
+        let from_data = from.data(plane);
+        let to_data = to.data(plane);
+        let blended_data = blended.data_mut(plane);
+        for y in 0..(from.plane_height(plane) as usize) {
+            let from_row = &from_data[(y * from_stride)..(y * from_stride + width)];
+            let to_row = &to_data[(y * to_stride)..(y * to_stride + width)];
+            let blended_row = &mut blended_data[(y * blended_stride)..(y * blended_stride + width)];
+            for ((value, &a), &b) in blended_row.iter_mut().zip(from_row).zip(to_row) {
+                *value = (f64::from(a) * (1.0 - weight) + f64::from(b) * weight).round() as u8;
+            }
+        }
+    }
+    blended
+}
+
+/// Darkens the frame to the given level, 0 making it black and 1 leaving it as it is. The chroma
+/// of YUV frames is pulled towards grey along with the luma.
+fn fade_frame(frame: &mut VideoFrame, level: f64) {
+    match frame.format() {
+        Pixel::RGB24 | Pixel::BGR24 => scale_plane(frame, 0, level),
+        _ => {
+            scale_plane(frame, 0, level);
+            for plane in 1..frame.planes() {
+                let width = plane_row_len(frame, plane);
+                let height = frame.plane_height(plane) as usize;
+                let stride = frame.stride(plane);
+                let data = frame.data_mut(plane);
+                for y in 0..height {
+                    for value in &mut data[(y * stride)..(y * stride + width)] {
+                        *value = (128.0 + (f64::from(*value) - 128.0) * level).round() as u8;
+                    }
+                }
+            }
+        },
+    }
+}
+
+/// The swscale flag for the scaling algorithm
+pub fn scaling_flags(algorithm: ScaleAlgorithm) -> ScalingFlags {
+    match algorithm {
+        ScaleAlgorithm::Bilinear => ScalingFlags::BILINEAR,
+        ScaleAlgorithm::Bicubic => ScalingFlags::BICUBIC,
+        ScaleAlgorithm::Lanczos => ScalingFlags::LANCZOS,
+        ScaleAlgorithm::Neighbor => ScalingFlags::POINT,
+        ScaleAlgorithm::Area => ScalingFlags::AREA,
+    }
+}
+
+/// Tells the scaler which matrix the YUV frames going in and coming out of it use, and whether
+/// they use the full range of values or the limited one. Without this, swscale assumes BT.601
+/// and the limited range for everything but the `yuvj` formats. Has no effect on RGB frames.
+pub fn set_color_details(scaler: &mut ScalingContext, space: color::Space, full_range_in: bool, full_range_out: bool) {
+    let space: AVColorSpace = space.into();
+    unsafe {
+        // Unknown matrices get the default coefficients
+        let coefficients = sws_getCoefficients(space as i32);
+        sws_setColorspaceDetails(scaler.as_mut_ptr(), coefficients, full_range_in as i32, coefficients, full_range_out as i32, 0, 1 << 16, 1 << 16);
+    }
+}
+
+/// Whether frames in the format always use the full range of values, whatever they are tagged
+pub fn is_full_range_format(format: Pixel) -> bool {
+    match format {
+        Pixel::YUVJ420P | Pixel::YUVJ422P | Pixel::YUVJ444P | Pixel::YUVJ440P | Pixel::YUVJ411P => true,
+        _ => false,
+    }
+}
+
+/// Number of bytes of actual picture data in each line of the plane
+pub fn plane_row_len(frame: &VideoFrame, plane: usize) -> usize {
+    match (frame.format(), plane) {
+        (Pixel::RGB24, _) | (Pixel::BGR24, _) => frame.width() as usize * 3,
+        (Pixel::NV12, 1) | (Pixel::YUV420P10LE, _) => frame.plane_width(plane) as usize * 2,
+        _ => frame.plane_width(plane) as usize,
+    }
+}
+
+/// Copies the given rectangle out of the frame into a new frame of the same format
+pub fn crop_frame(frame: &VideoFrame, rect: Rect) -> VideoFrame {
+    let mut cropped = VideoFrame::new(frame.format(), rect.width, rect.height);
+    let (chroma_shift_w, chroma_shift_h) = frame.format().descriptor()
+        .map_or((0, 0), |descriptor| (descriptor.log2_chroma_w(), descriptor.log2_chroma_h()));
+
+    for plane in 0..frame.planes() {
+        let bytes_per_pixel = match (frame.format(), plane) {
+            (Pixel::RGB24, _) | (Pixel::BGR24, _) => 3,
+            (Pixel::NV12, 1) | (Pixel::YUV420P10LE, _) => 2,
+            (Pixel::YUV420P, _) | (Pixel::YUVJ420P, _) | (Pixel::YUV422P, _) | (Pixel::YUV444P, _) | (Pixel::NV12, _) | (Pixel::GRAY8, _) => 1,
+            (format, _) => panic!("Cannot crop a frame in {:?} format", format),
+        };
+        let (x, y) = if plane == 1 || plane == 2 {
+            (rect.x >> chroma_shift_w, rect.y >> chroma_shift_h)
+        } else {
+            (rect.x, rect.y)
+        };
+
+        let row_len = cropped.plane_width(plane) as usize * bytes_per_pixel;
+        let offset = x as usize * bytes_per_pixel;
+        let src_stride = frame.stride(plane);
+        let dst_stride = cropped.stride(plane);
+        for row in 0..(cropped.plane_height(plane) as usize) {
+            let src_start = (y as usize + row) * src_stride + offset;
+            cropped.data_mut(plane)[(row * dst_stride)..(row * dst_stride + row_len)]
+                .copy_from_slice(&frame.data(plane)[src_start..(src_start + row_len)]);
+        }
+    }
+    cropped
+}
+
+/// Rotates the frame clockwise by 90, 180 or 270 degrees into a new frame of the same format.
+/// Formats with different horizontal and vertical chroma subsampling can't be rotated this way.
+pub fn rotate_frame(frame: &VideoFrame, degrees: u32) -> VideoFrame {
+    let (width, height) = match degrees {
+        90 | 270 => (frame.height(), frame.width()),
+        _ => (frame.width(), frame.height()),
+    };
+    let mut rotated = VideoFrame::new(frame.format(), width, height);
+
+    for plane in 0..frame.planes() {
+        let bytes_per_pixel = match frame.format() {
+            Pixel::RGB24 | Pixel::BGR24 => 3,
+            Pixel::YUV420P | Pixel::YUVJ420P | Pixel::YUV444P | Pixel::GRAY8 => 1,
+            Pixel::YUV420P10LE => 2,
+            format => panic!("Cannot rotate a frame in {:?} format", format),
+        };
+        let plane_width = frame.plane_width(plane) as usize;
+        let plane_height = frame.plane_height(plane) as usize;
+        let src_stride = frame.stride(plane);
+        let dst_stride = rotated.stride(plane);
+        let src = frame.data(plane);
+        let dst = rotated.data_mut(plane);
+
+        for y in 0..plane_height {
+            for x in 0..plane_width {
+                let (dst_x, dst_y) = match degrees {
+                    90 => (plane_height - 1 - y, x),
+                    180 => (plane_width - 1 - x, plane_height - 1 - y),
+                    270 => (y, plane_width - 1 - x),
+                    _ => (x, y),
+                };
+                let src_start = y * src_stride + x * bytes_per_pixel;
+                let dst_start = dst_y * dst_stride + dst_x * bytes_per_pixel;
+                dst[dst_start..(dst_start + bytes_per_pixel)].copy_from_slice(&src[src_start..(src_start + bytes_per_pixel)]);
+            }
+        }
+    }
+    rotated
+}
+
+/// A frame with every sample of every plane set to `value`, for tests
+#[cfg(test)]
+pub fn flat_frame(format: Pixel, width: u32, height: u32, value: u8) -> VideoFrame {
+    let mut frame = VideoFrame::new(format, width, height);
+    for plane in 0..frame.planes() {
+        for sample in frame.data_mut(plane).iter_mut() {
+            *sample = value;
+        }
+    }
+    frame
+}
+
+/// A YUV420P frame with the luma of each pixel given by `luma(x, y)` and neutral chroma, for tests
+#[cfg(test)]
+pub fn luma_frame<F>(width: u32, height: u32, luma: F) -> VideoFrame
+where F: Fn(u32, u32) -> u8 {
+    let mut frame = flat_frame(Pixel::YUV420P, width, height, 128);
+    let stride = frame.stride(0);
+    let data = frame.data_mut(0);
+    for y in 0..height {
+        for x in 0..width {
+            data[y as usize * stride + x as usize] = luma(x, y);
+        }
+    }
+    frame
+}
+
+/// The samples of the plane which are part of the picture, leaving out the padding, for tests
+#[cfg(test)]
+pub fn plane_samples(frame: &VideoFrame, plane: usize) -> Vec<u8> {
+    let row_len = plane_row_len(frame, plane);
+    let stride = frame.stride(plane);
+    (0..(frame.plane_height(plane) as usize))
+        .flat_map(|y| frame.data(plane)[(y * stride)..(y * stride + row_len)].to_vec())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blending_mixes_every_plane_by_the_weight() {
+        ffmpeg::init().unwrap();
+        let from = flat_frame(Pixel::YUV420P, 4, 4, 0);
+        let to = flat_frame(Pixel::YUV420P, 4, 4, 200);
+
+        let blended = blend_frames(&from, &to, 0.25);
+        assert_eq!(plane_samples(&blended, 0), vec![50; 16]);
+        assert_eq!(plane_samples(&blended, 1), vec![50; 4]);
+        assert_eq!(plane_samples(&blended, 2), vec![50; 4]);
+    }
+
+    #[test]
+    fn blending_by_nothing_or_everything_gives_one_of_the_frames() {
+        ffmpeg::init().unwrap();
+        let from = flat_frame(Pixel::RGB24, 4, 4, 10);
+        let to = flat_frame(Pixel::RGB24, 4, 4, 201);
+        assert_eq!(plane_samples(&blend_frames(&from, &to, 0.0), 0), vec![10; 48]);
+        assert_eq!(plane_samples(&blend_frames(&from, &to, 1.0), 0), vec![201; 48]);
+        // Halfway between rounds
+        assert_eq!(plane_samples(&blend_frames(&from, &to, 0.5), 0), vec![106; 48]);
+    }
+
+    #[test]
+    fn smoothing_averages_each_frame_with_the_ones_before_it() {
+        ffmpeg::init().unwrap();
+        let smoothed = |frames: usize, values: &[u8]| {
+            let mut smoother = Smoother::new(frames);
+            values.iter().map(|&value| plane_samples(&smoother.apply(&flat_frame(Pixel::YUV420P, 4, 4, value)), 0)[0]).collect::<Vec<_>>()
+        };
+        // Flicker between two frames is taken out altogether
+        assert_eq!(smoothed(1, &[100, 140, 100, 140, 100]), vec![100, 120, 120, 120, 120]);
+        // A jump is spread over as many frames as are averaged
+        assert_eq!(smoothed(2, &[0, 90, 90, 90, 90]), vec![0, 45, 60, 90, 90]);
+    }
+
+    #[test]
+    fn desaturating_yuv_keeps_the_luma_and_greys_the_chroma() {
+        ffmpeg::init().unwrap();
+        let mut frame = flat_frame(Pixel::YUV420P, 4, 4, 30);
+        desaturate(&mut frame);
+        assert_eq!(plane_samples(&frame, 0), vec![30; 16]);
+        assert_eq!(plane_samples(&frame, 1), vec![128; 4]);
+        assert_eq!(plane_samples(&frame, 2), vec![128; 4]);
+    }
+
+    #[test]
+    fn desaturating_rgb_sets_each_channel_to_the_luma() {
+        ffmpeg::init().unwrap();
+        let mut frame = flat_frame(Pixel::RGB24, 4, 4, 0);
+        let stride = frame.stride(0);
+        for y in 0..4 {
+            for pixel in frame.data_mut(0)[(y * stride)..(y * stride + 12)].chunks_exact_mut(3) {
+                pixel.copy_from_slice(&[255, 0, 0]);
+            }
+        }
+        desaturate(&mut frame);
+        assert_eq!(plane_samples(&frame, 0), vec![76; 48]);
+    }
+
+    #[test]
+    fn fading_starts_and_ends_on_black() {
+        ffmpeg::init().unwrap();
+        let mut fade = Fade::new(3, 3);
+        let mut faded = (0..8).filter_map(|_| fade.push(flat_frame(Pixel::YUV420P, 4, 4, 200))).collect::<Vec<_>>();
+        faded.extend(fade.finish());
+
+        let luma = faded.iter().map(|frame| plane_samples(frame, 0)[0]).collect::<Vec<_>>();
+        assert_eq!(luma, vec![0, 67, 133, 200, 200, 133, 67, 0]);
+        // Black has neutral chroma
+        for frame in [&faded[0], &faded[7]].iter() {
+            assert_eq!(plane_samples(frame, 1), vec![128; 4]);
+            assert_eq!(plane_samples(frame, 2), vec![128; 4]);
+        }
+    }
+
+    #[test]
+    fn deflickering_evens_out_alternating_brightness() {
+        ffmpeg::init().unwrap();
+        let mut deflicker = Deflicker::new(4);
+        let luma = [100, 140, 100, 140, 100, 140, 100, 140].iter().map(|&value| {
+            let mut frame = flat_frame(Pixel::YUV420P, 4, 4, value);
+            deflicker.apply(&mut frame);
+            plane_samples(&frame, 0)[0]
+        }).collect::<Vec<_>>();
+        // Once the history holds as many bright frames as dark ones, every frame is pulled to
+        // the middle
+        assert_eq!(luma, vec![100, 100, 120, 113, 120, 120, 120, 120]);
+    }
+}
@@ -0,0 +1,127 @@
+#[cfg(feature = "preview")]
+pub use self::window::Preview;
+#[cfg(not(feature = "preview"))]
+pub use self::stub::Preview;
+
+#[cfg(feature = "preview")]
+mod window {
+    use ffmpeg::format::Pixel;
+    use ffmpeg::software::scaling::{flag::Flags as ScalingFlags, Context as ScalingContext};
+    use ffmpeg::util::frame::Video as VideoFrame;
+    use minifb::{Window, WindowOptions};
+
+    use crate::error::TimelapseError;
+
+    /// Widest the preview window gets; larger frames are scaled down to fit
+    const MAX_WIDTH: u32 = 640;
+
+    /// A window showing the picked frames as they come. Once it is closed, frames are no longer
+    /// shown but the run carries on.
+    pub struct Preview {
+        window: Option<Window>,
+        closed: bool,
+        scaler: Option<ScalingContext>,
+        buffer: Vec<u32>,
+    }
+
+    impl Preview {
+        pub fn new() -> Result<Self, TimelapseError> {
+            Ok(Self {
+                window: None,
+                closed: false,
+                scaler: None,
+                buffer: Vec::new(),
+            })
+        }
+
+        /// Shows the frame in the window, opening it for the first frame
+        pub fn show(&mut self, frame: &VideoFrame) -> Result<(), TimelapseError> {
+            if self.closed {
+                return Ok(());
+            }
+            let (width, height) = preview_size(frame.width(), frame.height());
+            if self.window.is_none() {
+                let mut window = Window::new("timelapse-rs", width as usize, height as usize, WindowOptions::default())
+                    .map_err(|e| TimelapseError::InvalidRequest(format!("cannot open the preview window: {}", e)))?;
+                // Showing a frame shouldn't hold up picking the next one
+                window.limit_update_rate(None);
+                self.window = Some(window);
+            }
+
+            let reusable = self.scaler.as_ref().map_or(false, |scaler| {
+                let input = scaler.input();
+                input.format == frame.format() && input.width == frame.width() && input.height == frame.height()
+            });
+            if !reusable {
+                // BGRA read as little endian words is the 0RGB minifb takes
+                self.scaler = Some(ScalingContext::get(frame.format(), frame.width(), frame.height(), Pixel::BGRA, width, height, ScalingFlags::AREA)?);
+            }
+            let mut small_frame = VideoFrame::empty();
+            self.scaler.as_mut().unwrap().run(frame, &mut small_frame)?;
+
+            let stride = small_frame.stride(0);
+            let data = small_frame.data(0);
+            self.buffer.clear();
+            for y in 0..(height as usize) {
+                let row = &data[(y * stride)..(y * stride + width as usize * 4)];
+                self.buffer.extend(row.chunks_exact(4).map(|bgra| u32::from_le_bytes([bgra[0], bgra[1], bgra[2], bgra[3]])));
+            }
+
+            let window = self.window.as_mut().unwrap();
+            if !window.is_open() || window.update_with_buffer(&self.buffer, width as usize, height as usize).is_err() {
+                self.window = None;
+                self.closed = true;
+            }
+            Ok(())
+        }
+    }
+
+    /// Size of the preview of a frame of the given size, no wider than `MAX_WIDTH`
+    fn preview_size(width: u32, height: u32) -> (u32, u32) {
+        if width <= MAX_WIDTH {
+            (width, height)
+        } else {
+            (MAX_WIDTH, (u64::from(height) * u64::from(MAX_WIDTH) / u64::from(width)).max(1) as u32)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn preview_can_be_made_without_a_window() {
+            // The window only opens with the first frame, so this needs no display
+            let preview = Preview::new().unwrap();
+            assert!(preview.window.is_none());
+            assert!(!preview.closed);
+        }
+
+        #[test]
+        fn preview_size_fits_within_max_width() {
+            assert_eq!(preview_size(320, 240), (320, 240));
+            assert_eq!(preview_size(1920, 1080), (640, 360));
+            assert_eq!(preview_size(10_000, 1), (640, 1));
+        }
+    }
+}
+
+#[cfg(not(feature = "preview"))]
+mod stub {
+    use ffmpeg::util::frame::Video as VideoFrame;
+
+    use crate::error::TimelapseError;
+
+    /// Stands in for the preview window when timelapse-rs is built without the `preview` feature
+    pub struct Preview;
+
+    impl Preview {
+        pub fn new() -> Result<Self, TimelapseError> {
+            Err(TimelapseError::InvalidRequest(String::from("--preview needs timelapse-rs to be built with the preview feature")))
+        }
+
+        pub fn show(&mut self, _frame: &VideoFrame) -> Result<(), TimelapseError> {
+            Ok(())
+        }
+    }
+}
@@ -1,11 +1,17 @@
 use std::path::{Path, PathBuf};
+use std::error::Error;
 use std::ffi::OsStr;
 use std::string::ToString;
 use std::str::FromStr;
 use std::fmt::{self, Display};
+use std::time::Duration;
 
+use ffmpeg::Rational;
 use structopt::StructOpt;
 
+use crate::{output_frame_count, sampled_frame_count};
+use crate::decoder::VideoInfo;
+
 /// Processes videos into timelapses by selectively picking one for every window-size frames from
 /// the input. The frame is selected based on its similarity to the previous frame, in order to
 /// not result in a jittery sped-up video but something that's hopefully much smoother. The primary
@@ -13,11 +19,16 @@ use structopt::StructOpt;
 #[derive(StructOpt, Debug)]
 #[structopt(name = "timelapse-rs")]
 pub struct Request {
-    /// Path to the input file
-    #[structopt(name = "INPUT", parse(from_os_str))]
-    input_path: PathBuf,
+    /// Path to the input file. Several inputs with the same dimensions can be given, in which
+    /// case they are read one after another as if they were a single video. Use `-` to read from
+    /// standard input. URLs such as `rtsp://` or `http://` are read as network streams, eg. from
+    /// a live camera.
+    #[structopt(name = "INPUT", parse(from_os_str), required = true)]
+    input_paths: Vec<PathBuf>,
 
-    /// Path to the output file (.webm)
+    /// Path to the output file. Use `-` to write to standard output, in which case the format is
+    /// picked by `--format` or `--codec`; mp4 and mov output is written in fragments so that it
+    /// can be streamed.
     #[structopt(name = "OUTPUT", parse(from_os_str))]
     output_path: PathBuf,
 
@@ -25,21 +36,235 @@ pub struct Request {
     #[structopt(long, default_value = "25")]
     pub window_size: u32,
 
+    /// Length of input, in seconds, to pick each output frame from. Overrides `--window-size`
+    /// based on the frame rate of the input.
+    #[structopt(long)]
+    pub window_duration: Option<f64>,
+
+    /// Number of output frames to pick from each window. Values over 1 keep some of the motion
+    /// while still leaving out the frames that differ the most. The picked frames are written in
+    /// the order they appear in the input.
+    #[structopt(long, default_value = "1")]
+    pub frames_per_window: u32,
+
+    /// Stop once this many frames have been picked, eg. to get a timelapse of a set length. When
+    /// the length of the input is known, the windows are made longer if that is what it takes for
+    /// the whole input to fit. Frames added by `--blend` or `--boomerang` don't count.
+    #[structopt(long)]
+    pub max_output_frames: Option<u64>,
+
+    /// Length, in seconds, the output should play for. Overrides `--window-size` based on the
+    /// length of the input and the frame rate of the output; when the length of the input is not
+    /// known, `--window-size` is used after all.
+    #[structopt(long)]
+    pub target_duration: Option<f64>,
+
+    /// How many times as fast as the input the output should play, eg. 60 to turn every minute of
+    /// input into a second of output. Overrides `--window-size` based on the frame rates of the
+    /// input and the output.
+    #[structopt(long)]
+    pub speed: Option<f64>,
+
+    /// Number of frames at the end of each window to also offer as candidates in the next one,
+    /// which smooths out jumps where one window ends and the next begins. Frames which have been
+    /// picked already are not offered again. Must be smaller than the window size.
+    #[structopt(long, default_value = "0")]
+    pub window_overlap: u32,
+
     /// Number of input frames to skip for every output frame (may be useful for timelapses
     /// made from realtime videos)
     #[structopt(long, default_value = "0")]
     pub frame_skip: u32,
 
+    /// Only consider every nth frame of the input, eg. to get through inputs which are hours long
+    /// faster. Unlike `--frame-skip`, this thins out the whole input evenly: frames left out by
+    /// `--frame-skip` aren't counted, and `--window-size` counts the frames which are left.
+    #[structopt(long, default_value = "1")]
+    pub sample_every: u32,
+
     /// Only use "key" frames from the input, eg. frames that encode a full image rather than those
     /// that encode differences between images. The behaviour of this option depends on the encoding
     /// of the input video, and may be useful for timelapses made from realtime videos.
     #[structopt(long)]
     pub key_frames_only: bool,
 
+    /// Use the video stream with this index rather than letting ffmpeg pick the best one, for
+    /// inputs with more than one video stream
+    #[structopt(long)]
+    pub video_stream: Option<usize>,
+
+    /// Drop decoded frames which are the same as the frame before them, for cameras that repeat
+    /// frames while the scene is static
+    #[structopt(long)]
+    pub dedup: bool,
+
+    /// With `--dedup`, also drop frames whose luma differs from the frame before them by at most
+    /// this mean square error. At 0 only exact duplicates are dropped.
+    #[structopt(long, default_value = "0")]
+    pub dedup_epsilon: f64,
+
+    /// Leave out the frames at the start of the input until the scene first changes, and stop
+    /// reading the input once the scene has stayed still for `--static-tail` seconds, eg. to skip
+    /// an empty print bed before and after a print
+    #[structopt(long)]
+    pub trim_static: bool,
+
+    /// How much the scene must change (as the mean square error of luma) to count as moving for
+    /// `--trim-static`
+    #[structopt(long, default_value = "50")]
+    pub static_threshold: f64,
+
+    /// Number of seconds the scene must stay still for `--trim-static` to stop reading the input
+    #[structopt(long, default_value = "60")]
+    pub static_tail: f64,
+
+    /// Only use the input from this point onwards, given as `HH:MM:SS.mmm` or as a number of
+    /// seconds
+    #[structopt(long, parse(try_from_str = parse_timestamp))]
+    pub start_time: Option<Duration>,
+
+    /// Stop using the input at this point, given as `HH:MM:SS.mmm` or as a number of seconds
+    #[structopt(long, parse(try_from_str = parse_timestamp))]
+    pub end_time: Option<Duration>,
+
+    /// Copy the audio of the input into the output as it is. The audio is not sped up, so it will
+    /// usually carry on long after the video has ended.
+    #[structopt(long)]
+    pub keep_audio: bool,
+
+    /// Leave out frames which fail to decode or convert rather than stopping, eg. so that a
+    /// corrupt packet doesn't throw away hours of recording. The number of frames left out is
+    /// reported at the end.
+    #[structopt(long)]
+    pub skip_errors: bool,
+
+    /// Number of times in a row to try reading the input again, after a short pause, when reading
+    /// fails, eg. because a network stream dropped out. Without it, the first failed read ends
+    /// the run with an error.
+    #[structopt(long)]
+    pub read_retries: Option<u32>,
+
+    /// Number of bytes ffmpeg reads from the input to work out what is in it. Raise this if the
+    /// size of frames from eg. a webcam's MJPEG stream comes out wrong.
+    #[structopt(long)]
+    pub probe_size: Option<u64>,
+
+    /// Number of seconds of the input ffmpeg reads to work out what is in it. Raise this along
+    /// with `--probe-size` for streams it has trouble with.
+    #[structopt(long)]
+    pub analyze_duration: Option<f64>,
+
+    /// Option to open the inputs with, as `key=value`, eg. `rtsp_transport=udp`. Can be given
+    /// several times. Network streams are opened over TCP and time out after 10 seconds unless
+    /// these say otherwise.
+    #[structopt(long = "input-option", number_of_values = 1, parse(try_from_str = parse_option))]
+    pub input_options: Vec<(String, String)>,
+
+    /// Option to write the output with, as `key=value`, eg. `movflags=+faststart`. Can be given
+    /// several times.
+    #[structopt(long = "output-option", number_of_values = 1, parse(try_from_str = parse_option))]
+    pub output_options: Vec<(String, String)>,
+
+    /// Pick frames as usual but don't write the output, instead printing which input frame was
+    /// picked for every output frame
+    #[structopt(long)]
+    pub dry_run: bool,
+
+    /// Show the picked frames in a window as they are picked. Needs timelapse-rs to be built with
+    /// the `preview` feature.
+    #[structopt(long)]
+    pub preview: bool,
+
+    /// Overwrite the output if it exists already. Without this, an existing output is left alone
+    /// and nothing is done.
+    #[structopt(short, long)]
+    pub force: bool,
+
+    /// Write the timestamp and score of the input frame picked for every output frame to this file,
+    /// as CSV or JSON depending on its extension
+    #[structopt(long, parse(from_os_str))]
+    pub manifest: Option<PathBuf>,
+
+    /// Write a grid of thumbnails of the picked frames to this image, in the format given by its
+    /// extension
+    #[structopt(long, parse(from_os_str))]
+    pub contact_sheet: Option<PathBuf>,
+
+    /// Number of thumbnails in each row of the contact sheet
+    #[structopt(long, default_value = "8")]
+    pub sheet_cols: u32,
+
+    /// Put every Nth picked frame on the contact sheet. If not given, the frames are spread out so
+    /// that the sheet is about as tall as it is wide in thumbnails, if the number of output frames
+    /// is known, or every frame is put on it otherwise.
+    #[structopt(long)]
+    pub sheet_every: Option<u64>,
+
+    /// Write thumbnails for seeking through the output in a web player, as a sprite sheet at this
+    /// path with `.jpg` added and a WebVTT track pointing into it at this path with `.vtt` added.
+    /// Nothing is written with `--dry-run`, as the thumbnails are taken from the encoded frames.
+    #[structopt(long, parse(from_os_str))]
+    pub webvtt: Option<PathBuf>,
+
+    /// Seconds of output between the thumbnails written by `--webvtt`
+    #[structopt(long, default_value = "1")]
+    pub webvtt_interval: f64,
+
+    /// Keep the picked frames in this directory. A later run on the same input with the same
+    /// options for picking frames reads them from there instead of decoding the input again, which
+    /// makes trying out different encoder options much quicker. The frames are stored uncompressed,
+    /// so the cache can get large.
+    #[structopt(long, parse(from_os_str))]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Print the `--dry-run` report as JSON, one object per line
+    #[structopt(long)]
+    pub json: bool,
+
     /// Verbose output (-v, -vv, -vvv etc) - show messages from the app itself and from ffmpeg
     #[structopt(short, long, parse(from_occurrences))]
     pub verbose: u8,
 
+    /// Don't print progress or status messages, only errors. Messages asked for with `-v` and the
+    /// `--dry-run` report are still printed.
+    #[structopt(short, long)]
+    pub quiet: bool,
+
+    /// How to print messages: `text`, or `json` for one JSON object per line with an `event` field
+    /// saying what it is about (`message`, `progress`, `selection` or `done`)
+    #[structopt(long, default_value = "text")]
+    pub log_format: LogFormat,
+
+    /// Append messages, including ffmpeg's, to this file instead of printing them. Errors are
+    /// still printed.
+    #[structopt(long, parse(from_os_str))]
+    pub log_file: Option<PathBuf>,
+
+    /// Number of threads to compare frames on. 0 uses one thread per CPU core.
+    #[structopt(long, default_value = "0")]
+    pub threads: usize,
+
+    /// Number of threads ffmpeg decodes the input on, which speeds up eg. H.264 and HEVC. 0 lets
+    /// ffmpeg pick.
+    #[structopt(long, default_value = "0")]
+    pub decode_threads: usize,
+
+    /// Print how long decoding, picking and encoding frames took once done
+    #[structopt(long)]
+    pub stats: bool,
+
+    /// Print a histogram of the scores of the picked frames once done, eg. to tell whether the
+    /// comparison mode is to blame for a jittery timelapse
+    #[structopt(long)]
+    pub score_histogram: bool,
+
+    /// Read options from this TOML file. Keys are the long option names, eg. `window-size = 50`
+    /// or `keep-audio = true`, and options which can be given more than once take a list, eg.
+    /// `input-option = ["probesize=32"]`. Options given on the command line override those in the
+    /// file.
+    #[structopt(long, parse(from_os_str))]
+    pub config: Option<PathBuf>,
+
     /// How to compare frames to determine similarity
     /// 
     /// Current options:
@@ -47,38 +272,615 @@ pub struct Request {
     /// * `blockhash` (from `img_hash`) - fast but often has poor results
     /// * `gradienthash` (from `img_hash`) - slower, potentially better results
     /// * `meanhash` (from `img_hash`) - slower, potentially better results
+    /// * `dcthash` (from `img_hash`, also known as pHash) - copes well with noise and small changes
+    ///   in scale, which helps with webcam footage
     /// * `mse` - mean square error - slow, but should have good results
+    /// * `msergb` - mean square error over all colour channels rather than just luma - slower
+    ///   than `mse`, but notices changes in colour as well as in brightness
     /// * `ssim` - structured similarity index - slowest, but should have best results
+    /// * `sharpest` - picks the sharpest frame in each window without comparing it to the previous
+    ///   one - useful for cameras that keep refocusing
+    /// * `bestexposure` - picks the frame whose average brightness is closest to `--target-luma` -
+    ///   useful against flickering exposure outdoors
+    /// * `histogram` - compares colour histograms rather than pixels, so a frame that has only
+    ///   shifted by a few pixels still counts as similar - useful for shaky footage
+    /// * `motion` - ignores `--window-size` and emits a frame whenever the scene has changed by
+    ///   more than `--motion-threshold` since the last emitted frame
     #[structopt(short, long, default_value = "mse")]
     pub comparison_mode: ComparisonMode,
+
+    /// How much the scene must change (as the mean square error of luma) for the `motion`
+    /// comparison mode to emit a frame
+    #[structopt(long, default_value = "50")]
+    pub motion_threshold: f64,
+
+    /// Which frame to pick from each window by the comparison mode: `best`, the one closest to the
+    /// last picked frame, or `worst`, the one which differs from it the most, eg. to bring out
+    /// motion. Only applies to `mse`, `msergb`, `ssim`, `histogram` and the hash modes.
+    #[structopt(long, default_value = "best")]
+    pub select: SelectMode,
+
+    /// Invert the luma of frames before comparing them (the output is left as it is), eg. for
+    /// infrared or inverted cameras. Only works with `ssim` and `bestexposure`, where
+    /// `--target-luma` then applies to the inverted frames: the differences the other comparison
+    /// modes measure come out the same either way.
+    #[structopt(long)]
+    pub invert: bool,
+
+    /// Leave out picked frames which differ from the last frame written by less than this mean
+    /// square error of their luma, so that the output only moves on when something happens. The
+    /// `motion` comparison mode uses `--motion-threshold` instead.
+    #[structopt(long)]
+    pub min_change: Option<f64>,
+
+    /// Width and height of the hashes used by the `blockhash`, `gradienthash` and `meanhash`
+    /// comparison modes. Must be a power of two; larger hashes are slower but tell frames apart
+    /// more precisely.
+    #[structopt(long, default_value = "8")]
+    pub hash_size: u32,
+
+    /// Frames are shrunk to this many pixels square before being hashed, which is much faster
+    /// for large inputs. 0 hashes frames at full size.
+    #[structopt(long, default_value = "64")]
+    pub hash_resolution: u32,
+
+    /// Hash the luma of frames rather than their colours, which saves converting every frame to
+    /// RGB. Most of what the hashes pick up on is in the luma anyway.
+    #[structopt(long)]
+    pub hash_luma: bool,
+
+    /// Compare candidate frames against this image rather than against the previously picked
+    /// frame, so that every picked frame is the one closest to it (eg. an empty printer bed). Works
+    /// with the `mse`, `msergb`, `ssim` and hash comparison modes.
+    #[structopt(long, parse(from_os_str))]
+    pub reference: Option<PathBuf>,
+
+    /// Only compare this part of the frames, given as `x,y,width,height` in pixels. The whole
+    /// frame is still written to the output.
+    #[structopt(long)]
+    pub roi: Option<Rect>,
+
+    /// Crop the output to this part of the frames, given as `x,y,width,height` in pixels
+    #[structopt(long)]
+    pub crop: Option<Rect>,
+
+    /// Resize the output to this size, given as `WIDTHxHEIGHT`. Applied after `--crop`.
+    #[structopt(long)]
+    pub scale: Option<Size>,
+
+    /// Algorithm to resize and convert frames with
+    ///
+    /// Current options:
+    ///
+    /// * `bilinear` - fast and good enough for most inputs
+    /// * `bicubic` - sharper than bilinear, a little slower
+    /// * `lanczos` - sharpest, best for shrinking large inputs, slowest
+    /// * `neighbor` - fastest, but blocky
+    /// * `area` - averages pixels, which works well for shrinking
+    #[structopt(long, default_value = "bilinear")]
+    pub scale_algo: ScaleAlgorithm,
+
+    /// Make the output black and white
+    #[structopt(long)]
+    pub grayscale: bool,
+
+    /// Bits per sample to compare frames at. 10 keeps the full precision of 10-bit (eg. HDR)
+    /// inputs rather than cutting them down to 8 bits, and only works with the `mse` comparison
+    /// mode: the hashes, histograms, SSIM's constants and the other measures are all worked out
+    /// over 8-bit samples, so they would only cut the frames down again.
+    #[structopt(long, default_value = "8")]
+    pub bit_depth: u8,
+
+    /// Average brightness (0-255) the `bestexposure` comparison mode aims for
+    #[structopt(long, default_value = "128")]
+    pub target_luma: u8,
+
+    /// Even out the brightness of consecutive output frames to reduce flicker
+    #[structopt(long)]
+    pub deflicker: bool,
+
+    /// Number of previous output frames whose average brightness `--deflicker` aims for
+    #[structopt(long, default_value = "10")]
+    pub deflicker_window: usize,
+
+    /// Average each output frame with this many frames before it, which evens out the jumps left
+    /// between picked frames at the cost of some sharpness and a trail behind moving things. 0
+    /// leaves frames as they are.
+    #[structopt(long, default_value = "0")]
+    pub smooth: usize,
+
+    /// Number of frames to add between each pair of picked frames, each a mix of the two, which
+    /// makes the output play back more smoothly. The output ends up about `--blend` + 1 times as
+    /// long.
+    #[structopt(long, default_value = "0")]
+    pub blend: usize,
+
+    /// Number of output frames to fade in from black over at the start
+    #[structopt(long, default_value = "0")]
+    pub fade_in: usize,
+
+    /// Number of output frames to fade out to black over at the end
+    #[structopt(long, default_value = "0")]
+    pub fade_out: usize,
+
+    /// Once all picked frames have been written, write them again backwards (leaving out the last
+    /// and the first so that they don't show twice), so that the output plays forwards and then
+    /// backwards and loops seamlessly. The picked frames are kept in a temporary file until then.
+    #[structopt(long)]
+    pub boomerang: bool,
+
+    /// Space the output frames out by how far apart the frames they were picked from were shot,
+    /// rather than evenly, so that gaps in the input (eg. while the camera was off) show as pauses.
+    /// The output plays back just as much faster than the input on average as it would without
+    /// this. Cannot be used with `--blend`, `--boomerang` or `--webvtt`.
+    #[structopt(long)]
+    pub real_time_pacing: bool,
+
+    /// Draw how far into the input each output frame was taken in a corner of the frame
+    #[structopt(long)]
+    pub timestamp: bool,
+
+    /// How `--timestamp` is written: `%H` stands for hours, `%M` for minutes, `%S` for seconds and
+    /// `%s` for the total number of seconds. Only digits and `:` `.` `-` `/` are drawn.
+    #[structopt(long, default_value = "%H:%M:%S")]
+    pub timestamp_format: String,
+
+    /// Corner `--timestamp` is drawn in: `top-left`, `top-right`, `bottom-left` or `bottom-right`
+    #[structopt(long, default_value = "bottom-left")]
+    pub timestamp_pos: Corner,
+
+    /// Video codec to encode the output with
+    ///
+    /// Current options:
+    ///
+    /// * `vp9` - good compression, slow to encode
+    /// * `vp8` - faster than vp9 but produces larger files
+    /// * `h264` - widely supported, requires an ffmpeg build with an h264 encoder
+    /// * `av1` - best compression, slowest to encode
+    #[structopt(long, default_value = "vp9")]
+    pub codec: OutputCodec,
+
+    /// Encode on the GPU. The following options are supported:
+    ///
+    /// * `vaapi` - Intel and AMD GPUs on Linux, for the `h264`, `vp9` and `vp8` codecs
+    /// * `nvenc` - NVIDIA GPUs, for the `h264` codec
+    ///
+    /// The ffmpeg build must include the matching encoder.
+    #[structopt(long)]
+    pub hwaccel: Option<HwAccel>,
+
+    /// Largest number of output frames between key frames. Lower values make the output easier to
+    /// seek through, higher values make it smaller.
+    #[structopt(long, default_value = "10")]
+    pub gop: u32,
+
+    /// How much time the encoder spends on compressing the output, from `ultrafast` through
+    /// `superfast`, `veryfast`, `faster`, `fast`, `medium`, `slow` and `slower` to `veryslow`.
+    /// Slower presets make smaller or better looking files. Sets `preset` for h264 and `cpu-used`
+    /// for vp8, vp9 and av1; has no effect with `--hwaccel`.
+    #[structopt(long, default_value = "medium")]
+    pub preset: Preset,
+
+    /// Encode the output twice, first to analyse the picked frames and then to spend the bitrate
+    /// where it helps most, which keeps the output closer to `--bitrate`. The picked frames are
+    /// kept in a temporary file between the two passes, so the input is only decoded once; it
+    /// needs as much free space as the raw frames take up.
+    #[structopt(long)]
+    pub two_pass: bool,
+
+    /// Frame rate of the output, which controls how fast the timelapse plays back. By default the
+    /// output has the same frame rate as the input.
+    #[structopt(long, parse(try_from_str = parse_frame_rate))]
+    pub output_fps: Option<f64>,
+
+    /// Frame rate of GIF output, which controls how fast it plays back. Defaults to
+    /// `--output-fps`, or the frame rate of the input.
+    #[structopt(long, parse(try_from_str = parse_frame_rate))]
+    pub gif_fps: Option<f64>,
+
+    /// Largest width of GIF output in pixels; wider videos are scaled down to fit
+    #[structopt(long)]
+    pub gif_max_width: Option<u32>,
+
+    /// Container format of the output file, as an ffmpeg muxer name (eg. `webm`, `mp4`,
+    /// `matroska`). By default this is inferred from the extension of the output file, or from
+    /// the codec if the output file has no extension. An output such as `out/%05d.png` writes
+    /// every output frame as a separate image.
+    #[structopt(long)]
+    pub format: Option<String>,
+
+    /// Target bitrate of the output in bits per second. Accepts `k`, `M` and `G` suffixes, eg.
+    /// `500k` or `8M`.
+    #[structopt(long, default_value = "5000000", parse(try_from_str = parse_bitrate))]
+    pub bitrate: usize,
+
+    /// Maximum bitrate of the output in bits per second. Accepts the same suffixes as `--bitrate`.
+    #[structopt(long, default_value = "10000000", parse(try_from_str = parse_bitrate))]
+    pub max_bitrate: usize,
+
+    /// Range of values to write the output video with, `tv` for the limited range most players
+    /// expect or `pc` for the full range. The colour primaries, transfer characteristics and matrix
+    /// of the input are carried over to the output either way. Only applies to video output.
+    #[structopt(long, default_value = "tv")]
+    pub color_range: ColorRange,
+
+    /// Title to tag the output with
+    #[structopt(long)]
+    pub title: Option<String>,
+
+    /// Comment to tag the output with
+    #[structopt(long)]
+    pub comment: Option<String>,
+
+    /// Creation time to tag the output with, eg. `2020-06-01T12:00:00Z`. Defaults to the creation
+    /// time of the input if it has one, or to the current time.
+    #[structopt(long)]
+    pub creation_time: Option<String>,
 }
 
 impl Default for Request {
     fn default() -> Self {
         Self {
-            input_path: PathBuf::new(),
+            input_paths: Vec::new(),
             output_path: PathBuf::new(),
             window_size: 25,
+            window_duration: None,
+            frames_per_window: 1,
+            max_output_frames: None,
+            target_duration: None,
+            speed: None,
+            window_overlap: 0,
             frame_skip: 0,
+            sample_every: 1,
             key_frames_only: true,
+            video_stream: None,
+            dedup: false,
+            dedup_epsilon: 0.0,
+            trim_static: false,
+            static_threshold: 50.0,
+            static_tail: 60.0,
+            start_time: None,
+            end_time: None,
+            keep_audio: false,
+            skip_errors: false,
+            read_retries: None,
+            probe_size: None,
+            analyze_duration: None,
+            input_options: Vec::new(),
+            output_options: Vec::new(),
+            dry_run: false,
+            preview: false,
+            force: false,
+            manifest: None,
+            contact_sheet: None,
+            sheet_cols: 8,
+            sheet_every: None,
+            webvtt: None,
+            webvtt_interval: 1.0,
+            cache_dir: None,
+            json: false,
             verbose: 0,
+            quiet: false,
+            log_format: LogFormat::Text,
+            log_file: None,
+            threads: 0,
+            decode_threads: 0,
+            stats: false,
+            score_histogram: false,
+            config: None,
             comparison_mode: ComparisonMode::MSE,
+            hash_size: 8,
+            hash_resolution: 64,
+            hash_luma: false,
+            reference: None,
+            roi: None,
+            crop: None,
+            scale: None,
+            scale_algo: ScaleAlgorithm::Bilinear,
+            grayscale: false,
+            bit_depth: 8,
+            target_luma: 128,
+            motion_threshold: 50.0,
+            select: SelectMode::Best,
+            invert: false,
+            min_change: None,
+            deflicker: false,
+            deflicker_window: 10,
+            smooth: 0,
+            blend: 0,
+            fade_in: 0,
+            fade_out: 0,
+            boomerang: false,
+            real_time_pacing: false,
+            timestamp: false,
+            timestamp_format: String::from("%H:%M:%S"),
+            timestamp_pos: Corner::BottomLeft,
+            codec: OutputCodec::Vp9,
+            hwaccel: None,
+            gop: 10,
+            preset: Preset::Medium,
+            two_pass: false,
+            output_fps: None,
+            gif_fps: None,
+            gif_max_width: None,
+            format: None,
+            bitrate: 5_000_000,
+            max_bitrate: 10_000_000,
+            color_range: ColorRange::Tv,
+            title: None,
+            comment: None,
+            creation_time: None,
         }
     }
 }
 
 impl Request {
+    /// A request with the same defaults as the command line, to be filled in with the setters and
+    /// handed to [`run`](crate::run):
+    ///
+    /// ```no_run
+    /// use timelapse_rs::request::{ComparisonMode, Request};
+    ///
+    /// let mut request = Request::new();
+    /// request.set_input_path("print.mp4")
+    ///     .set_output_path("timelapse.webm")
+    ///     .set_window_size(30)
+    ///     .set_comparison_mode(ComparisonMode::SSIM);
+    /// request.validate().unwrap();
+    ///
+    /// let stats = timelapse_rs::run(&request, None).unwrap();
+    /// println!("{} frames written", stats.frames_written);
+    /// ```
     pub fn new() -> Self {
         Self::default()
     }
 
+    /// Checks for settings that cannot work together or would make processing fail later on
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if self.window_size == 0 {
+            return Err(ValidationError::ZeroWindowSize);
+        }
+
+        if self.frames_per_window == 0 {
+            return Err(ValidationError::ZeroFramesPerWindow);
+        }
+
+        if self.sample_every == 0 {
+            return Err(ValidationError::ZeroSampleEvery);
+        }
+
+        if self.max_output_frames == Some(0) {
+            return Err(ValidationError::ZeroMaxOutputFrames);
+        }
+
+        if let Some(analyze_duration) = self.analyze_duration {
+            if !analyze_duration.is_finite() || analyze_duration <= 0.0 {
+                return Err(ValidationError::InvalidAnalyzeDuration(analyze_duration));
+            }
+        }
+
+        if let Some(target_duration) = self.target_duration {
+            if !target_duration.is_finite() || target_duration <= 0.0 {
+                return Err(ValidationError::InvalidTargetDuration(target_duration));
+            }
+            if self.window_duration.is_some() {
+                return Err(ValidationError::TargetDurationWithWindowDuration);
+            }
+        }
+
+        if let Some(speed) = self.speed {
+            if !speed.is_finite() || speed <= 0.0 {
+                return Err(ValidationError::InvalidSpeed(speed));
+            }
+            if self.window_duration.is_some() {
+                return Err(ValidationError::SpeedWithWindowDuration);
+            }
+            if self.target_duration.is_some() {
+                return Err(ValidationError::SpeedWithTargetDuration);
+            }
+        }
+
+        // The other options replace --window-size with one that isn't known until the input has
+        // been opened, and the decoder checks the overlap against that instead
+        let window_size_given = self.window_duration.is_none() && self.speed.is_none()
+            && self.target_duration.is_none() && self.max_output_frames.is_none();
+        if window_size_given && self.window_overlap >= self.window_size {
+            return Err(ValidationError::OverlapNotSmallerThanWindow);
+        }
+
+        if self.input_paths.is_empty() {
+            return Err(ValidationError::NoInput);
+        }
+
+        for input_path in &self.input_paths {
+            if *input_path == self.output_path && !is_stdio(input_path) {
+                return Err(ValidationError::SameInputAndOutput);
+            }
+
+            let input_pathname = input_path.to_string_lossy();
+            let is_pattern = input_pathname.contains('*') || input_pathname.contains('?') || input_pathname.contains('%');
+            if !is_pattern && !is_stdio(input_path) && !is_url(input_path) && !input_path.exists() {
+                return Err(ValidationError::InputNotFound(input_path.clone()));
+            }
+        }
+
+        // Nothing is written on a dry run, and standard output cannot be overwritten
+        if !self.force && !self.dry_run && !is_stdio(&self.output_path) {
+            let first_output = first_sequence_path(&self.output_path);
+            if first_output.exists() {
+                return Err(ValidationError::OutputExists(first_output));
+            }
+        }
+
+        if self.keep_audio && self.input_paths.len() > 1 {
+            return Err(ValidationError::AudioFromMultipleInputs);
+        }
+
+        if self.keep_audio && self.two_pass {
+            return Err(ValidationError::AudioWithTwoPass);
+        }
+
+        if self.real_time_pacing && self.blend > 0 {
+            return Err(ValidationError::RealTimePacingWithBlend);
+        }
+
+        if self.real_time_pacing && self.boomerang {
+            return Err(ValidationError::RealTimePacingWithBoomerang);
+        }
+
+        if self.real_time_pacing && self.webvtt.is_some() {
+            return Err(ValidationError::RealTimePacingWithWebVtt);
+        }
+
+        if self.bit_depth != 8 && self.bit_depth != 10 {
+            return Err(ValidationError::UnsupportedBitDepth(self.bit_depth));
+        }
+
+        if self.bit_depth > 8 && self.comparison_mode != ComparisonMode::MSE {
+            return Err(ValidationError::BitDepthNotSupported(self.comparison_mode));
+        }
+
+        if self.two_pass && is_stdio(&self.output_path) {
+            return Err(ValidationError::TwoPassToStdout);
+        }
+
+        if let (Some(start_time), Some(end_time)) = (self.start_time, self.end_time) {
+            if start_time >= end_time {
+                return Err(ValidationError::StartAfterEnd);
+            }
+        }
+
+        if self.gop == 0 {
+            return Err(ValidationError::ZeroGop);
+        }
+
+        if !self.hash_size.is_power_of_two() {
+            return Err(ValidationError::InvalidHashSize(self.hash_size));
+        }
+
+        if let Some(manifest) = self.manifest.as_ref() {
+            if ManifestFormat::from_path(manifest).is_none() {
+                return Err(ValidationError::UnknownManifestFormat(manifest.clone()));
+            }
+        }
+
+        if let Some(contact_sheet) = self.contact_sheet.as_ref() {
+            if image::ImageFormat::from_path(contact_sheet).is_err() {
+                return Err(ValidationError::UnknownContactSheetFormat(contact_sheet.clone()));
+            }
+        }
+
+        if self.sheet_cols == 0 {
+            return Err(ValidationError::ZeroSheetCols);
+        }
+
+        if self.sheet_every == Some(0) {
+            return Err(ValidationError::ZeroSheetEvery);
+        }
+
+        if !self.webvtt_interval.is_finite() || self.webvtt_interval <= 0.0 {
+            return Err(ValidationError::InvalidWebVttInterval(self.webvtt_interval));
+        }
+
+        if self.invert {
+            match self.comparison_mode {
+                ComparisonMode::SSIM | ComparisonMode::BestExposure => (),
+                comparison_mode => return Err(ValidationError::InvertNotSupported(comparison_mode)),
+            }
+        }
+
+        if let Some(reference) = self.reference.as_ref() {
+            match self.comparison_mode {
+                ComparisonMode::MSE | ComparisonMode::MSERgb | ComparisonMode::SSIM | ComparisonMode::Blockhash | ComparisonMode::GradientHash | ComparisonMode::MeanHash | ComparisonMode::DctHash => (),
+                comparison_mode => return Err(ValidationError::ReferenceNotSupported(comparison_mode)),
+            }
+            if !reference.exists() {
+                return Err(ValidationError::ReferenceNotFound(reference.clone()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the parts of the request which depend on the size of the input frames
+    pub fn validate_for_frame_size(&self, width: u32, height: u32) -> Result<(), ValidationError> {
+        if let Some(roi) = self.roi {
+            if !roi.fits(width, height) {
+                return Err(ValidationError::RoiOutOfBounds(roi, width, height));
+            }
+        }
+
+        if let Some(crop) = self.crop {
+            if !crop.fits(width, height) {
+                return Err(ValidationError::CropOutOfBounds(crop, width, height));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Works out how many frames the output will have and how long it will play for from what is
+    /// known about the input, before any of it is decoded. The fields are `None` if the number of
+    /// frames in the input is not known, with `--key-frames-only`, where it depends on how many
+    /// key frames there are, and in motion mode, where it depends on what happens in the input.
+    pub fn estimate<R>(&self, video_info: &VideoInfo<R>) -> Estimate
+    where R: Into<Rational> + Copy + Clone {
+        let picked_frames = match video_info.total_frames {
+            Some(total_frames) if !self.key_frames_only && self.comparison_mode != ComparisonMode::Motion => {
+                // `--frame-skip` frames are skipped before every frame that is decoded
+                let skip = u64::from(self.frame_skip) + 1;
+                let decoded_frames = total_frames / skip + if total_frames % skip > 0 { 1 } else { 0 };
+                let frames_per_window = u64::from(self.frames_per_window.min(video_info.window_size));
+                let window_step = video_info.window_size - video_info.window_overlap;
+                output_frame_count(sampled_frame_count(decoded_frames, self.sample_every), window_step)
+                    .map(|windows| (windows * frames_per_window).min(self.max_output_frames.unwrap_or(u64::max_value())))
+            },
+            _ => None,
+        };
+        let output_frames = picked_frames.map(|picked_frames| {
+            // Boomerang writes every picked frame but the first and last again
+            let written_frames = if self.boomerang { picked_frames + picked_frames.saturating_sub(2) } else { picked_frames };
+            // Blended frames only go between the frames written
+            written_frames + written_frames.saturating_sub(1) * self.blend as u64
+        });
+        let input_frame_rate: Rational = video_info.frame_rate.into();
+        let output_fps = self.playback_fps().unwrap_or_else(|| f64::from(input_frame_rate));
+        Estimate {
+            picked_frames,
+            output_frames,
+            duration: output_frames.map(|output_frames| output_frames as f64 / output_fps),
+        }
+    }
+
+    /// Replaces the inputs with the given path
     pub fn set_input_path<'a, S: AsRef<OsStr> + ?Sized>(&'a mut self, pathname: &S) -> &'a mut Self {
-        self.input_path = PathBuf::from(pathname);
+        self.input_paths = vec![PathBuf::from(pathname)];
+        self
+    }
+
+    /// Adds a path to be read after the inputs given so far
+    pub fn add_input_path<'a, S: AsRef<OsStr> + ?Sized>(&'a mut self, pathname: &S) -> &'a mut Self {
+        self.input_paths.push(PathBuf::from(pathname));
         self
     }
 
+    /// The first input
     pub fn input_path(&self) -> &Path {
-        self.input_path.as_path()
+        self.input_paths.first().map_or(Path::new(""), PathBuf::as_path)
+    }
+
+    pub fn input_paths(&self) -> &[PathBuf] {
+        &self.input_paths
+    }
+
+    /// Whether one of the inputs is standard input
+    pub fn reads_stdin(&self) -> bool {
+        self.input_paths.iter().any(|path| is_stdio(path))
+    }
+
+    /// Whether one of the inputs is a network stream
+    pub fn reads_url(&self) -> bool {
+        self.input_paths.iter().any(|path| is_url(path))
     }
 
     pub fn set_output_path<'a, S: AsRef<OsStr> + ?Sized>(&'a mut self, pathname: &S) -> &'a mut Self {
@@ -90,64 +892,1534 @@ impl Request {
         self.output_path.as_path()
     }
 
+    /// Frame rate the output plays back at, if it isn't to be the same as the input's
+    pub fn playback_fps(&self) -> Option<f64> {
+        if self.output_format().map_or(false, |format| format == "gif") {
+            self.gif_fps.or(self.output_fps)
+        } else {
+            self.output_fps
+        }
+    }
+
+    /// Returns the ffmpeg short name of the muxer to write the output with
+    pub fn output_format(&self) -> Result<&str, UnknownOutputFormatError> {
+        if let Some(format) = &self.format {
+            return Ok(format.as_str());
+        }
+
+        let extension = match self.output_path.extension() {
+            Some(extension) => extension.to_string_lossy().to_ascii_lowercase(),
+            None => return Ok(self.codec.default_format()),
+        };
+
+        match extension.as_str() {
+            "png" | "jpg" | "jpeg" | "bmp" if self.output_path.to_string_lossy().contains('%') => Ok("image2"),
+            "webm" => Ok("webm"),
+            "mp4" | "m4v" => Ok("mp4"),
+            "mkv" => Ok("matroska"),
+            "mov" => Ok("mov"),
+            "gif" => Ok("gif"),
+            _ => Err(UnknownOutputFormatError(extension)),
+        }
+    }
+
     pub fn set_window_size<'a>(&'a mut self, window_size: u32) -> &'a mut Self {
         self.window_size = window_size;
         self
     }
 
+    pub fn set_window_duration<'a>(&'a mut self, window_duration: Option<f64>) -> &'a mut Self {
+        self.window_duration = window_duration;
+        self
+    }
+
+    pub fn set_frames_per_window<'a>(&'a mut self, frames_per_window: u32) -> &'a mut Self {
+        self.frames_per_window = frames_per_window;
+        self
+    }
+
+    pub fn set_max_output_frames<'a>(&'a mut self, max_output_frames: Option<u64>) -> &'a mut Self {
+        self.max_output_frames = max_output_frames;
+        self
+    }
+
+    pub fn set_target_duration<'a>(&'a mut self, target_duration: Option<f64>) -> &'a mut Self {
+        self.target_duration = target_duration;
+        self
+    }
+
+    pub fn set_speed<'a>(&'a mut self, speed: Option<f64>) -> &'a mut Self {
+        self.speed = speed;
+        self
+    }
+
+    pub fn set_window_overlap<'a>(&'a mut self, window_overlap: u32) -> &'a mut Self {
+        self.window_overlap = window_overlap;
+        self
+    }
+
     pub fn set_frame_skip<'a>(&'a mut self, frame_skip: u32) -> &'a mut Self {
         self.frame_skip = frame_skip;
         self
     }
 
+    pub fn set_sample_every<'a>(&'a mut self, sample_every: u32) -> &'a mut Self {
+        self.sample_every = sample_every;
+        self
+    }
+
     pub fn set_key_frames_only<'a>(&'a mut self, key_frames_only: bool) -> &'a mut Self {
         self.key_frames_only = key_frames_only;
         self
     }
 
-    pub fn set_verbose<'a>(&'a mut self, verbose: u8) -> &'a mut Self {
-        self.verbose = verbose;
+    pub fn set_video_stream<'a>(&'a mut self, video_stream: Option<usize>) -> &'a mut Self {
+        self.video_stream = video_stream;
         self
     }
-}
-
-#[derive(Debug, Copy, Clone)]
-pub enum ComparisonMode {
-    Noop,
-    Blockhash,
-    GradientHash,
-    MeanHash,
-    MSE,
-    SSIM,
-}
 
-#[derive(Debug)]
-pub struct ParseComparisonModeError;
+    pub fn set_dedup<'a>(&'a mut self, dedup: bool) -> &'a mut Self {
+        self.dedup = dedup;
+        self
+    }
 
-impl ToString for ParseComparisonModeError {
-    fn to_string(&self) -> String {
-        String::from("ParseComparisonModeError")
+    pub fn set_dedup_epsilon<'a>(&'a mut self, dedup_epsilon: f64) -> &'a mut Self {
+        self.dedup_epsilon = dedup_epsilon;
+        self
     }
-}
+
+    pub fn set_trim_static<'a>(&'a mut self, trim_static: bool) -> &'a mut Self {
+        self.trim_static = trim_static;
+        self
+    }
+
+    pub fn set_static_threshold<'a>(&'a mut self, static_threshold: f64) -> &'a mut Self {
+        self.static_threshold = static_threshold;
+        self
+    }
+
+    pub fn set_static_tail<'a>(&'a mut self, static_tail: f64) -> &'a mut Self {
+        self.static_tail = static_tail;
+        self
+    }
+
+    pub fn set_start_time<'a>(&'a mut self, start_time: Option<Duration>) -> &'a mut Self {
+        self.start_time = start_time;
+        self
+    }
+
+    pub fn set_end_time<'a>(&'a mut self, end_time: Option<Duration>) -> &'a mut Self {
+        self.end_time = end_time;
+        self
+    }
+
+    pub fn set_keep_audio<'a>(&'a mut self, keep_audio: bool) -> &'a mut Self {
+        self.keep_audio = keep_audio;
+        self
+    }
+
+    pub fn set_skip_errors<'a>(&'a mut self, skip_errors: bool) -> &'a mut Self {
+        self.skip_errors = skip_errors;
+        self
+    }
+
+    pub fn set_read_retries<'a>(&'a mut self, read_retries: Option<u32>) -> &'a mut Self {
+        self.read_retries = read_retries;
+        self
+    }
+
+    pub fn set_probe_size<'a>(&'a mut self, probe_size: Option<u64>) -> &'a mut Self {
+        self.probe_size = probe_size;
+        self
+    }
+
+    pub fn set_analyze_duration<'a>(&'a mut self, analyze_duration: Option<f64>) -> &'a mut Self {
+        self.analyze_duration = analyze_duration;
+        self
+    }
+
+    /// Adds an option to open the inputs with
+    pub fn add_input_option<'a>(&'a mut self, key: &str, value: &str) -> &'a mut Self {
+        self.input_options.push((String::from(key), String::from(value)));
+        self
+    }
+
+    pub fn set_input_options<'a>(&'a mut self, input_options: Vec<(String, String)>) -> &'a mut Self {
+        self.input_options = input_options;
+        self
+    }
+
+    /// Adds an option to write the output with
+    pub fn add_output_option<'a>(&'a mut self, key: &str, value: &str) -> &'a mut Self {
+        self.output_options.push((String::from(key), String::from(value)));
+        self
+    }
+
+    pub fn set_output_options<'a>(&'a mut self, output_options: Vec<(String, String)>) -> &'a mut Self {
+        self.output_options = output_options;
+        self
+    }
+
+    pub fn set_dry_run<'a>(&'a mut self, dry_run: bool) -> &'a mut Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub fn set_preview<'a>(&'a mut self, preview: bool) -> &'a mut Self {
+        self.preview = preview;
+        self
+    }
+
+    pub fn set_force<'a>(&'a mut self, force: bool) -> &'a mut Self {
+        self.force = force;
+        self
+    }
+
+    pub fn set_manifest<'a, S: AsRef<OsStr> + ?Sized>(&'a mut self, pathname: Option<&S>) -> &'a mut Self {
+        self.manifest = pathname.map(PathBuf::from);
+        self
+    }
+
+    pub fn set_contact_sheet<'a, S: AsRef<OsStr> + ?Sized>(&'a mut self, pathname: Option<&S>) -> &'a mut Self {
+        self.contact_sheet = pathname.map(PathBuf::from);
+        self
+    }
+
+    pub fn set_sheet_cols<'a>(&'a mut self, sheet_cols: u32) -> &'a mut Self {
+        self.sheet_cols = sheet_cols;
+        self
+    }
+
+    pub fn set_sheet_every<'a>(&'a mut self, sheet_every: Option<u64>) -> &'a mut Self {
+        self.sheet_every = sheet_every;
+        self
+    }
+
+    pub fn set_webvtt<'a, S: AsRef<OsStr> + ?Sized>(&'a mut self, pathname: Option<&S>) -> &'a mut Self {
+        self.webvtt = pathname.map(PathBuf::from);
+        self
+    }
+
+    pub fn set_webvtt_interval<'a>(&'a mut self, webvtt_interval: f64) -> &'a mut Self {
+        self.webvtt_interval = webvtt_interval;
+        self
+    }
+
+    pub fn set_cache_dir<'a, S: AsRef<OsStr> + ?Sized>(&'a mut self, pathname: Option<&S>) -> &'a mut Self {
+        self.cache_dir = pathname.map(PathBuf::from);
+        self
+    }
+
+    pub fn set_json<'a>(&'a mut self, json: bool) -> &'a mut Self {
+        self.json = json;
+        self
+    }
+
+    pub fn set_verbose<'a>(&'a mut self, verbose: u8) -> &'a mut Self {
+        self.verbose = verbose;
+        self
+    }
+
+    pub fn set_quiet<'a>(&'a mut self, quiet: bool) -> &'a mut Self {
+        self.quiet = quiet;
+        self
+    }
+
+    pub fn set_log_format<'a>(&'a mut self, log_format: LogFormat) -> &'a mut Self {
+        self.log_format = log_format;
+        self
+    }
+
+    pub fn set_log_file<'a, S: AsRef<OsStr> + ?Sized>(&'a mut self, pathname: Option<&S>) -> &'a mut Self {
+        self.log_file = pathname.map(PathBuf::from);
+        self
+    }
+
+    pub fn set_threads<'a>(&'a mut self, threads: usize) -> &'a mut Self {
+        self.threads = threads;
+        self
+    }
+
+    pub fn set_decode_threads<'a>(&'a mut self, decode_threads: usize) -> &'a mut Self {
+        self.decode_threads = decode_threads;
+        self
+    }
+
+    pub fn set_stats<'a>(&'a mut self, stats: bool) -> &'a mut Self {
+        self.stats = stats;
+        self
+    }
+
+    pub fn set_score_histogram<'a>(&'a mut self, score_histogram: bool) -> &'a mut Self {
+        self.score_histogram = score_histogram;
+        self
+    }
+
+    pub fn set_config<'a, S: AsRef<OsStr> + ?Sized>(&'a mut self, pathname: Option<&S>) -> &'a mut Self {
+        self.config = pathname.map(PathBuf::from);
+        self
+    }
+
+    pub fn set_hash_size<'a>(&'a mut self, hash_size: u32) -> &'a mut Self {
+        self.hash_size = hash_size;
+        self
+    }
+
+    pub fn set_hash_resolution<'a>(&'a mut self, hash_resolution: u32) -> &'a mut Self {
+        self.hash_resolution = hash_resolution;
+        self
+    }
+
+    pub fn set_hash_luma<'a>(&'a mut self, hash_luma: bool) -> &'a mut Self {
+        self.hash_luma = hash_luma;
+        self
+    }
+
+    pub fn set_reference<'a, S: AsRef<OsStr> + ?Sized>(&'a mut self, pathname: Option<&S>) -> &'a mut Self {
+        self.reference = pathname.map(PathBuf::from);
+        self
+    }
+
+    pub fn set_roi<'a>(&'a mut self, roi: Option<Rect>) -> &'a mut Self {
+        self.roi = roi;
+        self
+    }
+
+    pub fn set_crop<'a>(&'a mut self, crop: Option<Rect>) -> &'a mut Self {
+        self.crop = crop;
+        self
+    }
+
+    pub fn set_scale<'a>(&'a mut self, scale: Option<Size>) -> &'a mut Self {
+        self.scale = scale;
+        self
+    }
+
+    pub fn set_scale_algo<'a>(&'a mut self, scale_algo: ScaleAlgorithm) -> &'a mut Self {
+        self.scale_algo = scale_algo;
+        self
+    }
+
+    pub fn set_grayscale<'a>(&'a mut self, grayscale: bool) -> &'a mut Self {
+        self.grayscale = grayscale;
+        self
+    }
+
+    pub fn set_bit_depth<'a>(&'a mut self, bit_depth: u8) -> &'a mut Self {
+        self.bit_depth = bit_depth;
+        self
+    }
+
+    pub fn set_target_luma<'a>(&'a mut self, target_luma: u8) -> &'a mut Self {
+        self.target_luma = target_luma;
+        self
+    }
+
+    pub fn set_deflicker<'a>(&'a mut self, deflicker: bool) -> &'a mut Self {
+        self.deflicker = deflicker;
+        self
+    }
+
+    pub fn set_deflicker_window<'a>(&'a mut self, deflicker_window: usize) -> &'a mut Self {
+        self.deflicker_window = deflicker_window;
+        self
+    }
+
+    pub fn set_smooth<'a>(&'a mut self, smooth: usize) -> &'a mut Self {
+        self.smooth = smooth;
+        self
+    }
+
+    pub fn set_blend<'a>(&'a mut self, blend: usize) -> &'a mut Self {
+        self.blend = blend;
+        self
+    }
+
+    pub fn set_fade_in<'a>(&'a mut self, fade_in: usize) -> &'a mut Self {
+        self.fade_in = fade_in;
+        self
+    }
+
+    pub fn set_fade_out<'a>(&'a mut self, fade_out: usize) -> &'a mut Self {
+        self.fade_out = fade_out;
+        self
+    }
+
+    pub fn set_boomerang<'a>(&'a mut self, boomerang: bool) -> &'a mut Self {
+        self.boomerang = boomerang;
+        self
+    }
+
+    pub fn set_real_time_pacing<'a>(&'a mut self, real_time_pacing: bool) -> &'a mut Self {
+        self.real_time_pacing = real_time_pacing;
+        self
+    }
+
+    pub fn set_timestamp<'a>(&'a mut self, timestamp: bool) -> &'a mut Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    pub fn set_timestamp_format<'a>(&'a mut self, timestamp_format: String) -> &'a mut Self {
+        self.timestamp_format = timestamp_format;
+        self
+    }
+
+    pub fn set_timestamp_pos<'a>(&'a mut self, timestamp_pos: Corner) -> &'a mut Self {
+        self.timestamp_pos = timestamp_pos;
+        self
+    }
+
+    pub fn set_comparison_mode<'a>(&'a mut self, comparison_mode: ComparisonMode) -> &'a mut Self {
+        self.comparison_mode = comparison_mode;
+        self
+    }
+
+    pub fn set_select<'a>(&'a mut self, select: SelectMode) -> &'a mut Self {
+        self.select = select;
+        self
+    }
+
+    pub fn set_invert<'a>(&'a mut self, invert: bool) -> &'a mut Self {
+        self.invert = invert;
+        self
+    }
+
+    pub fn set_motion_threshold<'a>(&'a mut self, motion_threshold: f64) -> &'a mut Self {
+        self.motion_threshold = motion_threshold;
+        self
+    }
+
+    pub fn set_min_change<'a>(&'a mut self, min_change: Option<f64>) -> &'a mut Self {
+        self.min_change = min_change;
+        self
+    }
+
+    pub fn set_codec<'a>(&'a mut self, codec: OutputCodec) -> &'a mut Self {
+        self.codec = codec;
+        self
+    }
+
+    pub fn set_hwaccel<'a>(&'a mut self, hwaccel: Option<HwAccel>) -> &'a mut Self {
+        self.hwaccel = hwaccel;
+        self
+    }
+
+    pub fn set_gop<'a>(&'a mut self, gop: u32) -> &'a mut Self {
+        self.gop = gop;
+        self
+    }
+
+    pub fn set_preset<'a>(&'a mut self, preset: Preset) -> &'a mut Self {
+        self.preset = preset;
+        self
+    }
+
+    pub fn set_two_pass<'a>(&'a mut self, two_pass: bool) -> &'a mut Self {
+        self.two_pass = two_pass;
+        self
+    }
+
+    pub fn set_output_fps<'a>(&'a mut self, output_fps: Option<f64>) -> &'a mut Self {
+        self.output_fps = output_fps;
+        self
+    }
+
+    pub fn set_gif_fps<'a>(&'a mut self, gif_fps: Option<f64>) -> &'a mut Self {
+        self.gif_fps = gif_fps;
+        self
+    }
+
+    pub fn set_gif_max_width<'a>(&'a mut self, gif_max_width: Option<u32>) -> &'a mut Self {
+        self.gif_max_width = gif_max_width;
+        self
+    }
+
+    pub fn set_format<'a>(&'a mut self, format: Option<String>) -> &'a mut Self {
+        self.format = format;
+        self
+    }
+
+    pub fn set_bitrate<'a>(&'a mut self, bitrate: usize) -> &'a mut Self {
+        self.bitrate = bitrate;
+        self
+    }
+
+    pub fn set_max_bitrate<'a>(&'a mut self, max_bitrate: usize) -> &'a mut Self {
+        self.max_bitrate = max_bitrate;
+        self
+    }
+
+    pub fn set_color_range<'a>(&'a mut self, color_range: ColorRange) -> &'a mut Self {
+        self.color_range = color_range;
+        self
+    }
+
+    pub fn set_title<'a>(&'a mut self, title: Option<String>) -> &'a mut Self {
+        self.title = title;
+        self
+    }
+
+    pub fn set_comment<'a>(&'a mut self, comment: Option<String>) -> &'a mut Self {
+        self.comment = comment;
+        self
+    }
+
+    pub fn set_creation_time<'a>(&'a mut self, creation_time: Option<String>) -> &'a mut Self {
+        self.creation_time = creation_time;
+        self
+    }
+}
+
+/// What a request is expected to make of an input, as worked out by [`Request::estimate`]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Estimate {
+    /// Number of frames picked from the input
+    pub picked_frames: Option<u64>,
+    /// Number of frames in the output, counting those added by `--blend` and `--boomerang`
+    pub output_frames: Option<u64>,
+    /// How long the output plays for, in seconds
+    pub duration: Option<f64>,
+}
+
+#[derive(Debug)]
+pub enum ValidationError {
+    ZeroWindowSize,
+    ZeroFramesPerWindow,
+    ZeroSampleEvery,
+    ZeroMaxOutputFrames,
+    InvalidTargetDuration(f64),
+    InvalidAnalyzeDuration(f64),
+    TargetDurationWithWindowDuration,
+    InvalidSpeed(f64),
+    SpeedWithWindowDuration,
+    SpeedWithTargetDuration,
+    OverlapNotSmallerThanWindow,
+    ZeroGop,
+    NoInput,
+    SameInputAndOutput,
+    InputNotFound(PathBuf),
+    OutputExists(PathBuf),
+    AudioFromMultipleInputs,
+    AudioWithTwoPass,
+    RealTimePacingWithBlend,
+    RealTimePacingWithBoomerang,
+    RealTimePacingWithWebVtt,
+    TwoPassToStdout,
+    UnsupportedBitDepth(u8),
+    BitDepthNotSupported(ComparisonMode),
+    StartAfterEnd,
+    UnknownManifestFormat(PathBuf),
+    UnknownContactSheetFormat(PathBuf),
+    ZeroSheetCols,
+    ZeroSheetEvery,
+    InvalidWebVttInterval(f64),
+    InvalidHashSize(u32),
+    ReferenceNotFound(PathBuf),
+    ReferenceNotSupported(ComparisonMode),
+    InvertNotSupported(ComparisonMode),
+    RoiOutOfBounds(Rect, u32, u32),
+    CropOutOfBounds(Rect, u32, u32),
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::ZeroWindowSize => write!(f, "--window-size must be at least 1"),
+            ValidationError::ZeroFramesPerWindow => write!(f, "--frames-per-window must be at least 1"),
+            ValidationError::ZeroSampleEvery => write!(f, "--sample-every must be at least 1"),
+            ValidationError::ZeroMaxOutputFrames => write!(f, "--max-output-frames must be at least 1"),
+            ValidationError::InvalidTargetDuration(target_duration) => write!(f, "--target-duration must be a positive number of seconds, not {}", target_duration),
+            ValidationError::InvalidAnalyzeDuration(analyze_duration) => write!(f, "--analyze-duration must be a positive number of seconds, not {}", analyze_duration),
+            ValidationError::TargetDurationWithWindowDuration => write!(f, "--target-duration cannot be used with --window-duration"),
+            ValidationError::InvalidSpeed(speed) => write!(f, "--speed must be a positive number, not {}", speed),
+            ValidationError::SpeedWithWindowDuration => write!(f, "--speed cannot be used with --window-duration"),
+            ValidationError::SpeedWithTargetDuration => write!(f, "--speed cannot be used with --target-duration"),
+            ValidationError::OverlapNotSmallerThanWindow => write!(f, "--window-overlap must be smaller than --window-size"),
+            ValidationError::ZeroGop => write!(f, "--gop must be at least 1"),
+            ValidationError::NoInput => write!(f, "no input was given"),
+            ValidationError::SameInputAndOutput => write!(f, "the output must not overwrite the input"),
+            ValidationError::AudioFromMultipleInputs => write!(f, "--keep-audio only works with a single input"),
+            ValidationError::AudioWithTwoPass => write!(f, "--keep-audio cannot be used with --two-pass"),
+            ValidationError::RealTimePacingWithBlend => write!(f, "--real-time-pacing cannot be used with --blend"),
+            ValidationError::RealTimePacingWithBoomerang => write!(f, "--real-time-pacing cannot be used with --boomerang"),
+            ValidationError::RealTimePacingWithWebVtt => write!(f, "--real-time-pacing cannot be used with --webvtt"),
+            ValidationError::TwoPassToStdout => write!(f, "--two-pass cannot write to standard output"),
+            ValidationError::UnsupportedBitDepth(bit_depth) => write!(f, "--bit-depth must be 8 or 10, got {}", bit_depth),
+            ValidationError::BitDepthNotSupported(mode) => write!(f, "--bit-depth 10 only works with the mse comparison mode, not {}", mode),
+            ValidationError::InputNotFound(path) => write!(f, "input {} does not exist", path.display()),
+            ValidationError::OutputExists(path) => write!(f, "output {} exists already, use --force to overwrite it", path.display()),
+            ValidationError::StartAfterEnd => write!(f, "--start-time must be before --end-time"),
+            ValidationError::InvalidHashSize(size) => write!(f, "--hash-size must be a power of two, got {}", size),
+            ValidationError::UnknownManifestFormat(path) => write!(f, "cannot tell the format of manifest {}, use a .csv or .json extension", path.display()),
+            ValidationError::UnknownContactSheetFormat(path) => write!(f, "cannot tell the image format of contact sheet {}, use an extension such as .png or .jpg", path.display()),
+            ValidationError::ZeroSheetCols => write!(f, "--sheet-cols must be at least 1"),
+            ValidationError::ZeroSheetEvery => write!(f, "--sheet-every must be at least 1"),
+            ValidationError::InvalidWebVttInterval(interval) => write!(f, "--webvtt-interval must be a positive number of seconds, not {}", interval),
+            ValidationError::ReferenceNotFound(path) => write!(f, "reference image {} does not exist", path.display()),
+            ValidationError::ReferenceNotSupported(mode) => write!(f, "--reference cannot be used with the {} comparison mode", mode),
+            ValidationError::InvertNotSupported(mode) => write!(f, "--invert only changes what the ssim and bestexposure comparison modes pick, not {}", mode),
+            ValidationError::RoiOutOfBounds(roi, width, height) => write!(f, "--roi {} does not fit within the {}x{} input", roi, width, height),
+            ValidationError::CropOutOfBounds(crop, width, height) => write!(f, "--crop {} does not fit within the {}x{} input", crop, width, height),
+        }
+    }
+}
+
+impl Error for ValidationError {}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ManifestFormat {
+    Csv,
+    Json,
+}
+
+impl ManifestFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_string_lossy().to_ascii_lowercase().as_str() {
+            "csv" => Some(ManifestFormat::Csv),
+            "json" => Some(ManifestFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ComparisonMode {
+    Noop,
+    Blockhash,
+    GradientHash,
+    MeanHash,
+    DctHash,
+    MSE,
+    MSERgb,
+    SSIM,
+    Sharpest,
+    BestExposure,
+    Motion,
+    Histogram,
+}
+
+/// Names `--comparison-mode` accepts, in the order they are listed in errors
+const COMPARISON_MODE_NAMES: &[&str] = &[
+    "noop", "blockhash", "gradienthash", "meanhash", "dcthash", "mse", "msergb", "ssim", "sharpest",
+    "bestexposure", "motion", "histogram",
+];
+
+#[derive(Debug)]
+pub struct ParseComparisonModeError(pub String);
+
+impl Display for ParseComparisonModeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown comparison mode '{}'; valid: {}", self.0, COMPARISON_MODE_NAMES.join(", "))
+    }
+}
+
+impl Error for ParseComparisonModeError {}
 
 impl FromStr for ComparisonMode {
     type Err = ParseComparisonModeError;
 
-    fn from_str(s: &str) -> Result<ComparisonMode, Self::Err> {
+    fn from_str(s: &str) -> Result<ComparisonMode, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "noop" => Ok(ComparisonMode::Noop),
+            "blockhash" => Ok(ComparisonMode::Blockhash),
+            "gradienthash" => Ok(ComparisonMode::GradientHash),
+            "meanhash" => Ok(ComparisonMode::MeanHash),
+            "dcthash" => Ok(ComparisonMode::DctHash),
+            "mse" => Ok(ComparisonMode::MSE),
+            "msergb" => Ok(ComparisonMode::MSERgb),
+            "ssim" => Ok(ComparisonMode::SSIM),
+            "sharpest" => Ok(ComparisonMode::Sharpest),
+            "bestexposure" => Ok(ComparisonMode::BestExposure),
+            "motion" => Ok(ComparisonMode::Motion),
+            "histogram" => Ok(ComparisonMode::Histogram),
+            _ => Err(ParseComparisonModeError(String::from(s))),
+        }
+    }
+}
+
+impl Display for ComparisonMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum OutputCodec {
+    Vp9,
+    Vp8,
+    H264,
+    Av1,
+}
+
+impl OutputCodec {
+    /// The container format used for this codec when it can't be inferred from the output path
+    pub fn default_format(&self) -> &'static str {
+        match self {
+            OutputCodec::Vp9 | OutputCodec::Vp8 => "webm",
+            OutputCodec::H264 => "mp4",
+            OutputCodec::Av1 => "matroska",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseOutputCodecError(pub String);
+
+impl Display for ParseOutputCodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown codec '{}', expected vp9, vp8, h264 or av1", self.0)
+    }
+}
+
+impl FromStr for OutputCodec {
+    type Err = ParseOutputCodecError;
+
+    fn from_str(s: &str) -> Result<OutputCodec, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "vp9" => Ok(OutputCodec::Vp9),
+            "vp8" => Ok(OutputCodec::Vp8),
+            "h264" => Ok(OutputCodec::H264),
+            "av1" => Ok(OutputCodec::Av1),
+            _ => Err(ParseOutputCodecError(String::from(s))),
+        }
+    }
+}
+
+impl Display for OutputCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum HwAccel {
+    Vaapi,
+    Nvenc,
+}
+
+#[derive(Debug)]
+pub struct ParseHwAccelError(pub String);
+
+impl Display for ParseHwAccelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown hardware acceleration '{}', expected vaapi or nvenc", self.0)
+    }
+}
+
+impl FromStr for HwAccel {
+    type Err = ParseHwAccelError;
+
+    fn from_str(s: &str) -> Result<HwAccel, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "vaapi" => Ok(HwAccel::Vaapi),
+            "nvenc" => Ok(HwAccel::Nvenc),
+            _ => Err(ParseHwAccelError(String::from(s))),
+        }
+    }
+}
+
+impl Display for HwAccel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ScaleAlgorithm {
+    Bilinear,
+    Bicubic,
+    Lanczos,
+    Neighbor,
+    Area,
+}
+
+#[derive(Debug)]
+pub struct ParseScaleAlgorithmError(pub String);
+
+impl Display for ParseScaleAlgorithmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown scaling algorithm '{}', expected bilinear, bicubic, lanczos, neighbor or area", self.0)
+    }
+}
+
+impl FromStr for ScaleAlgorithm {
+    type Err = ParseScaleAlgorithmError;
+
+    fn from_str(s: &str) -> Result<ScaleAlgorithm, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "bilinear" => Ok(ScaleAlgorithm::Bilinear),
+            "bicubic" => Ok(ScaleAlgorithm::Bicubic),
+            "lanczos" => Ok(ScaleAlgorithm::Lanczos),
+            "neighbor" => Ok(ScaleAlgorithm::Neighbor),
+            "area" => Ok(ScaleAlgorithm::Area),
+            _ => Err(ParseScaleAlgorithmError(String::from(s))),
+        }
+    }
+}
+
+impl Display for ScaleAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+#[derive(Debug)]
+pub struct ParseCornerError(pub String);
+
+impl Display for ParseCornerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown corner '{}', expected top-left, top-right, bottom-left or bottom-right", self.0)
+    }
+}
+
+impl FromStr for Corner {
+    type Err = ParseCornerError;
+
+    fn from_str(s: &str) -> Result<Corner, Self::Err> {
         match s.to_ascii_lowercase().as_str() {
-            "noop" => Ok(ComparisonMode::Noop),
-            "blockhash" => Ok(ComparisonMode::Blockhash),
-            "gradienthash" => Ok(ComparisonMode::GradientHash),
-            "meanhash" => Ok(ComparisonMode::MeanHash),
-            "mse" => Ok(ComparisonMode::MSE),
-            "ssim" => Ok(ComparisonMode::SSIM),
-            _ => Err(ParseComparisonModeError),
+            "top-left" => Ok(Corner::TopLeft),
+            "top-right" => Ok(Corner::TopRight),
+            "bottom-left" => Ok(Corner::BottomLeft),
+            "bottom-right" => Ok(Corner::BottomRight),
+            _ => Err(ParseCornerError(String::from(s))),
         }
     }
 }
 
-impl Display for ComparisonMode {
+impl Display for Corner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug)]
+pub struct ParseLogFormatError(pub String);
+
+impl Display for ParseLogFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown log format '{}', expected text or json", self.0)
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = ParseLogFormatError;
+
+    fn from_str(s: &str) -> Result<LogFormat, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(ParseLogFormatError(String::from(s))),
+        }
+    }
+}
+
+impl Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SelectMode {
+    Best,
+    Worst,
+}
+
+#[derive(Debug)]
+pub struct ParseSelectModeError(pub String);
+
+impl Display for ParseSelectModeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown selection '{}', expected best or worst", self.0)
+    }
+}
+
+impl FromStr for SelectMode {
+    type Err = ParseSelectModeError;
+
+    fn from_str(s: &str) -> Result<SelectMode, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "best" => Ok(SelectMode::Best),
+            "worst" => Ok(SelectMode::Worst),
+            _ => Err(ParseSelectModeError(String::from(s))),
+        }
+    }
+}
+
+impl Display for SelectMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Range of the luma and chroma values in a video
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ColorRange {
+    /// Limited range, eg. 16-235 for 8-bit luma
+    Tv,
+    /// Full range, eg. 0-255 for 8-bit luma
+    Pc,
+}
+
+#[derive(Debug)]
+pub struct ParseColorRangeError(pub String);
+
+impl Display for ParseColorRangeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown colour range '{}', expected tv or pc", self.0)
+    }
+}
+
+impl FromStr for ColorRange {
+    type Err = ParseColorRangeError;
+
+    fn from_str(s: &str) -> Result<ColorRange, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "tv" => Ok(ColorRange::Tv),
+            "pc" => Ok(ColorRange::Pc),
+            _ => Err(ParseColorRangeError(String::from(s))),
+        }
+    }
+}
+
+impl Display for ColorRange {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{:?}", self)
     }
 }
+
+/// Encoder speed presets, from fastest to slowest
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Preset {
+    Ultrafast,
+    Superfast,
+    Veryfast,
+    Faster,
+    Fast,
+    Medium,
+    Slow,
+    Slower,
+    Veryslow,
+}
+
+impl Preset {
+    /// Name of the preset as x264 knows it
+    pub fn name(&self) -> &'static str {
+        match self {
+            Preset::Ultrafast => "ultrafast",
+            Preset::Superfast => "superfast",
+            Preset::Veryfast => "veryfast",
+            Preset::Faster => "faster",
+            Preset::Fast => "fast",
+            Preset::Medium => "medium",
+            Preset::Slow => "slow",
+            Preset::Slower => "slower",
+            Preset::Veryslow => "veryslow",
+        }
+    }
+
+    /// How fast the preset is, from 8 for `ultrafast` down to 0 for `veryslow`, the same scale as
+    /// `cpu-used` for libvpx and libaom
+    pub fn speed(&self) -> u8 {
+        match self {
+            Preset::Ultrafast => 8,
+            Preset::Superfast => 7,
+            Preset::Veryfast => 6,
+            Preset::Faster => 5,
+            Preset::Fast => 4,
+            Preset::Medium => 3,
+            Preset::Slow => 2,
+            Preset::Slower => 1,
+            Preset::Veryslow => 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ParsePresetError(pub String);
+
+impl Display for ParsePresetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown preset '{}', expected ultrafast, superfast, veryfast, faster, fast, medium, slow, slower or veryslow", self.0)
+    }
+}
+
+impl FromStr for Preset {
+    type Err = ParsePresetError;
+
+    fn from_str(s: &str) -> Result<Preset, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ultrafast" => Ok(Preset::Ultrafast),
+            "superfast" => Ok(Preset::Superfast),
+            "veryfast" => Ok(Preset::Veryfast),
+            "faster" => Ok(Preset::Faster),
+            "fast" => Ok(Preset::Fast),
+            "medium" => Ok(Preset::Medium),
+            "slow" => Ok(Preset::Slow),
+            "slower" => Ok(Preset::Slower),
+            "veryslow" => Ok(Preset::Veryslow),
+            _ => Err(ParsePresetError(String::from(s))),
+        }
+    }
+}
+
+impl Display for Preset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownOutputFormatError(pub String);
+
+impl Display for UnknownOutputFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cannot infer the output format from extension '.{}', use --format to set it explicitly", self.0)
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseBitrateError(pub String);
+
+impl Display for ParseBitrateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid bitrate '{}', expected a number optionally followed by k, M or G", self.0)
+    }
+}
+
+/// Parses a bitrate such as `500k` or `8M` into bits per second. Suffixes are binary, so `1k` is
+/// 1024 bps; a number without a suffix is taken as bps.
+pub fn parse_bitrate(s: &str) -> Result<usize, ParseBitrateError> {
+    let s = s.trim();
+    let (digits, multiplier) = match s.chars().last() {
+        Some('k') | Some('K') => (&s[..s.len() - 1], 1024),
+        Some('m') | Some('M') => (&s[..s.len() - 1], 1024 * 1024),
+        Some('g') | Some('G') => (&s[..s.len() - 1], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+
+    digits.parse::<usize>().ok()
+        .and_then(|value| value.checked_mul(multiplier))
+        .ok_or_else(|| ParseBitrateError(String::from(s)))
+}
+
+#[derive(Debug)]
+pub struct ParseTimestampError(pub String);
+
+impl Display for ParseTimestampError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid time '{}', expected HH:MM:SS.mmm or a number of seconds", self.0)
+    }
+}
+
+/// Whether the path stands for standard input or output, ie. is `-`
+pub fn is_stdio(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// Whether the path is a URL such as `rtsp://camera/stream`, ie. starts with a scheme followed
+/// by `://`
+pub fn is_url(path: &Path) -> bool {
+    let pathname = path.to_string_lossy();
+    match pathname.find("://") {
+        Some(end) => {
+            let scheme = &pathname[..end];
+            // A single letter would be a Windows drive
+            scheme.len() > 1
+                && scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme.chars().all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')
+        },
+        None => false,
+    }
+}
+
+/// Returns the path of the first image written for an image sequence pattern such as
+/// `frames/%04d.png`, which ffmpeg numbers from 1. Other paths are returned as they are.
+fn first_sequence_path(path: &Path) -> PathBuf {
+    let pathname = path.to_string_lossy();
+    let start = match pathname.find('%') {
+        Some(start) => start,
+        None => return path.to_path_buf(),
+    };
+    let spec = &pathname[(start + 1)..];
+    let width_len = spec.find(|c: char| !c.is_ascii_digit()).unwrap_or_else(|| spec.len());
+    if !spec[width_len..].starts_with('d') {
+        return path.to_path_buf();
+    }
+    let width = spec[..width_len].parse::<usize>().unwrap_or(0);
+    PathBuf::from(format!("{}{:0width$}{}", &pathname[..start], 1, &spec[(width_len + 1)..], width = width))
+}
+
+/// Returns the path to hand to ffmpeg for the given input or output. On Windows, paths longer than
+/// the 260 characters most APIs accept are canonicalized, which gives them the `\\?\` prefix that
+/// lifts the limit. Elsewhere the path is returned as it is.
+#[cfg(windows)]
+pub fn ffmpeg_path(path: &Path) -> PathBuf {
+    use std::path::Component;
+
+    const MAX_PATH: usize = 260;
+    let absolute = match std::env::current_dir() {
+        Ok(current_dir) if path.is_relative() => current_dir.join(path),
+        _ => path.to_path_buf(),
+    };
+    let verbatim = match absolute.components().next() {
+        Some(Component::Prefix(prefix)) => prefix.kind().is_verbatim(),
+        _ => false,
+    };
+    if is_stdio(path) || is_url(path) || verbatim || absolute.as_os_str().len() < MAX_PATH {
+        return path.to_path_buf();
+    }
+
+    // Outputs usually don't exist yet, in which case the directory they go in is canonicalized
+    let canonical = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(file_name)) if !path.exists() => std::fs::canonicalize(parent).map(|parent| parent.join(file_name)),
+        _ => std::fs::canonicalize(path),
+    };
+    canonical.unwrap_or(absolute)
+}
+
+/// Returns the path to hand to ffmpeg for the given input or output. On Windows, paths longer than
+/// the 260 characters most APIs accept are canonicalized, which gives them the `\\?\` prefix that
+/// lifts the limit. Elsewhere the path is returned as it is.
+#[cfg(not(windows))]
+pub fn ffmpeg_path(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+/// Parses a point in time given as `HH:MM:SS.mmm`, `MM:SS.mmm` or a plain number of seconds
+pub fn parse_timestamp(s: &str) -> Result<Duration, ParseTimestampError> {
+    let err = || ParseTimestampError(String::from(s));
+
+    let mut parts = s.trim().rsplit(':');
+    let seconds = parts.next().and_then(|p| p.parse::<f64>().ok()).ok_or_else(err)?;
+    if !seconds.is_finite() || seconds < 0.0 {
+        return Err(err());
+    }
+
+    let mut total = seconds;
+    for multiplier in &[60.0, 3600.0] {
+        match parts.next() {
+            Some(part) => total += part.parse::<u32>().map_err(|_| err())? as f64 * multiplier,
+            None => break,
+        }
+    }
+
+    if parts.next().is_some() {
+        return Err(err());
+    }
+
+    Ok(Duration::from_secs_f64(total))
+}
+
+#[derive(Debug)]
+pub struct ParseOptionError(pub String);
+
+impl Display for ParseOptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid option '{}', expected key=value", self.0)
+    }
+}
+
+/// Parses an ffmpeg option given as `key=value`. The value may be empty, the key may not.
+pub fn parse_option(s: &str) -> Result<(String, String), ParseOptionError> {
+    match s.find('=').map(|separator| (s[..separator].trim(), &s[separator + 1..])) {
+        Some((key, value)) if !key.is_empty() => Ok((String::from(key), String::from(value))),
+        _ => Err(ParseOptionError(String::from(s))),
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseFrameRateError(pub String);
+
+impl Display for ParseFrameRateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid frame rate '{}', expected a positive number", self.0)
+    }
+}
+
+/// Parses a frame rate, which must be a positive number
+pub fn parse_frame_rate(s: &str) -> Result<f64, ParseFrameRateError> {
+    match s.trim().parse::<f64>() {
+        Ok(fps) if fps.is_finite() && fps > 0.0 => Ok(fps),
+        _ => Err(ParseFrameRateError(String::from(s))),
+    }
+}
+
+/// A rectangle within a frame, in pixels
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    /// Whether the rectangle lies entirely within a frame of the given size
+    pub fn fits(&self, width: u32, height: u32) -> bool {
+        u64::from(self.x) + u64::from(self.width) <= u64::from(width)
+            && u64::from(self.y) + u64::from(self.height) <= u64::from(height)
+    }
+}
+
+impl Display for Rect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{},{}", self.x, self.y, self.width, self.height)
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseRectError(pub String);
+
+impl Display for ParseRectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid rectangle '{}', expected x,y,width,height with a non-zero width and height", self.0)
+    }
+}
+
+impl FromStr for Rect {
+    type Err = ParseRectError;
+
+    fn from_str(s: &str) -> Result<Rect, Self::Err> {
+        let err = || ParseRectError(String::from(s));
+
+        let parts = s.split(',').map(|part| part.trim().parse::<u32>()).collect::<Result<Vec<_>, _>>().map_err(|_| err())?;
+        match parts.as_slice() {
+            &[x, y, width, height] if width > 0 && height > 0 => Ok(Rect { x, y, width, height }),
+            _ => Err(err()),
+        }
+    }
+}
+
+/// Dimensions of a frame, in pixels
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Size {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseSizeError(pub String);
+
+impl Display for ParseSizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid size '{}', expected WIDTHxHEIGHT with a non-zero width and height", self.0)
+    }
+}
+
+impl FromStr for Size {
+    type Err = ParseSizeError;
+
+    fn from_str(s: &str) -> Result<Size, Self::Err> {
+        let err = || ParseSizeError(String::from(s));
+
+        let mut parts = s.trim().splitn(2, |c| c == 'x' || c == 'X');
+        let width = parts.next().and_then(|p| p.trim().parse::<u32>().ok()).ok_or_else(err)?;
+        let height = parts.next().and_then(|p| p.trim().parse::<u32>().ok()).ok_or_else(err)?;
+        if width == 0 || height == 0 {
+            return Err(err());
+        }
+        Ok(Size { width, height })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crate_path(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join(name)
+    }
+
+    /// A request which passes validation: it reads a file which exists and writes to one which
+    /// doesn't
+    fn valid_request() -> Request {
+        let mut request = Request::new();
+        request.set_input_path(&crate_path("Cargo.toml"))
+            .set_output_path(&crate_path("no-such-output.webm"));
+        request
+    }
+
+    fn validate_with<F: FnOnce(&mut Request)>(change: F) -> Result<(), ValidationError> {
+        let mut request = valid_request();
+        change(&mut request);
+        request.validate()
+    }
+
+    #[test]
+    fn validate_accepts_the_defaults() {
+        assert!(valid_request().validate().is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_bad_window_settings() {
+        assert!(matches!(validate_with(|r| r.window_size = 0), Err(ValidationError::ZeroWindowSize)));
+        assert!(matches!(validate_with(|r| r.frames_per_window = 0), Err(ValidationError::ZeroFramesPerWindow)));
+        assert!(matches!(validate_with(|r| r.sample_every = 0), Err(ValidationError::ZeroSampleEvery)));
+        assert!(matches!(validate_with(|r| r.max_output_frames = Some(0)), Err(ValidationError::ZeroMaxOutputFrames)));
+        assert!(matches!(validate_with(|r| r.target_duration = Some(-1.0)), Err(ValidationError::InvalidTargetDuration(_))));
+        assert!(matches!(validate_with(|r| r.analyze_duration = Some(0.0)), Err(ValidationError::InvalidAnalyzeDuration(_))));
+        assert!(matches!(validate_with(|r| {
+            r.target_duration = Some(10.0);
+            r.window_duration = Some(1.0);
+        }), Err(ValidationError::TargetDurationWithWindowDuration)));
+        assert!(matches!(validate_with(|r| r.speed = Some(std::f64::NAN)), Err(ValidationError::InvalidSpeed(_))));
+        assert!(matches!(validate_with(|r| {
+            r.speed = Some(10.0);
+            r.window_duration = Some(1.0);
+        }), Err(ValidationError::SpeedWithWindowDuration)));
+        assert!(matches!(validate_with(|r| {
+            r.speed = Some(10.0);
+            r.target_duration = Some(10.0);
+        }), Err(ValidationError::SpeedWithTargetDuration)));
+        assert!(matches!(validate_with(|r| {
+            r.window_size = 4;
+            r.window_overlap = 4;
+        }), Err(ValidationError::OverlapNotSmallerThanWindow)));
+        // The window size the overlap has to fit in is only known once the input is opened
+        assert!(validate_with(|r| {
+            r.window_size = 4;
+            r.window_overlap = 4;
+            r.target_duration = Some(10.0);
+        }).is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_bad_paths() {
+        assert!(matches!(validate_with(|r| r.input_paths.clear()), Err(ValidationError::NoInput)));
+        assert!(matches!(validate_with(|r| r.output_path = crate_path("Cargo.toml")), Err(ValidationError::SameInputAndOutput)));
+        assert!(matches!(validate_with(|r| r.input_paths = vec![crate_path("no-such-input.mp4")]), Err(ValidationError::InputNotFound(_))));
+        assert!(matches!(validate_with(|r| r.output_path = crate_path("src/lib.rs")), Err(ValidationError::OutputExists(_))));
+        assert!(validate_with(|r| {
+            r.output_path = crate_path("src/lib.rs");
+            r.force = true;
+        }).is_ok());
+        assert!(matches!(validate_with(|r| r.manifest = Some(PathBuf::from("manifest.txt"))), Err(ValidationError::UnknownManifestFormat(_))));
+        assert!(matches!(validate_with(|r| r.contact_sheet = Some(PathBuf::from("sheet.unknown"))), Err(ValidationError::UnknownContactSheetFormat(_))));
+        assert!(matches!(validate_with(|r| r.reference = Some(crate_path("no-such-reference.png"))), Err(ValidationError::ReferenceNotFound(_))));
+    }
+
+    #[test]
+    fn validate_rejects_options_which_do_not_go_together() {
+        assert!(matches!(validate_with(|r| {
+            r.keep_audio = true;
+            r.input_paths.push(crate_path("Cargo.toml"));
+        }), Err(ValidationError::AudioFromMultipleInputs)));
+        assert!(matches!(validate_with(|r| {
+            r.keep_audio = true;
+            r.two_pass = true;
+        }), Err(ValidationError::AudioWithTwoPass)));
+        assert!(matches!(validate_with(|r| {
+            r.real_time_pacing = true;
+            r.blend = 1;
+        }), Err(ValidationError::RealTimePacingWithBlend)));
+        assert!(matches!(validate_with(|r| {
+            r.real_time_pacing = true;
+            r.boomerang = true;
+        }), Err(ValidationError::RealTimePacingWithBoomerang)));
+        assert!(matches!(validate_with(|r| {
+            r.real_time_pacing = true;
+            r.webvtt = Some(PathBuf::from("thumbs"));
+        }), Err(ValidationError::RealTimePacingWithWebVtt)));
+        assert!(matches!(validate_with(|r| {
+            r.two_pass = true;
+            r.output_path = PathBuf::from("-");
+        }), Err(ValidationError::TwoPassToStdout)));
+        assert!(matches!(validate_with(|r| {
+            r.start_time = Some(Duration::from_secs(10));
+            r.end_time = Some(Duration::from_secs(5));
+        }), Err(ValidationError::StartAfterEnd)));
+    }
+
+    #[test]
+    fn validate_rejects_options_the_comparison_mode_cannot_use() {
+        assert!(matches!(validate_with(|r| r.bit_depth = 12), Err(ValidationError::UnsupportedBitDepth(12))));
+        assert!(matches!(validate_with(|r| {
+            r.bit_depth = 10;
+            r.comparison_mode = ComparisonMode::SSIM;
+        }), Err(ValidationError::BitDepthNotSupported(ComparisonMode::SSIM))));
+        assert!(matches!(validate_with(|r| {
+            r.comparison_mode = ComparisonMode::Sharpest;
+            r.reference = Some(crate_path("Cargo.toml"));
+        }), Err(ValidationError::ReferenceNotSupported(ComparisonMode::Sharpest))));
+        assert!(matches!(validate_with(|r| r.invert = true), Err(ValidationError::InvertNotSupported(ComparisonMode::MSE))));
+        assert!(validate_with(|r| {
+            r.invert = true;
+            r.comparison_mode = ComparisonMode::BestExposure;
+        }).is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_zero_and_out_of_range_values() {
+        assert!(matches!(validate_with(|r| r.gop = 0), Err(ValidationError::ZeroGop)));
+        assert!(matches!(validate_with(|r| r.hash_size = 12), Err(ValidationError::InvalidHashSize(12))));
+        assert!(matches!(validate_with(|r| r.sheet_cols = 0), Err(ValidationError::ZeroSheetCols)));
+        assert!(matches!(validate_with(|r| r.sheet_every = Some(0)), Err(ValidationError::ZeroSheetEvery)));
+        assert!(matches!(validate_with(|r| r.webvtt_interval = 0.0), Err(ValidationError::InvalidWebVttInterval(_))));
+    }
+
+    #[test]
+    fn validate_for_frame_size_rejects_rectangles_outside_the_frame() {
+        let mut request = valid_request();
+        request.roi = Some(Rect { x: 600, y: 0, width: 100, height: 100 });
+        assert!(matches!(request.validate_for_frame_size(640, 480), Err(ValidationError::RoiOutOfBounds(..))));
+        assert!(request.validate_for_frame_size(700, 480).is_ok());
+
+        let mut request = valid_request();
+        request.crop = Some(Rect { x: 0, y: 400, width: 640, height: 100 });
+        assert!(matches!(request.validate_for_frame_size(640, 480), Err(ValidationError::CropOutOfBounds(..))));
+    }
+
+    #[test]
+    fn parse_bitrate_takes_binary_suffixes() {
+        assert_eq!(parse_bitrate("8M").unwrap(), 8_388_608);
+        assert_eq!(parse_bitrate("500k").unwrap(), 512_000);
+        assert_eq!(parse_bitrate("2G").unwrap(), 2_147_483_648);
+        assert_eq!(parse_bitrate("1500000").unwrap(), 1_500_000);
+        assert_eq!(parse_bitrate(" 8m ").unwrap(), 8_388_608);
+    }
+
+    #[test]
+    fn parse_bitrate_rejects_unknown_suffixes() {
+        assert!(parse_bitrate("8X").is_err());
+        assert!(parse_bitrate("8MB").is_err());
+        assert!(parse_bitrate("k").is_err());
+        assert!(parse_bitrate("").is_err());
+    }
+
+    #[test]
+    fn parse_comparison_mode_error_names_the_value_and_the_valid_modes() {
+        let error = "blurry".parse::<ComparisonMode>().unwrap_err();
+        assert_eq!(error.to_string(), "unknown comparison mode 'blurry'; valid: noop, blockhash, gradienthash, meanhash, dcthash, mse, msergb, ssim, sharpest, bestexposure, motion, histogram");
+    }
+
+    #[test]
+    fn every_listed_comparison_mode_parses() {
+        for name in COMPARISON_MODE_NAMES {
+            assert!(name.parse::<ComparisonMode>().is_ok(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn parse_errors_name_the_bad_value() {
+        assert_eq!("h265".parse::<OutputCodec>().unwrap_err().to_string(), "unknown codec 'h265', expected vp9, vp8, h264 or av1");
+        assert_eq!(parse_bitrate("8X").unwrap_err().to_string(), "invalid bitrate '8X', expected a number optionally followed by k, M or G");
+        assert_eq!("640x0".parse::<Size>().unwrap_err().to_string(), "invalid size '640x0', expected WIDTHxHEIGHT with a non-zero width and height");
+    }
+
+    /// What the decoder would say about a 25 fps input of the given number of frames, read in
+    /// windows of 25 frames
+    fn video_info(total_frames: Option<u64>) -> VideoInfo<Rational> {
+        VideoInfo {
+            width: 640,
+            height: 480,
+            frame_rate: Rational::new(25, 1),
+            timebase: Rational::new(1, 25),
+            total_frames,
+            window_size: 25,
+            window_overlap: 0,
+            decoded_pixel_format: ffmpeg::format::Pixel::YUV420P,
+            color_space: ffmpeg::util::color::Space::BT709,
+            color_primaries: ffmpeg::util::color::Primaries::BT709,
+            color_transfer: ffmpeg::util::color::TransferCharacteristic::BT709,
+        }
+    }
+
+    fn estimate_with<F: FnOnce(&mut Request)>(total_frames: Option<u64>, change: F) -> Estimate {
+        let mut request = Request::new();
+        request.key_frames_only = false;
+        change(&mut request);
+        request.estimate(&video_info(total_frames))
+    }
+
+    #[test]
+    fn estimate_picks_a_frame_from_every_window() {
+        let estimate = estimate_with(Some(250), |_| ());
+        assert_eq!(estimate.picked_frames, Some(10));
+        assert_eq!(estimate.output_frames, Some(10));
+        assert_eq!(estimate.duration, Some(0.4));
+
+        // A window left over at the end still gets a frame picked from it
+        assert_eq!(estimate_with(Some(260), |_| ()).picked_frames, Some(11));
+        assert_eq!(estimate_with(Some(250), |r| r.frames_per_window = 2).picked_frames, Some(20));
+        assert_eq!(estimate_with(Some(250), |r| r.frame_skip = 1).picked_frames, Some(5));
+        assert_eq!(estimate_with(Some(250), |r| r.max_output_frames = Some(3)).picked_frames, Some(3));
+    }
+
+    #[test]
+    fn estimate_counts_the_frames_added_to_the_output() {
+        assert_eq!(estimate_with(Some(250), |r| r.boomerang = true).output_frames, Some(18));
+        assert_eq!(estimate_with(Some(250), |r| r.blend = 2).output_frames, Some(28));
+        let estimate = estimate_with(Some(250), |r| {
+            r.boomerang = true;
+            r.blend = 1;
+            r.output_fps = Some(5.0);
+        });
+        assert_eq!(estimate.picked_frames, Some(10));
+        assert_eq!(estimate.output_frames, Some(35));
+        assert_eq!(estimate.duration, Some(7.0));
+    }
+
+    #[test]
+    fn estimate_gives_nothing_when_the_output_length_cannot_be_told() {
+        assert!(estimate_with(None, |_| ()).output_frames.is_none());
+        assert!(estimate_with(Some(250), |r| r.key_frames_only = true).output_frames.is_none());
+        let estimate = estimate_with(Some(250), |r| r.comparison_mode = ComparisonMode::Motion);
+        assert!(estimate.picked_frames.is_none());
+        assert!(estimate.duration.is_none());
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn long_paths_are_handed_to_ffmpeg_with_the_verbatim_prefix() {
+        // Directories this deep can only be made through a path which already has the prefix
+        let root = std::fs::canonicalize(std::env::temp_dir()).unwrap()
+            .join(format!("timelapse-rs-test-long-path-{}", std::process::id()));
+        let long_dir = (0..6).fold(root.clone(), |dir, i| dir.join(format!("{}-{}", i, "d".repeat(50))));
+        std::fs::create_dir_all(&long_dir).unwrap();
+        let plain_dir = PathBuf::from(long_dir.to_string_lossy().trim_start_matches(r"\\?\"));
+        assert!(plain_dir.as_os_str().len() > 260);
+
+        let output = ffmpeg_path(&plain_dir.join("output.mp4"));
+        assert!(output.to_string_lossy().starts_with(r"\\?\"), "{}", output.display());
+        assert!(output.ends_with("output.mp4"));
+        // Short paths are left as they are
+        assert_eq!(ffmpeg_path(Path::new("output.mp4")), PathBuf::from("output.mp4"));
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn options_are_parsed_as_key_and_value() {
+        assert_eq!(parse_option("rtsp_transport=tcp").unwrap(), (String::from("rtsp_transport"), String::from("tcp")));
+        assert_eq!(parse_option(" tune =film").unwrap(), (String::from("tune"), String::from("film")));
+        // Only the first = separates the key from the value, which may be empty
+        assert_eq!(parse_option("x264-params=keyint=60").unwrap(), (String::from("x264-params"), String::from("keyint=60")));
+        assert_eq!(parse_option("metadata=").unwrap(), (String::from("metadata"), String::new()));
+
+        assert!(parse_option("novalue").is_err());
+        assert!(parse_option("=x").is_err());
+        assert!(parse_option(" =x").is_err());
+    }
+}
@@ -0,0 +1,102 @@
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use ffmpeg::util::frame::Video as VideoFrame;
+
+use crate::contact_sheet::ContactSheet;
+use crate::error::TimelapseError;
+use crate::request::Request;
+
+/// Number of thumbnails in each row of the sprite sheet
+const SPRITE_COLUMNS: u32 = 10;
+
+/// Collects thumbnails evenly spread over the output and writes them as a sprite sheet along with
+/// a WebVTT track telling a player which part of the sheet to show for each part of the output.
+/// It is given every frame the encoder writes, so the thumbnails look the way the output does.
+pub struct WebVtt {
+    sprites: ContactSheet,
+    output_fps: f64,
+}
+
+impl WebVtt {
+    /// Creates a track for the request, where the output plays back at `output_fps`
+    pub fn new(request: &Request, output_fps: f64) -> Self {
+        let every = (request.webvtt_interval * output_fps).round().max(1.0) as u64;
+        Self {
+            sprites: ContactSheet::with_spacing(SPRITE_COLUMNS, every),
+            output_fps,
+        }
+    }
+
+    /// Takes a thumbnail of the output frame if one is due at its place in the output
+    pub fn add(&mut self, frame: &VideoFrame) -> Result<(), TimelapseError> {
+        self.sprites.add(frame)
+    }
+
+    /// Writes the sprite sheet and the track next to each other, at the base path with `.jpg` and
+    /// `.vtt` added
+    pub fn save(&self, base_path: &Path) -> Result<(), TimelapseError> {
+        if self.sprites.thumbnail_count() == 0 {
+            return Ok(());
+        }
+
+        let sprite_path = with_added_extension(base_path, "jpg");
+        self.sprites.save(&sprite_path)?;
+        // The track refers to the sheet relative to itself, so that the two can be moved together
+        let sprite_name = sprite_path.file_name().unwrap().to_string_lossy();
+
+        let frames_seen = self.sprites.frames_seen();
+        let mut writer = BufWriter::new(File::create(with_added_extension(base_path, "vtt"))?);
+        writeln!(writer, "WEBVTT")?;
+        for i in 0..self.sprites.thumbnail_count() {
+            let first_frame = i as u64 * self.sprites.every();
+            let end_frame = (first_frame + self.sprites.every()).min(frames_seen);
+            let start = first_frame as f64 / self.output_fps;
+            let end = end_frame as f64 / self.output_fps;
+            let rect = self.sprites.thumbnail_rect(i).unwrap();
+            writeln!(writer)?;
+            writeln!(writer, "{} --> {}", format_cue_time(start), format_cue_time(end))?;
+            writeln!(writer, "{}#xywh={},{},{},{}", sprite_name, rect.x, rect.y, rect.width, rect.height)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+fn with_added_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(".");
+    path.push(extension);
+    PathBuf::from(path)
+}
+
+/// Formats seconds as a WebVTT timestamp, eg. `00:01:02.500`
+fn format_cue_time(seconds: f64) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+    format!("{:02}:{:02}:{:02}.{:03}", millis / 3_600_000, millis / 60_000 % 60, millis / 1000 % 60, millis % 1000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_cue_time_pads_every_field() {
+        assert_eq!(format_cue_time(0.0), "00:00:00.000");
+        assert_eq!(format_cue_time(62.5), "00:01:02.500");
+        assert_eq!(format_cue_time(3723.004), "01:02:03.004");
+    }
+
+    #[test]
+    fn format_cue_time_rounds_to_the_millisecond() {
+        assert_eq!(format_cue_time(1.0 / 3.0), "00:00:00.333");
+        assert_eq!(format_cue_time(59.9996), "00:01:00.000");
+    }
+
+    #[test]
+    fn with_added_extension_keeps_the_existing_one() {
+        assert_eq!(with_added_extension(Path::new("out/thumbs.webm"), "vtt"), PathBuf::from("out/thumbs.webm.vtt"));
+    }
+}
@@ -0,0 +1,51 @@
+mod common;
+
+use std::path::Path;
+
+use rayon::ThreadPoolBuilder;
+use timelapse_rs::request::{ComparisonMode, Request};
+use timelapse_rs::ProgressInfo;
+
+/// Runs the request on a pool of the given number of threads, and returns the input frame picked
+/// for each output frame along with the number of frames written at each progress report
+fn run_on_threads(request: &Request, threads: usize) -> (Vec<u64>, Vec<u64>) {
+    let pool = ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+    let mut reported = Vec::new();
+    let mut report = |progress: ProgressInfo| reported.push(progress.frames_written);
+    let stats = pool.install(|| timelapse_rs::run(request, Some(&mut report))).unwrap();
+    let picked = stats.selections.iter().map(|selection| selection.input_index).collect();
+    (picked, reported)
+}
+
+fn request_for(input: &Path, output: &Path, comparison_mode: ComparisonMode) -> Request {
+    let mut request = Request::new();
+    request.set_input_path(input)
+        .set_output_path(output)
+        .set_window_size(4)
+        .set_frames_per_window(2)
+        .set_comparison_mode(comparison_mode)
+        .set_dry_run(true);
+    request
+}
+
+#[test]
+fn parallel_runs_pick_the_same_frames_as_serial_ones() {
+    let dir = common::TempDir::new("batch-selection");
+    let input = common::write_frames(dir.path(), 30, 64, 48);
+    let output = dir.path().join("output.webm");
+
+    for &comparison_mode in [ComparisonMode::Sharpest, ComparisonMode::BestExposure].iter() {
+        let request = request_for(&input, &output, comparison_mode);
+        let (serial, serial_reported) = run_on_threads(&request, 1);
+        let (parallel, parallel_reported) = run_on_threads(&request, 4);
+
+        // 30 frames make 8 windows, the last one of 2 frames
+        assert_eq!(serial.len(), 16, "{:?}", comparison_mode);
+        assert_eq!(parallel, serial, "{:?}", comparison_mode);
+
+        // Progress is reported before every frame written, however the windows are batched
+        let expected = (0..16).collect::<Vec<u64>>();
+        assert_eq!(serial_reported, expected, "{:?}", comparison_mode);
+        assert_eq!(parallel_reported, expected, "{:?}", comparison_mode);
+    }
+}
@@ -0,0 +1,21 @@
+mod common;
+
+use timelapse_rs::request::Request;
+
+#[test]
+fn boomerang_plays_the_frames_back_without_repeating_either_end() {
+    let dir = common::TempDir::new("boomerang");
+    let input = common::write_frames(dir.path(), 30, 64, 48);
+    let output = dir.path().join("output.webm");
+
+    let mut request = Request::new();
+    request.set_input_path(&input)
+        .set_output_path(&output)
+        .set_window_size(3)
+        .set_boomerang(true);
+    let stats = timelapse_rs::run(&request, None).unwrap();
+
+    // 10 frames forwards, then 8 of them backwards
+    assert_eq!(stats.selections.len(), 10);
+    assert_eq!(common::count_decoded_frames(&output), 18);
+}
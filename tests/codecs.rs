@@ -0,0 +1,37 @@
+mod common;
+
+use ffmpeg_next::codec::{self, Id as CodecId};
+
+use timelapse_rs::request::{OutputCodec, Request};
+
+#[test]
+fn frames_are_encoded_with_every_available_codec() {
+    ffmpeg_next::init().unwrap();
+    let dir = common::TempDir::new("codecs");
+    let input = common::write_frames(dir.path(), 30, 64, 48);
+
+    let codecs = [
+        (OutputCodec::Vp9, CodecId::VP9, "webm"),
+        (OutputCodec::Vp8, CodecId::VP8, "webm"),
+        (OutputCodec::H264, CodecId::H264, "mp4"),
+        (OutputCodec::Av1, CodecId::AV1, "mkv"),
+    ];
+    for &(codec, codec_id, extension) in codecs.iter() {
+        // Not every build of ffmpeg comes with every encoder, and the output is read back to count
+        // its frames
+        if codec::encoder::find(codec_id).is_none() || codec::decoder::find(codec_id).is_none() {
+            println!("skipping {}, which this build of ffmpeg can't encode or decode", codec);
+            continue;
+        }
+
+        let output = dir.path().join(format!("output-{}.{}", codec, extension));
+        let mut request = Request::new();
+        request.set_input_path(&input)
+            .set_output_path(&output)
+            .set_window_size(3)
+            .set_codec(codec);
+        let stats = timelapse_rs::run(&request, None).unwrap();
+        assert_eq!(stats.frames_written, 10, "{}", codec);
+        assert_eq!(common::count_decoded_frames(&output), 10, "{}", codec);
+    }
+}
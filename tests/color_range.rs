@@ -0,0 +1,32 @@
+mod common;
+
+use std::path::Path;
+
+use ffmpeg_next::util::color;
+
+use timelapse_rs::request::{ColorRange, Request};
+
+/// Encodes a few frames with the given colour range and returns the range the output says it has
+fn output_color_range(dir: &Path, color_range: ColorRange) -> color::Range {
+    let input = common::write_frames(dir, 6, 64, 48);
+    let output = dir.join(format!("output-{}.webm", color_range));
+    let mut request = Request::new();
+    request.set_input_path(&input)
+        .set_output_path(&output)
+        .set_window_size(2)
+        .set_color_range(color_range);
+    timelapse_rs::run(&request, None).unwrap();
+
+    ffmpeg_next::init().unwrap();
+    let input = ffmpeg_next::format::input(&output).unwrap();
+    let stream = input.streams().best(ffmpeg_next::media::Type::Video).unwrap();
+    let decoder = stream.codec().decoder().video().unwrap();
+    decoder.color_range()
+}
+
+#[test]
+fn output_has_the_colour_range_asked_for() {
+    let dir = common::TempDir::new("color-range");
+    assert_eq!(output_color_range(dir.path(), ColorRange::Pc), color::Range::JPEG);
+    assert_eq!(output_color_range(dir.path(), ColorRange::Tv), color::Range::MPEG);
+}
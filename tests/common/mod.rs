@@ -0,0 +1,82 @@
+// Each test file only uses some of these
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use image::{Rgb, RgbImage};
+
+/// A directory of its own under the temporary directory, removed again when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("timelapse-rs-test-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Writes `count` PNG frames of a diagonal gradient which moves along by a few pixels from each
+/// frame to the next, and returns the pattern to read them back as a video with
+pub fn write_frames(dir: &Path, count: u32, width: u32, height: u32) -> PathBuf {
+    for i in 0..count {
+        let image = RgbImage::from_fn(width, height, |x, y| {
+            let value = ((x + y + i * 3) % 256) as u8;
+            Rgb([value, value / 2, 255 - value])
+        });
+        image.save(dir.join(format!("frame-{:03}.png", i + 1))).unwrap();
+    }
+    dir.join("frame-%03d.png")
+}
+
+/// Writes `count` PNG frames which are all the same, and returns the pattern to read them back as
+/// a video with
+pub fn write_still_frames(dir: &Path, count: u32, width: u32, height: u32) -> PathBuf {
+    let image = RgbImage::from_fn(width, height, |x, y| {
+        let value = ((x + y) % 256) as u8;
+        Rgb([value, value / 2, 255 - value])
+    });
+    for i in 0..count {
+        image.save(dir.join(format!("still-{:03}.png", i + 1))).unwrap();
+    }
+    dir.join("still-%03d.png")
+}
+
+/// Decodes every frame of the first video stream in the file and returns how many there were
+pub fn count_decoded_frames(path: &Path) -> usize {
+    ffmpeg_next::init().unwrap();
+    let mut input = ffmpeg_next::format::input(&path).unwrap();
+    let stream = input.streams().best(ffmpeg_next::media::Type::Video).unwrap();
+    let stream_index = stream.index();
+    let mut decoder = stream.codec().decoder().video().unwrap();
+
+    let mut frames = 0;
+    let mut frame = ffmpeg_next::util::frame::Video::empty();
+    for (stream, packet) in input.packets() {
+        if stream.index() != stream_index {
+            continue;
+        }
+        decoder.send_packet(&packet).unwrap();
+        while decoder.receive_frame(&mut frame).is_ok() {
+            frames += 1;
+        }
+    }
+    decoder.send_eof().unwrap();
+    while decoder.receive_frame(&mut frame).is_ok() {
+        frames += 1;
+    }
+    frames
+}
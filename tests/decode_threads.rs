@@ -0,0 +1,29 @@
+mod common;
+
+use timelapse_rs::request::Request;
+
+fn frames_read(first: &std::path::Path, second: &std::path::Path, output: &std::path::Path, decode_threads: usize) -> u64 {
+    let mut request = Request::new();
+    request.set_input_path(first)
+        .add_input_path(second)
+        .set_output_path(output)
+        .set_window_size(2)
+        .set_decode_threads(decode_threads)
+        .set_dry_run(true);
+    timelapse_rs::run(&request, None).unwrap().frames_read
+}
+
+#[test]
+fn every_frame_of_every_input_is_read_whatever_the_number_of_threads() {
+    let first_dir = common::TempDir::new("decode-threads-first");
+    let second_dir = common::TempDir::new("decode-threads-second");
+    let first = common::write_frames(first_dir.path(), 12, 64, 48);
+    let second = common::write_frames(second_dir.path(), 8, 64, 48);
+    let output = first_dir.path().join("output.webm");
+
+    let single_threaded = frames_read(&first, &second, &output, 1);
+    assert_eq!(single_threaded, 20);
+    assert_eq!(frames_read(&first, &second, &output, 4), single_threaded);
+    // 0 lets ffmpeg pick, which is what the command line defaults to
+    assert_eq!(frames_read(&first, &second, &output, 0), single_threaded);
+}
@@ -0,0 +1,34 @@
+mod common;
+
+use std::path::Path;
+use std::process::Command;
+
+/// Runs timelapse-rs with `--cache-dir` and returns what it printed
+fn run_with_cache(input: &Path, output: &Path, cache_dir: &Path) -> String {
+    let result = Command::new(env!("CARGO_BIN_EXE_timelapse-rs"))
+        .arg(input)
+        .arg(output)
+        .args(&["--window-size", "3", "--codec", "vp9", "-v", "--cache-dir"])
+        .arg(cache_dir)
+        .output()
+        .unwrap();
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    String::from_utf8_lossy(&result.stdout).into_owned()
+}
+
+#[test]
+fn second_run_reads_the_picked_frames_from_the_cache() {
+    let dir = common::TempDir::new("frame-cache");
+    let input = common::write_frames(dir.path(), 30, 64, 48);
+    let cache_dir = dir.path().join("cache");
+    let first_output = dir.path().join("first.webm");
+    let second_output = dir.path().join("second.webm");
+
+    let first_log = run_with_cache(&input, &first_output, &cache_dir);
+    assert!(!first_log.contains("from the cache"), "{}", first_log);
+    let second_log = run_with_cache(&input, &second_output, &cache_dir);
+    assert!(second_log.contains("Reading the picked frames from the cache at"), "{}", second_log);
+
+    assert_eq!(common::count_decoded_frames(&first_output), 10);
+    assert_eq!(common::count_decoded_frames(&second_output), 10);
+}
@@ -0,0 +1,30 @@
+mod common;
+
+use std::path::Path;
+
+use timelapse_rs::request::{ComparisonMode, Request};
+
+fn frames_emitted(input: &Path, output: &Path) -> usize {
+    let mut request = Request::new();
+    request.set_input_path(input)
+        .set_output_path(output)
+        .set_comparison_mode(ComparisonMode::Motion)
+        // The gradient only moves along by about one level of luma from each frame to the next
+        .set_motion_threshold(0.5)
+        .set_dry_run(true);
+    timelapse_rs::run(&request, None).unwrap().selections.len()
+}
+
+#[test]
+fn static_clip_gives_only_its_first_frame() {
+    let dir = common::TempDir::new("motion-static");
+    let input = common::write_still_frames(dir.path(), 20, 64, 48);
+    assert_eq!(frames_emitted(&input, &dir.path().join("output.webm")), 1);
+}
+
+#[test]
+fn changing_clip_gives_every_frame() {
+    let dir = common::TempDir::new("motion-changing");
+    let input = common::write_frames(dir.path(), 20, 64, 48);
+    assert_eq!(frames_emitted(&input, &dir.path().join("output.webm")), 20);
+}
@@ -0,0 +1,24 @@
+mod common;
+
+use std::fs;
+use std::process::Command;
+
+#[test]
+fn output_written_to_stdout_is_a_valid_stream() {
+    let dir = common::TempDir::new("stdout");
+    let input = common::write_frames(dir.path(), 20, 64, 48);
+
+    // -vv makes sure there are messages which could end up in the stream
+    let output = Command::new(env!("CARGO_BIN_EXE_timelapse-rs"))
+        .arg(&input)
+        .arg("-")
+        .args(&["--window-size", "2", "--codec", "vp9", "-vv"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!output.stderr.is_empty());
+
+    let stream_path = dir.path().join("stdout.webm");
+    fs::write(&stream_path, &output.stdout).unwrap();
+    assert_eq!(common::count_decoded_frames(&stream_path), 10);
+}
@@ -0,0 +1,31 @@
+mod common;
+
+use std::path::Path;
+use std::time::Duration;
+
+use timelapse_rs::request::Request;
+
+fn frames_picked(input: &Path, output: &Path, start_time: Option<Duration>, end_time: Option<Duration>) -> usize {
+    let mut request = Request::new();
+    request.set_input_path(input)
+        .set_output_path(output)
+        .set_window_size(1)
+        .set_start_time(start_time)
+        .set_end_time(end_time)
+        .set_dry_run(true);
+    timelapse_rs::run(&request, None).unwrap().selections.len()
+}
+
+#[test]
+fn trimmed_range_gives_fewer_frames() {
+    let dir = common::TempDir::new("trim");
+    let input = common::write_frames(dir.path(), 50, 64, 48);
+    let output = dir.path().join("output.webm");
+
+    // Image sequences are read at 25 fps, so this is 2 s of frames
+    assert_eq!(frames_picked(&input, &output, None, None), 50);
+    // Frames 10 to 30, both included
+    assert_eq!(frames_picked(&input, &output, Some(Duration::from_millis(400)), Some(Duration::from_millis(1200))), 21);
+    assert_eq!(frames_picked(&input, &output, Some(Duration::from_millis(1200)), None), 20);
+    assert_eq!(frames_picked(&input, &output, None, Some(Duration::from_millis(400))), 11);
+}
@@ -0,0 +1,39 @@
+mod common;
+
+use std::fs;
+
+use image::GenericImageView;
+use timelapse_rs::request::{Request, Size};
+
+#[test]
+fn cues_cover_the_whole_boomerang_with_thumbnails_of_the_output() {
+    let dir = common::TempDir::new("webvtt");
+    let input = common::write_frames(dir.path(), 20, 64, 48);
+    let base_path = dir.path().join("thumbs");
+
+    let mut request = Request::new();
+    request.set_input_path(&input)
+        .set_output_path(&dir.path().join("output.webm"))
+        .set_window_size(2)
+        .set_boomerang(true)
+        .set_scale(Some(Size { width: 32, height: 24 }))
+        .set_output_fps(Some(6.0))
+        .set_webvtt(Some(&base_path))
+        .set_webvtt_interval(1.0);
+    let stats = timelapse_rs::run(&request, None).unwrap();
+    assert_eq!(stats.frames_written, 10);
+
+    // 10 picked frames, then 8 of them again backwards, is 3 seconds at 6 fps
+    let track = fs::read_to_string(dir.path().join("thumbs.vtt")).unwrap();
+    let cues = track.lines().filter(|line| line.contains(" --> ")).collect::<Vec<_>>();
+    assert_eq!(cues, vec![
+        "00:00:00.000 --> 00:00:01.000",
+        "00:00:01.000 --> 00:00:02.000",
+        "00:00:02.000 --> 00:00:03.000",
+    ]);
+    assert!(track.contains("thumbs.jpg#xywh=64,0,32,24"));
+
+    // The thumbnails are of the scaled output rather than of the input
+    let sprites = image::open(dir.path().join("thumbs.jpg")).unwrap();
+    assert_eq!(sprites.dimensions(), (96, 24));
+}
@@ -0,0 +1,36 @@
+mod common;
+
+use std::path::Path;
+
+use timelapse_rs::error::TimelapseError;
+use timelapse_rs::request::Request;
+use timelapse_rs::ProcessStats;
+
+fn run_with_overlap<F>(input: &Path, output: &Path, window_overlap: u32, change: F) -> Result<ProcessStats, TimelapseError>
+where F: FnOnce(&mut Request) {
+    let mut request = Request::new();
+    request.set_input_path(input)
+        .set_output_path(output)
+        .set_window_size(2)
+        .set_window_overlap(window_overlap)
+        .set_dry_run(true);
+    change(&mut request);
+    request.validate().unwrap();
+    timelapse_rs::run(&request, None)
+}
+
+#[test]
+fn overlap_must_fit_in_the_window_size_worked_out_from_the_input() {
+    let dir = common::TempDir::new("window-overlap");
+    let input = common::write_frames(dir.path(), 50, 64, 48);
+    let output = dir.path().join("output.webm");
+
+    // Image sequences are read at 25 fps, so these windows are 2 frames long
+    let result = run_with_overlap(&input, &output, 3, |r| { r.set_window_duration(Some(0.08)); });
+    assert!(matches!(result, Err(TimelapseError::InvalidRequest(_))), "{:?}", result.map(|stats| stats.frames_written));
+
+    // 10 frames out of 50 makes windows of 5 frames and the overlap on top, which --window-size
+    // would have been too small for
+    let stats = run_with_overlap(&input, &output, 3, |r| { r.set_target_duration(Some(0.4)); }).unwrap();
+    assert!((1..=10).contains(&stats.selections.len()), "{}", stats.selections.len());
+}